@@ -37,6 +37,11 @@ enum Cmd {
         #[arg(long, default_value_t = 500)]
         scan: usize,
     },
+    /// Fetch a transaction by txid (mempool, or confirmed with --tx-index on the node)
+    Tx {
+        /// Transaction id (hex)
+        txid: String,
+    },
 }
 
 #[derive(Deserialize, Debug)]
@@ -137,6 +142,11 @@ fn main() -> Result<()> {
                 ));
             }
         },
+        Cmd::Tx { txid } => {
+            let url = join_url(&rpc, &format!("/tx/{txid}"));
+            let out = http_get_text(&url)?;
+            println!("{out}");
+        }
     }
 
     Ok(())
@@ -0,0 +1,275 @@
+use crate::persist::{load_json_or_default, save_json_atomic};
+use anyhow::Result;
+use kexa_p2p::{PeerAddr, MAX_ADDRS};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Most addresses kept; beyond this the lowest scored are dropped.
+const MAX_KNOWN: usize = 4_096;
+/// Addresses neither seen nor connected to for this long are forgotten.
+const HORIZON_SECS: u64 = 30 * 24 * 60 * 60;
+/// First retry delay after a failed attempt, doubled with each further one.
+const BASE_BACKOFF_SECS: u64 = 10;
+const MAX_BACKOFF_SECS: u64 = 60 * 60;
+
+/// What we know about one address.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct AddrInfo {
+    /// Last time a peer advertised the address or we were connected to it.
+    last_seen: u64,
+    /// Last outbound connection that completed the handshake.
+    last_success: u64,
+    last_attempt: u64,
+    /// Failed attempts since the last success.
+    failures: u32,
+}
+
+impl AddrInfo {
+    /// Higher is better: addresses we have connected to before, recently,
+    /// and that are not currently failing.
+    fn score(&self, now: u64) -> i64 {
+        let hours = |since: u64| (now.saturating_sub(since) / 3_600) as i64;
+        let mut score = -hours(self.last_seen).min(1_000);
+        if self.last_success > 0 {
+            score += 1_000 - hours(self.last_success).min(1_000);
+        }
+        score - 100 * i64::from(self.failures)
+    }
+
+    fn retry_at(&self) -> u64 {
+        if self.failures == 0 {
+            return 0;
+        }
+        let backoff = BASE_BACKOFF_SECS
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_BACKOFF_SECS);
+        self.last_attempt.saturating_add(backoff)
+    }
+
+    fn is_stale(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_seen.max(self.last_success)) > HORIZON_SECS
+    }
+}
+
+/// Peer addresses learned from `Addr` messages and connections, with a
+/// record of how connecting to each has gone. Outbound connections are
+/// picked from here, so the node stays connected when its configured
+/// `--peers` are down. Kept as JSON in the data dir across restarts.
+#[derive(Default)]
+pub(crate) struct AddrMan {
+    path: Option<PathBuf>,
+    addrs: BTreeMap<String, AddrInfo>,
+    dirty: bool,
+}
+
+impl AddrMan {
+    /// Loads the addresses saved at `path`. A missing or unreadable file
+    /// starts an empty table, since addresses are relearned from peers.
+    pub(crate) fn open(path: PathBuf) -> Self {
+        Self {
+            addrs: load_json_or_default(&path),
+            path: Some(path),
+            dirty: false,
+        }
+    }
+
+    /// Writes the table if it changed since the last save, via a temporary
+    /// file so a crash never leaves it half written.
+    pub(crate) fn save(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        save_json_atomic(path, &self.addrs)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Records an advertised address. Returns `false` for addresses that are
+    /// not `ip:port` or that we had already seen more recently.
+    pub(crate) fn add(&mut self, addr: &str, last_seen: u64, now: u64) -> bool {
+        let Ok(parsed) = addr.parse::<SocketAddr>() else {
+            return false;
+        };
+        if parsed.port() == 0 || parsed.ip().is_unspecified() {
+            return false;
+        }
+        // Peers cannot vouch for the future.
+        let last_seen = last_seen.min(now);
+        let key = parsed.to_string();
+        if let Some(info) = self.addrs.get_mut(&key) {
+            if info.last_seen >= last_seen {
+                return false;
+            }
+            info.last_seen = last_seen;
+            self.dirty = true;
+            return true;
+        }
+        if self.addrs.len() >= MAX_KNOWN {
+            self.make_room(now);
+        }
+        self.addrs.insert(
+            key,
+            AddrInfo {
+                last_seen,
+                ..AddrInfo::default()
+            },
+        );
+        self.dirty = true;
+        true
+    }
+
+    pub(crate) fn attempt(&mut self, addr: &str, now: u64) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.last_attempt = now;
+            self.dirty = true;
+        }
+    }
+
+    pub(crate) fn failed(&mut self, addr: &str) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.failures = info.failures.saturating_add(1);
+            self.dirty = true;
+        }
+    }
+
+    /// Records a completed outbound handshake with `addr`.
+    pub(crate) fn connected(&mut self, addr: SocketAddr, now: u64) {
+        let info = self.addrs.entry(addr.to_string()).or_default();
+        info.last_seen = now;
+        info.last_success = now;
+        info.failures = 0;
+        self.dirty = true;
+    }
+
+    /// Best scored address that is not `excluded` and not backing off after
+    /// failures, to connect to next.
+    pub(crate) fn select(&self, excluded: impl Fn(&str) -> bool, now: u64) -> Option<String> {
+        self.addrs
+            .iter()
+            .filter(|(addr, info)| !excluded(addr) && !info.is_stale(now) && info.retry_at() <= now)
+            .max_by_key(|(_, info)| info.score(now))
+            .map(|(addr, _)| addr.clone())
+    }
+
+    /// Up to [`MAX_ADDRS`] live addresses in random order, to answer `GetAddr`.
+    pub(crate) fn sample(&self, now: u64) -> Vec<PeerAddr> {
+        let mut addrs: Vec<PeerAddr> = self
+            .addrs
+            .iter()
+            .filter(|(_, info)| !info.is_stale(now))
+            .map(|(addr, info)| PeerAddr {
+                addr: addr.clone(),
+                last_seen: info.last_seen.max(info.last_success),
+            })
+            .collect();
+        addrs.shuffle(&mut rand::thread_rng());
+        addrs.truncate(MAX_ADDRS);
+        addrs
+    }
+
+    /// Drops stale addresses, or the lowest scored one if none are.
+    fn make_room(&mut self, now: u64) {
+        let before = self.addrs.len();
+        self.addrs.retain(|_, info| !info.is_stale(now));
+        if self.addrs.len() < before {
+            return;
+        }
+        let worst = self
+            .addrs
+            .iter()
+            .min_by_key(|(_, info)| info.score(now))
+            .map(|(addr, _)| addr.clone());
+        if let Some(worst) = worst {
+            self.addrs.remove(&worst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn addresses_persist_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("kexa-addrman-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).expect("dir");
+        let path = dir.join("peers.json");
+
+        let mut addrs = AddrMan::open(path.clone());
+        assert!(addrs.add("10.0.0.1:8031", NOW, NOW));
+        addrs.connected("10.0.0.2:8031".parse().expect("addr"), NOW);
+        addrs.save().expect("save");
+
+        let reopened = AddrMan::open(path);
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.addrs, addrs.addrs);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn invalid_and_future_addresses_are_sanitised() {
+        let mut addrs = AddrMan::default();
+        assert!(!addrs.add("seed.example:8031", NOW, NOW));
+        assert!(!addrs.add("10.0.0.1:0", NOW, NOW));
+        assert!(!addrs.add("0.0.0.0:8031", NOW, NOW));
+        assert!(addrs.add("10.0.0.1:8031", NOW + 1_000_000, NOW));
+        assert_eq!(addrs.addrs["10.0.0.1:8031"].last_seen, NOW);
+        assert!(!addrs.add("10.0.0.1:8031", NOW - 10, NOW));
+    }
+
+    #[test]
+    fn selection_prefers_proven_peers_and_backs_off_failures() {
+        let mut addrs = AddrMan::default();
+        addrs.add("10.0.0.1:8031", NOW, NOW);
+        addrs.connected("10.0.0.2:8031".parse().expect("addr"), NOW - 7_200);
+        assert_eq!(
+            addrs.select(|_| false, NOW).as_deref(),
+            Some("10.0.0.2:8031")
+        );
+        assert_eq!(
+            addrs.select(|addr| addr == "10.0.0.2:8031", NOW).as_deref(),
+            Some("10.0.0.1:8031")
+        );
+
+        addrs.attempt("10.0.0.2:8031", NOW);
+        addrs.failed("10.0.0.2:8031");
+        assert_eq!(
+            addrs.select(|_| false, NOW).as_deref(),
+            Some("10.0.0.1:8031")
+        );
+        let retry = NOW + 2 * BASE_BACKOFF_SECS;
+        addrs.attempt("10.0.0.1:8031", NOW);
+        addrs.failed("10.0.0.1:8031");
+        addrs.failed("10.0.0.1:8031");
+        assert_eq!(
+            addrs.select(|_| false, retry).as_deref(),
+            Some("10.0.0.2:8031")
+        );
+    }
+
+    #[test]
+    fn full_table_forgets_the_worst_address() {
+        let mut addrs = AddrMan::default();
+        for i in 0..MAX_KNOWN {
+            let addr = format!("10.{}.{}.{}:8031", i >> 16, (i >> 8) & 0xff, i & 0xff);
+            assert!(addrs.add(&addr, NOW, NOW));
+        }
+        addrs.attempt("10.0.0.0:8031", NOW);
+        addrs.failed("10.0.0.0:8031");
+        assert!(addrs.add("192.168.0.1:8031", NOW, NOW));
+        assert_eq!(addrs.len(), MAX_KNOWN);
+        assert!(!addrs.addrs.contains_key("10.0.0.0:8031"));
+    }
+}
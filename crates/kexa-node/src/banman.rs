@@ -0,0 +1,217 @@
+use crate::persist::{load_json_or_default, save_json_atomic};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// Misbehavior score at which a peer's IP is banned.
+pub(crate) const BAN_THRESHOLD: u32 = 100;
+/// Score for a message that breaks framing or does not decode.
+pub(crate) const MALFORMED_PENALTY: u32 = 50;
+/// Score for a message that is well formed but breaks the protocol.
+pub(crate) const PROTOCOL_PENALTY: u32 = 20;
+/// Ban length for automatic bans, and for RPC bans that give none.
+pub(crate) const DEFAULT_BAN_SECS: u64 = 24 * 60 * 60;
+/// Misbehavior older than this is forgiven rather than added to.
+const SCORE_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// Misbehavior by a peer, carried by the error that disconnects it. The
+/// connection's owner adds `score` to the peer's IP.
+#[derive(Debug)]
+pub(crate) struct Misbehavior {
+    pub(crate) score: u32,
+    pub(crate) reason: String,
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for Misbehavior {}
+
+pub(crate) fn misbehaving(score: u32, reason: impl Into<String>) -> anyhow::Error {
+    anyhow::Error::new(Misbehavior {
+        score,
+        reason: reason.into(),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Ban {
+    /// Unix time the ban expires.
+    pub(crate) until: u64,
+    pub(crate) reason: String,
+}
+
+/// Banned IPs, kept as JSON in the data dir across restarts, and the
+/// misbehavior scores that lead to automatic bans, which are not.
+#[derive(Default)]
+pub(crate) struct BanMan {
+    path: Option<PathBuf>,
+    bans: BTreeMap<IpAddr, Ban>,
+    /// Score per IP and when it started adding up.
+    scores: HashMap<IpAddr, (u32, u64)>,
+    dirty: bool,
+}
+
+impl BanMan {
+    /// Loads the bans saved at `path`. A missing file starts with none; an
+    /// unreadable one too, with a warning.
+    pub(crate) fn open(path: PathBuf) -> Self {
+        Self {
+            bans: load_json_or_default(&path),
+            path: Some(path),
+            scores: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Writes the ban list if it changed since the last save.
+    pub(crate) fn save(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        save_json_atomic(path, &self.bans)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub(crate) fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        self.bans.get(&ip).is_some_and(|ban| ban.until > now)
+    }
+
+    /// Whether `addr`, an `ip:port` peer address, is banned. Host names
+    /// cannot be and are not.
+    pub(crate) fn is_banned_addr(&self, addr: &str, now: u64) -> bool {
+        addr.parse::<SocketAddr>()
+            .is_ok_and(|addr| self.is_banned(addr.ip(), now))
+    }
+
+    /// Bans `ip` until `until`, replacing any ban it had.
+    pub(crate) fn ban(&mut self, ip: IpAddr, until: u64, reason: impl Into<String>) {
+        self.scores.remove(&ip);
+        self.bans.insert(
+            ip,
+            Ban {
+                until,
+                reason: reason.into(),
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Lifts the ban on `ip`; `false` if it had none.
+    pub(crate) fn unban(&mut self, ip: IpAddr) -> bool {
+        self.scores.remove(&ip);
+        let removed = self.bans.remove(&ip).is_some();
+        self.dirty |= removed;
+        removed
+    }
+
+    /// Lifts every ban and forgives all misbehavior. Returns how many bans
+    /// there were.
+    pub(crate) fn clear(&mut self) -> usize {
+        self.scores.clear();
+        let count = self.bans.len();
+        self.bans.clear();
+        self.dirty |= count > 0;
+        count
+    }
+
+    /// Bans in force, dropping expired ones.
+    pub(crate) fn list(&mut self, now: u64) -> Vec<(IpAddr, Ban)> {
+        let before = self.bans.len();
+        self.bans.retain(|_, ban| ban.until > now);
+        self.dirty |= self.bans.len() < before;
+        self.bans
+            .iter()
+            .map(|(ip, ban)| (*ip, ban.clone()))
+            .collect()
+    }
+
+    /// Adds `misbehavior` to the score of `ip`, banning it for
+    /// [`DEFAULT_BAN_SECS`] on reaching [`BAN_THRESHOLD`]. Returns whether
+    /// it was banned.
+    pub(crate) fn misbehaved(&mut self, ip: IpAddr, misbehavior: &Misbehavior, now: u64) -> bool {
+        let (score, since) = self.scores.entry(ip).or_insert((0, now));
+        if now.saturating_sub(*since) > SCORE_WINDOW_SECS {
+            *score = 0;
+            *since = now;
+        }
+        *score = score.saturating_add(misbehavior.score);
+        if *score < BAN_THRESHOLD {
+            return false;
+        }
+        self.ban(
+            ip,
+            now.saturating_add(DEFAULT_BAN_SECS),
+            misbehavior.reason.clone(),
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().expect("ip")
+    }
+
+    #[test]
+    fn bans_persist_until_they_expire() {
+        let dir = std::env::temp_dir().join(format!("kexa-banman-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).expect("dir");
+        let path = dir.join("banlist.json");
+
+        let mut bans = BanMan::open(path.clone());
+        bans.ban(ip("10.0.0.1"), NOW + 60, "manual");
+        bans.ban(ip("::1"), NOW + 120, "manual");
+        bans.save().expect("save");
+
+        let mut reopened = BanMan::open(path);
+        assert!(reopened.is_banned(ip("10.0.0.1"), NOW));
+        assert!(reopened.is_banned_addr("[::1]:8031", NOW));
+        assert!(!reopened.is_banned_addr("seed.example:8031", NOW));
+        assert!(!reopened.is_banned(ip("10.0.0.1"), NOW + 60));
+        assert_eq!(reopened.list(NOW + 60).len(), 1);
+        assert!(reopened.unban(ip("::1")));
+        assert!(!reopened.unban(ip("::1")));
+        assert!(reopened.list(NOW).is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn misbehavior_adds_up_to_a_ban() {
+        let mut bans = BanMan::default();
+        let peer = ip("10.0.0.1");
+        let violation = Misbehavior {
+            score: PROTOCOL_PENALTY,
+            reason: "message before handshake completed".to_string(),
+        };
+        for _ in 1..BAN_THRESHOLD / PROTOCOL_PENALTY {
+            assert!(!bans.misbehaved(peer, &violation, NOW));
+        }
+        // A day later the earlier offences are forgiven.
+        let later = NOW + SCORE_WINDOW_SECS + 1;
+        assert!(!bans.misbehaved(peer, &violation, later));
+        for _ in 2..BAN_THRESHOLD / PROTOCOL_PENALTY {
+            assert!(!bans.misbehaved(peer, &violation, later));
+        }
+        assert!(bans.misbehaved(peer, &violation, later));
+        assert!(bans.is_banned(peer, later));
+        assert_eq!(bans.list(later)[0].1.until, later + DEFAULT_BAN_SECS);
+        assert_eq!(bans.clear(), 1);
+        assert!(!bans.is_banned(peer, later));
+    }
+}
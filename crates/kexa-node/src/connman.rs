@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+/// Inbound peers with the lowest ping that eviction spares.
+const PROTECT_BY_PING: usize = 4;
+/// Inbound peers that most recently sent us a new block that eviction spares.
+const PROTECT_BY_BLOCKS: usize = 4;
+
+/// How many peers the node connects to and accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ConnLimits {
+    pub(crate) max_inbound: usize,
+    /// Outbound connections kept open, counting configured `--peers`.
+    pub(crate) max_outbound: usize,
+    /// Most inbound connections from one IP.
+    pub(crate) max_per_ip: usize,
+    /// Most inbound connections from one /24 (IPv4) or /64 (IPv6) subnet.
+    pub(crate) max_per_subnet: usize,
+}
+
+impl Default for ConnLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: 64,
+            max_outbound: 8,
+            max_per_ip: 4,
+            max_per_subnet: 16,
+        }
+    }
+}
+
+/// The subnet `ip` is counted in: its /24 for IPv4, including IPv4-mapped
+/// IPv6, and its /64 otherwise.
+pub(crate) fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => subnet(IpAddr::V4(ip)),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+/// An inbound peer that could make room for a new connection.
+#[derive(Clone, Debug)]
+pub(crate) struct EvictionCandidate {
+    pub(crate) ip: IpAddr,
+    /// Unix time the connection was accepted.
+    pub(crate) connected_at: u64,
+    pub(crate) ping: Option<Duration>,
+    /// Unix time the peer last sent a block that extended our chain.
+    pub(crate) last_block: Option<u64>,
+}
+
+/// Index of the inbound peer to disconnect when all inbound slots are full,
+/// or `None` to refuse the new connection instead. The peers with the lowest
+/// ping and those that sent us blocks last are kept, being the most useful;
+/// of the rest, the newest from the subnet with the most connections goes,
+/// so a single host filling our slots mostly evicts itself.
+pub(crate) fn select_victim(candidates: &[EvictionCandidate]) -> Option<usize> {
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+    remaining.sort_by_key(|&i| candidates[i].ping.unwrap_or(Duration::MAX));
+    let pinged = remaining
+        .iter()
+        .take_while(|&&i| candidates[i].ping.is_some())
+        .count();
+    remaining.drain(..pinged.min(PROTECT_BY_PING));
+    remaining.sort_by_key(|&i| std::cmp::Reverse(candidates[i].last_block));
+    let relayed = remaining
+        .iter()
+        .take_while(|&&i| candidates[i].last_block.is_some())
+        .count();
+    remaining.drain(..relayed.min(PROTECT_BY_BLOCKS));
+
+    let mut by_subnet: HashMap<IpAddr, Vec<usize>> = HashMap::new();
+    for i in remaining {
+        by_subnet
+            .entry(subnet(candidates[i].ip))
+            .or_default()
+            .push(i);
+    }
+    // Ties go to the subnet whose newest peer connected last.
+    let newest = |peers: &Vec<usize>| {
+        peers
+            .iter()
+            .map(|&i| candidates[i].connected_at)
+            .max()
+            .unwrap_or(0)
+    };
+    let crowded = by_subnet
+        .into_values()
+        .max_by_key(|peers| (peers.len(), newest(peers)))?;
+    crowded
+        .into_iter()
+        .max_by_key(|&i| candidates[i].connected_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn candidate(ip: &str, connected_at: u64) -> EvictionCandidate {
+        EvictionCandidate {
+            ip: ip.parse().expect("ip"),
+            connected_at,
+            ping: None,
+            last_block: None,
+        }
+    }
+
+    #[test]
+    fn subnets_group_nearby_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().expect("ip");
+        assert_eq!(subnet(ip("10.1.2.3")), ip("10.1.2.0"));
+        assert_eq!(subnet(ip("::ffff:10.1.2.3")), ip("10.1.2.0"));
+        assert_eq!(subnet(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+    }
+
+    #[test]
+    fn eviction_spares_fast_and_relaying_peers() {
+        let mut peers = Vec::new();
+        // A host that opened many connections, each fast to answer pings.
+        for i in 0..6 {
+            let mut peer = candidate(&format!("10.0.0.{i}"), NOW + i);
+            peer.ping = Some(Duration::from_millis(5 + i));
+            peers.push(peer);
+        }
+        // A distant peer that keeps sending blocks.
+        let mut relayer = candidate("192.168.1.1", NOW + 100);
+        relayer.ping = Some(Duration::from_secs(1));
+        relayer.last_block = Some(NOW + 200);
+        peers.push(relayer);
+        peers.push(candidate("172.16.0.1", NOW));
+
+        // The four fastest and the relayer are kept; the crowded subnet
+        // loses its newest unprotected peer.
+        assert_eq!(select_victim(&peers), Some(5));
+        peers.remove(5);
+        // Subnets tied on connections lose their newest peer first.
+        assert_eq!(select_victim(&peers), Some(4));
+        peers.remove(4);
+        assert_eq!(select_victim(&peers), Some(5));
+        peers.remove(5);
+        assert_eq!(select_victim(&peers), None);
+    }
+}
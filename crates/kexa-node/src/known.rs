@@ -0,0 +1,100 @@
+use kexa_proto::Hash32;
+use std::collections::{HashSet, VecDeque};
+
+/// Most txids remembered per peer; beyond this the oldest are forgotten.
+const MAX_KNOWN_TXS: usize = 5 * kexa_p2p::MAX_INV_ITEMS;
+
+/// Txids a peer has announced or been sent, so none is offered twice. The
+/// oldest are forgotten once the set is full, at worst costing a repeated
+/// announcement.
+#[derive(Default)]
+pub(crate) struct KnownTxs {
+    txids: HashSet<Hash32>,
+    /// Insertion order, to forget the oldest first. May hold txids already
+    /// removed from the set.
+    order: VecDeque<Hash32>,
+}
+
+impl KnownTxs {
+    /// Remembers `txid`. Returns false if it was known already.
+    pub(crate) fn insert(&mut self, txid: Hash32) -> bool {
+        if !self.txids.insert(txid) {
+            return false;
+        }
+        self.order.push_back(txid);
+        while self.txids.len() > MAX_KNOWN_TXS {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.txids.remove(&oldest);
+        }
+        // Drop stale order entries so removals cannot grow it unbounded.
+        if self.order.len() > 2 * MAX_KNOWN_TXS {
+            let txids = &self.txids;
+            self.order.retain(|txid| txids.contains(txid));
+        }
+        true
+    }
+
+    /// Forgets `txid`, so it can be announced or requested again.
+    pub(crate) fn remove(&mut self, txid: &Hash32) {
+        self.txids.remove(txid);
+    }
+
+    /// Forgets every txid for which `keep` returns false.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&Hash32) -> bool) {
+        self.txids.retain(|txid| keep(txid));
+        let txids = &self.txids;
+        self.order.retain(|txid| txids.contains(txid));
+    }
+
+    #[cfg(test)]
+    fn contains(&self, txid: &Hash32) -> bool {
+        self.txids.contains(txid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txid(i: usize) -> Hash32 {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&(i as u64).to_le_bytes());
+        Hash32(bytes)
+    }
+
+    #[test]
+    fn oldest_txids_are_forgotten_when_full() {
+        let mut known = KnownTxs::default();
+        for i in 0..=MAX_KNOWN_TXS {
+            assert!(known.insert(txid(i)));
+        }
+        assert!(!known.insert(txid(MAX_KNOWN_TXS)));
+        assert_eq!(known.txids.len(), MAX_KNOWN_TXS);
+        assert!(!known.contains(&txid(0)));
+        assert!(known.contains(&txid(1)));
+
+        // Removed txids do not keep the order queue growing.
+        for i in 0..4 * MAX_KNOWN_TXS {
+            let id = txid(MAX_KNOWN_TXS + 1 + i);
+            known.insert(id);
+            known.remove(&id);
+        }
+        assert!(known.order.len() <= 2 * MAX_KNOWN_TXS);
+    }
+
+    #[test]
+    fn removed_txids_can_be_learned_again() {
+        let mut known = KnownTxs::default();
+        assert!(known.insert(txid(1)));
+        known.remove(&txid(1));
+        assert!(!known.contains(&txid(1)));
+        assert!(known.insert(txid(1)));
+
+        known.insert(txid(2));
+        known.retain(|id| *id == txid(2));
+        assert!(!known.contains(&txid(1)));
+        assert_eq!(known.order, VecDeque::from([txid(2)]));
+    }
+}
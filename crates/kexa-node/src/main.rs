@@ -2,38 +2,63 @@ use anyhow::{Context, Result};
 use axum::http::StatusCode;
 use axum::{
     extract::{Path, Query},
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
-use borsh::BorshDeserialize;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use kexa_consensus::{block_subsidy, check_pow, merkle_root, COINBASE_MATURITY, DIFFICULTY_BITS};
-use kexa_p2p::{encode_message, Message, MAX_MESSAGE_SIZE};
+use kexa_p2p::{
+    FrameError, InvItem, Magic, Message, MessageReader, MessageWriter, PeerConnection,
+    MAINNET_MAGIC, MAX_ADDRS, MAX_BLOCKS_PER_GETBLOCKS, MAX_HEADERS, MAX_INV_ITEMS,
+    MAX_USER_AGENT_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SERVICE_FULL_HISTORY,
+    TESTNET_MAGIC,
+};
 use kexa_proto::{
     tx_signing_hash, verify_tx_signature, Address, Block, BlockHeader, Hash32, OutPoint,
     Transaction, TxOut,
 };
-use kexa_storage::Storage;
+use kexa_storage::{ChainStore, SnapshotHeader, Storage, StorageError, StorageOptions, TxLocation};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeSet,
-    net::SocketAddr,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{broadcast, mpsc, Mutex},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
+mod addrman;
+mod banman;
+mod connman;
 mod genesis;
-
+mod known;
+mod maintenance;
+mod mempool;
+mod orphans;
+mod persist;
+mod sync;
+mod transport;
+
+use crate::addrman::AddrMan;
+use crate::banman::{
+    misbehaving, BanMan, Misbehavior, BAN_THRESHOLD, DEFAULT_BAN_SECS, MALFORMED_PENALTY,
+    PROTOCOL_PENALTY,
+};
+use crate::connman::{select_victim, subnet, ConnLimits, EvictionCandidate};
 use crate::genesis::{
     build_genesis_from_spec, build_testnet_genesis, load_genesis_spec, GenesisSpec,
     TESTNET_GENESIS_HASH_HEX,
 };
+use crate::known::KnownTxs;
+use crate::mempool::Mempool;
+use crate::orphans::OrphanPool;
+use crate::sync::{block_locator, locate, validate_header, HeaderSync};
+use crate::transport::{Encryption, NodeKey, PeerReader, PeerWriter, Transport};
 
 #[derive(Parser, Debug)]
 #[command(name = "kexa-node", version)]
@@ -42,7 +67,7 @@ struct Args {
     rpc_addr: String,
     #[arg(long, default_value = "0.0.0.0:9030")]
     p2p_addr: String,
-    #[arg(long, default_value = "./data")]
+    #[arg(long, default_value = "./data", global = true)]
     data_dir: String,
     #[arg(long)]
     mine: bool,
@@ -50,12 +75,77 @@ struct Args {
     miner_address: Option<String>,
     #[arg(long, default_value = "")]
     peers: String,
-    #[arg(long, value_enum, default_value_t = Network::Testnet)]
+    #[arg(long, value_enum, default_value_t = Network::Testnet, global = true)]
     network: Network,
-    #[arg(long)]
+    #[arg(long, global = true)]
     genesis: Option<String>,
     #[arg(long)]
     print_genesis: bool,
+    /// Maintain a txid -> block index so GET /tx/:txid can serve confirmed
+    /// transactions. Building it on an existing data dir scans every block.
+    #[arg(long, global = true)]
+    tx_index: bool,
+    /// Maintain per-address transaction history for
+    /// GET /address/:address/history. Built from stored blocks on first use.
+    #[arg(long, global = true)]
+    address_history: bool,
+    /// Memory budget in MiB for buffering UTXO set changes between disk
+    /// flushes; speeds up initial sync. 0 writes them with every block.
+    #[arg(long, default_value_t = 64, global = true)]
+    utxo_cache_mb: usize,
+    /// Encryption of P2P connections, authenticated by the node key in
+    /// `p2p_key.json` in the data dir.
+    #[arg(long, value_enum, default_value_t = Encryption::Prefer)]
+    p2p_encryption: Encryption,
+    /// Most inbound P2P connections; when full, a new one evicts the least
+    /// useful inbound peer.
+    #[arg(long, default_value_t = ConnLimits::default().max_inbound)]
+    max_inbound: usize,
+    /// Outbound P2P connections kept open, counting configured `--peers`.
+    #[arg(long, default_value_t = ConnLimits::default().max_outbound)]
+    max_outbound: usize,
+    /// Most inbound P2P connections from one IP.
+    #[arg(long, default_value_t = ConnLimits::default().max_per_ip)]
+    max_per_ip: usize,
+    /// Most inbound P2P connections from one /24 (IPv4) or /64 (IPv6).
+    #[arg(long, default_value_t = ConnLimits::default().max_per_subnet)]
+    max_per_subnet: usize,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Offline maintenance on the data dir; the node is not started.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rebuild the UTXO set and indexes from the stored blocks
+    Reindex,
+    /// Re-validate the newest stored blocks and check the UTXO set against a
+    /// replay of the chain
+    VerifyChain {
+        /// Number of blocks back from the tip to fully re-validate
+        #[arg(long, default_value_t = 288)]
+        depth: u64,
+    },
+    /// Write the active chain to a bootstrap file
+    ExportBlocks { file: PathBuf },
+    /// Validate and connect the blocks of a bootstrap file; works on an
+    /// empty data dir
+    ImportBlocks { file: PathBuf },
+    /// Write the UTXO set at a height, with its commitment hash, to a file
+    ExportUtxoSnapshot {
+        file: PathBuf,
+        /// Block height to snapshot; defaults to the tip
+        #[arg(long)]
+        height: Option<u64>,
+    },
+    /// Start an empty data dir from a UTXO snapshot; the history below it is
+    /// validated in the background once the node runs
+    LoadUtxoSnapshot {
+        file: PathBuf,
+        /// Commitment hash published alongside the snapshot
+        #[arg(long)]
+        expect_hash: String,
+    },
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum, PartialEq, Eq)]
@@ -70,16 +160,129 @@ enum NetworkMode {
     Mainnet { spec: GenesisSpec },
 }
 
+impl NetworkMode {
+    fn magic(&self) -> Magic {
+        match self {
+            NetworkMode::Testnet => TESTNET_MAGIC,
+            NetworkMode::Mainnet { .. } => MAINNET_MAGIC,
+        }
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     inner: Arc<Mutex<ChainState>>,
+    /// Blocks connected and transactions accepted, for every peer connection
+    /// to announce.
+    announcements: broadcast::Sender<InvItem>,
+    /// IPs just banned, for their connections to drop.
+    banned: broadcast::Sender<IpAddr>,
+    magic: Magic,
+    /// Sent in our `Version`; receiving it back means we dialled ourselves.
+    nonce: u64,
+    /// P2P port advertised in our `Version`, 0 when not listening.
+    listen_port: u16,
+    /// Static key of encrypted connections.
+    node_key: Arc<NodeKey>,
+    encryption: Encryption,
+    limits: ConnLimits,
+    /// Notified to stop the node, as on Ctrl-C.
+    shutdown: Arc<tokio::sync::Notify>,
+}
+
+impl AppState {
+    fn new(chain: ChainState, magic: Magic) -> Self {
+        let (announcements, _) = broadcast::channel(1_024);
+        let (banned, _) = broadcast::channel(64);
+        Self {
+            inner: Arc::new(Mutex::new(chain)),
+            announcements,
+            banned,
+            magic,
+            nonce: rand::random(),
+            listen_port: 0,
+            node_key: Arc::new(NodeKey::generate()),
+            encryption: Encryption::Prefer,
+            limits: ConnLimits::default(),
+            shutdown: Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+
+    fn announce(&self, item: InvItem) {
+        // Sending only fails with no peer connected, when there is no one to tell.
+        let _ = self.announcements.send(item);
+    }
+
+    /// Drops every connection with `ip`, after banning it.
+    fn disconnect(&self, ip: IpAddr) {
+        let _ = self.banned.send(ip);
+    }
 }
 
 struct ChainState {
     storage: Storage,
-    mempool: Vec<Transaction>,
+    mempool: Mempool,
     peers: Vec<String>,
-    live_peers: BTreeSet<String>,
+    live_peers: BTreeMap<String, Arc<LivePeer>>,
+    /// Known peer addresses to pick outbound connections from.
+    addrs: AddrMan,
+    /// Banned IPs and misbehavior scores of peers.
+    bans: BanMan,
+    /// Blocks received before their parents.
+    orphans: OrphanPool,
+    /// Background validation of the blocks below a UTXO snapshot.
+    history: Option<HistorySync>,
+}
+
+struct LivePeer {
+    /// We dialled it, as opposed to it connecting to us.
+    outbound: bool,
+    /// Unix time the connection was opened.
+    connected_at: u64,
+    /// The peer's node key, once an encrypted connection is established.
+    node_key: std::sync::OnceLock<[u8; 32]>,
+    /// Notified to drop the connection for a new inbound peer.
+    evicted: tokio::sync::Notify,
+    /// Updated by the connection as it runs.
+    stats: std::sync::Mutex<PeerStats>,
+}
+
+struct PeerStats {
+    /// Unix time of the last message received, or of connecting.
+    last_seen: u64,
+    /// Round trip of the last answered ping.
+    ping: Option<Duration>,
+    /// Unix time the peer last sent a block that extended our chain.
+    last_block: Option<u64>,
+}
+
+impl LivePeer {
+    fn new(outbound: bool) -> Self {
+        let now = now_timestamp();
+        Self {
+            outbound,
+            connected_at: now,
+            node_key: std::sync::OnceLock::new(),
+            evicted: tokio::sync::Notify::new(),
+            stats: std::sync::Mutex::new(PeerStats {
+                last_seen: now,
+                ping: None,
+                last_block: None,
+            }),
+        }
+    }
+
+    fn stats(&self) -> std::sync::MutexGuard<'_, PeerStats> {
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Separate store that syncs and validates the chain from genesis up to the
+/// snapshot base, while `storage` carries on from the snapshot.
+struct HistorySync {
+    store: Storage,
+    snapshot: SnapshotHeader,
+    dir: PathBuf,
 }
 
 #[derive(Serialize)]
@@ -106,6 +309,40 @@ struct ErrorResponse {
     error: String,
 }
 
+#[derive(Serialize)]
+struct LivePeerResponse {
+    address: String,
+    outbound: bool,
+    /// Hex node key the peer authenticated with; absent on plaintext
+    /// connections.
+    node_key: Option<String>,
+    /// Unix time of the last message received.
+    last_seen: u64,
+    /// Last ping round trip, once the peer has answered one.
+    ping_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct BanResponse {
+    address: String,
+    /// Unix time the ban expires.
+    banned_until: u64,
+    reason: String,
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    /// IP to ban; the port of an `ip:port` is ignored.
+    address: String,
+    duration_secs: Option<u64>,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UnbanResponse {
+    removed: usize,
+}
+
 #[derive(Deserialize)]
 struct MineRequest {
     count: u64,
@@ -136,8 +373,60 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let storage = Storage::open(&args.data_dir)?;
+    let storage = Storage::open_with(
+        &args.data_dir,
+        StorageOptions {
+            tx_index: args.tx_index,
+            address_history: args.address_history,
+            utxo_cache_bytes: args.utxo_cache_mb << 20,
+        },
+    )?;
+    if let Some(command) = &args.command {
+        let imports = matches!(
+            command,
+            Command::ImportBlocks { .. } | Command::LoadUtxoSnapshot { .. }
+        );
+        if storage.get_tip()?.is_none() && !imports {
+            anyhow::bail!("no chain in data dir {}", args.data_dir);
+        }
+        init_genesis(&storage, &mode)?;
+        return match command {
+            Command::Reindex => maintenance::reindex(&storage),
+            Command::VerifyChain { depth } => maintenance::verify_chain(&storage, *depth),
+            Command::ExportBlocks { file } => maintenance::export_blocks(&storage, file),
+            Command::ImportBlocks { file } => maintenance::import_blocks(&storage, file),
+            Command::ExportUtxoSnapshot { file, height } => {
+                maintenance::export_utxo_snapshot(&storage, file, *height)
+            }
+            Command::LoadUtxoSnapshot { file, expect_hash } => {
+                maintenance::load_utxo_snapshot(&storage, file, expect_hash)
+            }
+        };
+    }
     init_genesis(&storage, &mode)?;
+    if let Some(reason) = storage.snapshot_failure()? {
+        anyhow::bail!(
+            "the utxo snapshot data dir {} was started from contradicts the validated chain ({reason}); its UTXO set cannot be trusted, resync without the snapshot",
+            args.data_dir
+        );
+    }
+    let history = match storage.snapshot_base()? {
+        Some(snapshot) => {
+            let dir = PathBuf::from(&args.data_dir).join("snapshot-history");
+            let store = Storage::open(dir.to_str().context("data dir path is not utf-8")?)?;
+            init_genesis(&store, &mode)?;
+            info!(
+                "started from utxo snapshot at height {}, validating history in the background",
+                snapshot.base.height
+            );
+            Some(HistorySync {
+                store,
+                snapshot,
+                dir,
+            })
+        }
+        None => None,
+    };
     let peers = if args.peers.is_empty() {
         Vec::new()
     } else {
@@ -147,17 +436,42 @@ async fn main() -> Result<()> {
             .collect()
     };
 
-    let state = AppState {
-        inner: Arc::new(Mutex::new(ChainState {
+    let rpc_addr: SocketAddr = args.rpc_addr.parse()?;
+    let p2p_addr: SocketAddr = args.p2p_addr.parse()?;
+
+    let addrs = AddrMan::open(PathBuf::from(&args.data_dir).join("peers.json"));
+    info!("loaded {} known peer addresses", addrs.len());
+    let mut state = AppState::new(
+        ChainState {
             storage,
-            mempool: Vec::new(),
+            mempool: Mempool::default(),
             peers,
-            live_peers: BTreeSet::new(),
-        })),
+            live_peers: BTreeMap::new(),
+            addrs,
+            bans: BanMan::open(PathBuf::from(&args.data_dir).join("banlist.json")),
+            orphans: OrphanPool::default(),
+            history,
+        },
+        mode.magic(),
+    );
+    state.listen_port = p2p_addr.port();
+    state.node_key = Arc::new(NodeKey::load_or_create(
+        &PathBuf::from(&args.data_dir).join("p2p_key.json"),
+    )?);
+    state.encryption = args.p2p_encryption;
+    state.limits = ConnLimits {
+        max_inbound: args.max_inbound,
+        max_outbound: args.max_outbound,
+        max_per_ip: args.max_per_ip,
+        max_per_subnet: args.max_per_subnet,
     };
-
-    let rpc_addr: SocketAddr = args.rpc_addr.parse()?;
-    let p2p_addr: SocketAddr = args.p2p_addr.parse()?;
+    info!(
+        "p2p node key {} ({:?} encryption)",
+        hex::encode(state.node_key.public()),
+        state.encryption
+    );
+    // A previous run may have stopped once the history reached the base.
+    complete_history(&mut *state.inner.lock().await)?;
 
     let state_clone = state.clone();
     tokio::spawn(async move {
@@ -183,14 +497,58 @@ async fn main() -> Result<()> {
         });
     }
 
-    let app = build_router(state);
+    let app = build_router(state.clone());
 
     info!("rpc listening on {rpc_addr}");
     let listener = tokio::net::TcpListener::bind(rpc_addr).await?;
-    axum::serve(listener, app).await?;
+    let shutdown = state.shutdown.clone();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            tokio::select! {
+                () = shutdown_signal() => {}
+                () = shutdown.notified() => {}
+            }
+        })
+        .await?;
+    info!("shutting down, flushing storage");
+    let mut guard = state.inner.lock().await;
+    guard.storage.flush()?;
+    guard.addrs.save()?;
+    guard.bans.save()?;
+    if let Some(reason) = guard.storage.snapshot_failure()? {
+        anyhow::bail!("stopped: utxo snapshot contradicts the validated chain ({reason})");
+    }
     Ok(())
 }
 
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for ctrl-c: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                error!("failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
 fn build_network_mode(args: &Args) -> Result<NetworkMode> {
     match args.network {
         Network::Testnet => {
@@ -249,13 +607,11 @@ fn print_genesis(mode: &NetworkMode) -> Result<()> {
     Ok(())
 }
 
-async fn ready(state: axum::extract::State<AppState>) -> Json<TipResponse> {
+async fn ready(
+    state: axum::extract::State<AppState>,
+) -> Result<Json<TipResponse>, (StatusCode, Json<ErrorResponse>)> {
     let guard = state.inner.lock().await;
-    let (height, hash) = guard.storage.get_tip().unwrap().expect("tip");
-    Json(TipResponse {
-        height,
-        hash: hex::encode(hash.0),
-    })
+    tip_response(&guard.storage)
 }
 
 fn build_router(state: AppState) -> Router {
@@ -267,20 +623,33 @@ fn build_router(state: AppState) -> Router {
         .route("/block/:hash", get(get_block))
         .route("/balance/:address", get(get_balance))
         .route("/utxos/:address", get(get_utxos))
+        .route("/tx/:txid", get(get_tx))
+        .route("/address/:address/history", get(get_address_history))
         .route("/submit_tx", post(submit_tx))
         .route("/mine_blocks", post(mine_blocks))
         .route("/peers", get(get_peers))
         .route("/peers/live", get(get_live_peers))
+        .route("/bans", get(get_bans).post(add_ban).delete(clear_bans))
+        .route("/bans/:address", delete(remove_ban))
         .with_state(state)
 }
 
-async fn get_tip(state: axum::extract::State<AppState>) -> Json<TipResponse> {
+async fn get_tip(
+    state: axum::extract::State<AppState>,
+) -> Result<Json<TipResponse>, (StatusCode, Json<ErrorResponse>)> {
     let guard = state.inner.lock().await;
-    let (height, hash) = guard.storage.get_tip().unwrap().expect("tip");
-    Json(TipResponse {
+    tip_response(&guard.storage)
+}
+
+fn tip_response(storage: &Storage) -> Result<Json<TipResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let (height, hash) = storage
+        .get_tip()
+        .map_err(internal_error)?
+        .ok_or_else(|| internal_error(anyhow::anyhow!("tip missing")))?;
+    Ok(Json(TipResponse {
         height,
         hash: hex::encode(hash.0),
-    })
+    }))
 }
 
 async fn get_blocks(
@@ -297,24 +666,26 @@ async fn get_blocks(
         .storage
         .get_tip()
         .map_err(internal_error)?
-        .expect("tip");
+        .ok_or_else(|| internal_error(anyhow::anyhow!("tip missing")))?;
 
     let mut out: Vec<BlockSummary> = Vec::with_capacity(limit);
     let mut cur = tip_hash;
 
+    let snapshot = guard.storage.snapshot_base().map_err(internal_error)?;
     for _ in 0..limit {
-        let block = guard
-            .storage
-            .get_block(&cur)
-            .map_err(internal_error)?
-            .ok_or_else(|| {
-                (
-                    StatusCode::NOT_FOUND,
-                    Json(ErrorResponse {
-                        error: "block not found".to_string(),
-                    }),
-                )
-            })?;
+        let block = guard.storage.get_block(&cur).map_err(internal_error)?;
+        // Blocks up to a UTXO snapshot base arrive with its history.
+        let Some(block) = block else {
+            if snapshot.is_some() {
+                break;
+            }
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse {
+                    error: "block not found".to_string(),
+                }),
+            ));
+        };
 
         out.push(BlockSummary {
             height: block.header.height,
@@ -395,16 +766,257 @@ async fn get_utxos(
     Ok(Json(response))
 }
 
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct HistoryEntryResponse {
+    txid: String,
+    height: u64,
+    position: u32,
+    timestamp: u64,
+    received: u64,
+    sent: u64,
+    net: i64,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    address: String,
+    entries: Vec<HistoryEntryResponse>,
+    /// Offset of the next page, absent on the last page.
+    next_offset: Option<usize>,
+}
+
+async fn get_address_history(
+    Path(address): Path<String>,
+    Query(q): Query<HistoryQuery>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<HistoryResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let address = parse_address(&address)?;
+    let limit = q.limit.unwrap_or(50);
+    if limit == 0 || limit > 500 {
+        return Err(bad_request("limit must be 1..=500"));
+    }
+    let offset = q.offset.unwrap_or(0);
+    let guard = state.inner.lock().await;
+    if !guard.storage.options().address_history {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            Json(ErrorResponse {
+                error: "address history disabled (start node with --address-history)".to_string(),
+            }),
+        ));
+    }
+    let mut entries = guard
+        .storage
+        .address_history(&address.payload, offset, limit + 1)
+        .map_err(internal_error)?;
+    let next_offset = if entries.len() > limit {
+        entries.truncate(limit);
+        Some(offset + limit)
+    } else {
+        None
+    };
+    let entries = entries
+        .into_iter()
+        .map(|entry| HistoryEntryResponse {
+            txid: hex::encode(entry.txid.0),
+            height: entry.height,
+            position: entry.position,
+            timestamp: entry.timestamp,
+            received: entry.received,
+            sent: entry.sent,
+            net: entry.received as i64 - entry.sent as i64,
+        })
+        .collect();
+    Ok(Json(HistoryResponse {
+        address: address.to_bech32(),
+        entries,
+        next_offset,
+    }))
+}
+
+#[derive(Serialize)]
+struct TxInputResponse {
+    txid: String,
+    index: u32,
+    amount: u64,
+    address: String,
+}
+
+#[derive(Serialize)]
+struct TxResponse {
+    txid: String,
+    /// "confirmed" or "mempool".
+    status: &'static str,
+    block_hash: Option<String>,
+    height: Option<u64>,
+    position: Option<u32>,
+    timestamp: Option<u64>,
+    confirmations: u64,
+    coinbase: bool,
+    inputs: Vec<TxInputResponse>,
+    input_total: u64,
+    output_total: u64,
+    fee: u64,
+    tx: Transaction,
+}
+
+async fn get_tx(
+    Path(txid): Path<String>,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<TxResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let txid = parse_hash32(&txid)?;
+    let guard = state.inner.lock().await;
+    if let Some(tx) = guard.mempool.get(&txid) {
+        let response = tx_response(&guard.storage, tx.clone(), None).map_err(internal_error)?;
+        return Ok(Json(response));
+    }
+    let Some((tx, location)) = guard
+        .storage
+        .get_transaction(&txid)
+        .map_err(internal_error)?
+    else {
+        let error = if guard.storage.options().tx_index {
+            "transaction not found"
+        } else {
+            "transaction not found (confirmed lookups need --tx-index)"
+        };
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: error.to_string(),
+            }),
+        ));
+    };
+    let response = tx_response(&guard.storage, tx, Some(location)).map_err(internal_error)?;
+    Ok(Json(response))
+}
+
+fn tx_response(
+    storage: &Storage,
+    tx: Transaction,
+    location: Option<TxLocation>,
+) -> Result<TxResponse> {
+    let coinbase = location.is_some_and(|location| location.position == 0);
+    let mut inputs = Vec::with_capacity(tx.inputs.len());
+    for input in &tx.inputs {
+        let output = spent_output(storage, &input.outpoint)?
+            .with_context(|| format!("input {} not found", hex::encode(input.outpoint.txid.0)))?;
+        inputs.push(TxInputResponse {
+            txid: hex::encode(input.outpoint.txid.0),
+            index: input.outpoint.index,
+            amount: output.amount,
+            address: Address {
+                payload: output.address,
+            }
+            .to_bech32(),
+        });
+    }
+    let input_total = inputs
+        .iter()
+        .fold(0u64, |sum, input| sum.saturating_add(input.amount));
+    let output_total = tx
+        .outputs
+        .iter()
+        .fold(0u64, |sum, output| sum.saturating_add(output.amount));
+    let fee = if coinbase {
+        0
+    } else {
+        input_total.saturating_sub(output_total)
+    };
+    let (timestamp, confirmations) = match location {
+        Some(location) => {
+            let (tip_height, _) = storage.get_tip()?.context("tip missing")?;
+            let header = storage
+                .get_header(location.height)?
+                .context("header missing for indexed tx")?;
+            (
+                Some(header.timestamp),
+                tip_height.saturating_sub(location.height) + 1,
+            )
+        }
+        None => (None, 0),
+    };
+    Ok(TxResponse {
+        txid: hex::encode(tx.txid().0),
+        status: if location.is_some() {
+            "confirmed"
+        } else {
+            "mempool"
+        },
+        block_hash: location.map(|location| hex::encode(location.block_hash.0)),
+        height: location.map(|location| location.height),
+        position: location.map(|location| location.position),
+        timestamp,
+        confirmations,
+        coinbase,
+        inputs,
+        input_total,
+        output_total,
+        fee,
+        tx,
+    })
+}
+
+/// Resolves the output an input spends: from the UTXO set while it is
+/// unspent, otherwise from the transaction that created it via the tx index.
+fn spent_output(storage: &Storage, outpoint: &OutPoint) -> Result<Option<TxOut>> {
+    if let Some(output) = storage.get_utxo(outpoint)? {
+        return Ok(Some(output));
+    }
+    Ok(storage
+        .get_transaction(&outpoint.txid)?
+        .and_then(|(tx, _)| tx.outputs.into_iter().nth(outpoint.index as usize)))
+}
+
 async fn submit_tx(
     state: axum::extract::State<AppState>,
     Json(req): Json<SubmitRequest>,
 ) -> Json<String> {
     let mut guard = state.inner.lock().await;
-    if let Err(err) = validate_tx(&guard.storage, &guard.mempool, &req.tx) {
-        return Json(format!("error: {err}"));
+    let txid = req.tx.txid();
+    match accept_tx(&mut guard, req.tx) {
+        Ok(true) => state.announce(InvItem::Tx(txid)),
+        Ok(false) => {}
+        Err(err) => return Json(format!("error: {err}")),
+    }
+    Json(hex::encode(txid.0))
+}
+
+/// Validates `tx` against the chain and mempool and adds it to the mempool.
+/// Returns `false` if it was already there; otherwise the caller announces it.
+fn accept_tx(chain: &mut ChainState, tx: Transaction) -> Result<bool> {
+    let txid = tx.txid();
+    if chain.mempool.contains(&txid) {
+        return Ok(false);
     }
-    guard.mempool.push(req.tx.clone());
-    Json(hex::encode(req.tx.txid().0))
+    validate_tx(
+        &UtxoOverlay::new(&chain.storage),
+        chain.mempool.as_slice(),
+        &tx,
+    )?;
+    Ok(chain.mempool.insert(tx))
+}
+
+/// Drops mempool transactions that spend an output `block` spent: the ones
+/// it confirmed and the ones it conflicts with.
+fn evict_spent(mempool: &mut Mempool, block: &Block) {
+    let spent: HashSet<([u8; 32], u32)> = block
+        .txs
+        .iter()
+        .flat_map(|tx| &tx.inputs)
+        .map(|input| (input.outpoint.txid.0, input.outpoint.index))
+        .collect();
+    mempool.retain(|tx| {
+        !tx.inputs
+            .iter()
+            .any(|input| spent.contains(&(input.outpoint.txid.0, input.outpoint.index)))
+    });
 }
 
 async fn mine_blocks(
@@ -435,9 +1047,90 @@ async fn get_peers(state: axum::extract::State<AppState>) -> Json<Vec<String>> {
     Json(guard.peers.clone())
 }
 
-async fn get_live_peers(state: axum::extract::State<AppState>) -> Json<Vec<String>> {
+async fn get_live_peers(state: axum::extract::State<AppState>) -> Json<Vec<LivePeerResponse>> {
     let guard = state.inner.lock().await;
-    Json(guard.live_peers.iter().cloned().collect())
+    Json(
+        guard
+            .live_peers
+            .iter()
+            .map(|(address, peer)| {
+                let stats = peer.stats();
+                LivePeerResponse {
+                    address: address.clone(),
+                    outbound: peer.outbound,
+                    node_key: peer.node_key.get().map(hex::encode),
+                    last_seen: stats.last_seen,
+                    ping_ms: stats.ping.map(|ping| ping.as_secs_f64() * 1_000.0),
+                }
+            })
+            .collect(),
+    )
+}
+
+async fn get_bans(state: axum::extract::State<AppState>) -> Json<Vec<BanResponse>> {
+    let mut guard = state.inner.lock().await;
+    let bans = guard.bans.list(now_timestamp());
+    Json(
+        bans.into_iter()
+            .map(|(ip, ban)| BanResponse {
+                address: ip.to_string(),
+                banned_until: ban.until,
+                reason: ban.reason,
+            })
+            .collect(),
+    )
+}
+
+async fn add_ban(
+    state: axum::extract::State<AppState>,
+    Json(req): Json<BanRequest>,
+) -> Result<Json<BanResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ip = parse_ip(&req.address)?;
+    let duration = req.duration_secs.unwrap_or(DEFAULT_BAN_SECS);
+    if duration == 0 {
+        return Err(bad_request("duration must be positive"));
+    }
+    let until = now_timestamp().saturating_add(duration);
+    let reason = req.reason.unwrap_or_else(|| "banned over rpc".to_string());
+    {
+        let mut guard = state.inner.lock().await;
+        guard.bans.ban(ip, until, reason.clone());
+        guard.bans.save().map_err(internal_error)?;
+    }
+    state.disconnect(ip);
+    Ok(Json(BanResponse {
+        address: ip.to_string(),
+        banned_until: until,
+        reason,
+    }))
+}
+
+async fn remove_ban(
+    state: axum::extract::State<AppState>,
+    Path(address): Path<String>,
+) -> Result<Json<UnbanResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ip = parse_ip(&address)?;
+    let mut guard = state.inner.lock().await;
+    let removed = usize::from(guard.bans.unban(ip));
+    guard.bans.save().map_err(internal_error)?;
+    Ok(Json(UnbanResponse { removed }))
+}
+
+async fn clear_bans(
+    state: axum::extract::State<AppState>,
+) -> Result<Json<UnbanResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let mut guard = state.inner.lock().await;
+    let removed = guard.bans.clear();
+    guard.bans.save().map_err(internal_error)?;
+    Ok(Json(UnbanResponse { removed }))
+}
+
+/// An IP, or the IP of an `ip:port`.
+fn parse_ip(address: &str) -> Result<IpAddr, (StatusCode, Json<ErrorResponse>)> {
+    address
+        .parse::<IpAddr>()
+        .or_else(|_| address.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .map_err(|_| bad_request("invalid ip address"))
 }
 
 fn parse_hash32(hash: &str) -> Result<Hash32, (StatusCode, Json<ErrorResponse>)> {
@@ -463,12 +1156,23 @@ fn bad_request(message: &str) -> (StatusCode, Json<ErrorResponse>) {
     )
 }
 
-fn internal_error(err: anyhow::Error) -> (StatusCode, Json<ErrorResponse>) {
+fn internal_error(err: impl Into<anyhow::Error>) -> (StatusCode, Json<ErrorResponse>) {
+    let err = err.into();
+    let corrupt = err
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<StorageError>())
+        .any(StorageError::is_corruption);
+    let error = if corrupt {
+        error!("storage corruption: {err:#}");
+        format!(
+            "storage corruption: {err:#}; stop the node and run `kexa-node verify-chain` or `kexa-node reindex`"
+        )
+    } else {
+        format!("internal error: {err}")
+    };
     (
         StatusCode::INTERNAL_SERVER_ERROR,
-        Json(ErrorResponse {
-            error: format!("internal error: {err}"),
-        }),
+        Json(ErrorResponse { error }),
     )
 }
 
@@ -479,7 +1183,7 @@ fn now_timestamp() -> u64 {
         .as_secs()
 }
 
-fn init_genesis(storage: &Storage, mode: &NetworkMode) -> Result<()> {
+fn init_genesis(storage: &dyn ChainStore, mode: &NetworkMode) -> Result<()> {
     let (block, expected_hash) = expected_genesis(mode)?;
 
     if storage.get_tip()?.is_some() {
@@ -505,10 +1209,7 @@ fn init_genesis(storage: &Storage, mode: &NetworkMode) -> Result<()> {
         return Ok(());
     }
 
-    storage.put_block(&expected_hash, &block)?;
-    storage.put_header(0, &block.header)?;
-    storage.put_height_hash(0, &expected_hash)?;
-    storage.set_tip(0, &expected_hash)?;
+    storage.put_genesis(&block)?;
     Ok(())
 }
 
@@ -522,8 +1223,8 @@ async fn mine_one_block(state: AppState, miner_address: &str) -> Result<Hash32>
     let address = Address::from_bech32(miner_address)?;
     let (height, prev_hash, mempool) = {
         let mut guard = state.inner.lock().await;
-        let (height, prev_hash) = guard.storage.get_tip()?.expect("tip");
-        let mempool = guard.mempool.drain(..).collect::<Vec<_>>();
+        let (height, prev_hash) = guard.storage.get_tip()?.context("tip missing")?;
+        let mempool = guard.mempool.take_all();
         (height, prev_hash, mempool)
     };
     let mut fee_total = 0u64;
@@ -582,51 +1283,165 @@ async fn calculate_fee(state: AppState, tx: &Transaction) -> Result<u64> {
     Ok(input_sum.saturating_sub(output_sum))
 }
 
-async fn apply_block(state: AppState, block: Block) -> Result<()> {
-    let guard = state.inner.lock().await;
-    validate_block(&guard.storage, &block)?;
-    for (idx, tx) in block.txs.iter().enumerate() {
-        if idx == 0 {
-            if !tx.inputs.is_empty() {
-                anyhow::bail!("coinbase has inputs");
-            }
-        } else {
-            for input in &tx.inputs {
-                if guard.storage.get_utxo(&input.outpoint)?.is_none() {
-                    anyhow::bail!("missing utxo when applying block");
-                }
-                guard.storage.delete_utxo(&input.outpoint)?;
+/// Validates and connects `block`, then any orphans waiting on it. Returns
+/// whether it extended the chain, as opposed to being one we had or one
+/// below a UTXO snapshot.
+async fn apply_block(state: AppState, block: Block) -> Result<bool> {
+    let mut guard = state.inner.lock().await;
+    let height = block.header.height;
+    if guard
+        .history
+        .as_ref()
+        .is_some_and(|history| height <= history.snapshot.base.height)
+    {
+        apply_history_block(&state, &mut guard, &block)?;
+        return Ok(false);
+    }
+    // History requests resend blocks we already have; they are not errors.
+    if guard.storage.get_hash_by_height(height)? == Some(block.header.hash()) {
+        return Ok(false);
+    }
+    if let Err(err) = validate_block(&guard.storage, &block) {
+        return Err(blame_invalid_block(&guard.storage, &block, err));
+    }
+    if block.txs.first().is_some_and(|tx| !tx.inputs.is_empty()) {
+        anyhow::bail!("coinbase has inputs");
+    }
+    guard.storage.connect_block(&block)?;
+    evict_spent(&mut guard.mempool, &block);
+    state.announce(InvItem::Block(block.header.hash()));
+    connect_orphans(&state, &mut guard, block.header.hash())?;
+    Ok(true)
+}
+
+/// Connects the held orphans descending from `parent`, which was just
+/// connected. Invalid ones are dropped, since whoever sent them is unknown.
+fn connect_orphans(state: &AppState, chain: &mut ChainState, parent: Hash32) -> Result<()> {
+    let mut parents = vec![parent];
+    while let Some(parent) = parents.pop() {
+        for block in chain.orphans.take_children(&parent) {
+            let hash = block.header.hash();
+            if let Err(err) = validate_block(&chain.storage, &block) {
+                debug!("dropped orphan block {}: {err}", hex::encode(hash.0));
+                continue;
             }
+            chain.storage.connect_block(&block)?;
+            evict_spent(&mut chain.mempool, &block);
+            state.announce(InvItem::Block(hash));
+            parents.push(hash);
         }
-        for (index, output) in tx.outputs.iter().enumerate() {
-            let outpoint = OutPoint {
-                txid: tx.txid(),
-                index: index as u32,
-            };
-            guard.storage.put_utxo(&outpoint, output)?;
+    }
+    Ok(())
+}
+
+/// Validates and connects a block at or below the snapshot base in the
+/// history store. Reaching the base checks the snapshot against the validated
+/// chain, and stops the node if they disagree.
+fn apply_history_block(state: &AppState, chain: &mut ChainState, block: &Block) -> Result<()> {
+    let Some(history) = &chain.history else {
+        return Ok(());
+    };
+    let height = block.header.height;
+    if history.store.get_hash_by_height(height)? == Some(block.header.hash()) {
+        return Ok(());
+    }
+    validate_block(&history.store, block)?;
+    history.store.connect_block(block)?;
+    let base_height = history.snapshot.base.height;
+    if height < base_height {
+        if height.is_multiple_of(1_000) {
+            info!("snapshot history validated to height {height}/{base_height}");
         }
+        return Ok(());
+    }
+    let res = complete_history(chain);
+    if res.is_err() && chain.storage.snapshot_failure()?.is_some() {
+        error!("utxo snapshot does not match the validated chain; its UTXO set cannot be trusted, stopping");
+        state.shutdown.notify_one();
+    }
+    res
+}
+
+/// Once the history store reaches the snapshot base, checks the snapshot
+/// against it and, if they agree, hands the history over to the main store.
+/// The history is kept when they disagree, which the storage records so the
+/// node refuses to start from the data dir again.
+fn complete_history(chain: &mut ChainState) -> Result<()> {
+    let Some(history) = &chain.history else {
+        return Ok(());
+    };
+    let base_height = history.snapshot.base.height;
+    let (height, _) = history.store.get_tip()?.context("tip missing")?;
+    if height < base_height {
+        return Ok(());
     }
-    let hash = block.header.hash();
-    guard.storage.put_block(&hash, &block)?;
-    guard
+    chain
         .storage
-        .put_header(block.header.height, &block.header)?;
-    guard.storage.put_height_hash(block.header.height, &hash)?;
-    guard.storage.set_tip(block.header.height, &hash)?;
+        .complete_snapshot_history(&history.store)
+        .context("completing utxo snapshot history")?;
+    info!("utxo snapshot at height {base_height} matches the validated chain");
+    if let Some(HistorySync { store, dir, .. }) = chain.history.take() {
+        drop(store);
+        if let Err(err) = std::fs::remove_dir_all(&dir) {
+            error!("failed to remove {}: {err}", dir.display());
+        }
+    }
     Ok(())
 }
 
-fn validate_tx(storage: &Storage, mempool: &[Transaction], tx: &Transaction) -> Result<()> {
+/// Block-local view of the UTXO set. Outputs created by earlier transactions in
+/// the block are spendable and outputs they spent are hidden, so dependent
+/// transactions validate as long as they appear in topological order.
+struct UtxoOverlay<'a> {
+    storage: &'a dyn ChainStore,
+    created: HashMap<([u8; 32], u32), TxOut>,
+    spent: HashSet<([u8; 32], u32)>,
+}
+
+impl<'a> UtxoOverlay<'a> {
+    fn new(storage: &'a dyn ChainStore) -> Self {
+        Self {
+            storage,
+            created: HashMap::new(),
+            spent: HashSet::new(),
+        }
+    }
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<TxOut>> {
+        let key = (outpoint.txid.0, outpoint.index);
+        if self.spent.contains(&key) {
+            return Ok(None);
+        }
+        if let Some(output) = self.created.get(&key) {
+            return Ok(Some(output.clone()));
+        }
+        Ok(self.storage.get_utxo(outpoint)?)
+    }
+
+    fn apply_tx(&mut self, tx: &Transaction) {
+        for input in &tx.inputs {
+            let key = (input.outpoint.txid.0, input.outpoint.index);
+            self.created.remove(&key);
+            self.spent.insert(key);
+        }
+        let txid = tx.txid();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            self.created.insert((txid.0, index as u32), output.clone());
+        }
+    }
+}
+
+fn validate_tx(utxos: &UtxoOverlay, mempool: &[Transaction], tx: &Transaction) -> Result<()> {
     if tx.inputs.is_empty() {
         anyhow::bail!("non-coinbase tx must have inputs");
     }
     let mut input_sum = 0u64;
-    let mut seen = std::collections::HashSet::new();
+    let mut seen = HashSet::new();
     for input in &tx.inputs {
         if !seen.insert((input.outpoint.txid.0, input.outpoint.index)) {
             anyhow::bail!("double spend in tx");
         }
-        let utxo = storage.get_utxo(&input.outpoint)?.context("missing utxo")?;
+        let utxo = utxos.get_utxo(&input.outpoint)?.context("missing utxo")?;
         input_sum = input_sum.saturating_add(utxo.amount);
         let input_address = Address::from_pubkey_bytes(&input.pubkey).context("invalid pubkey")?;
         if input_address.payload != utxo.address {
@@ -657,10 +1472,31 @@ fn validate_tx(storage: &Storage, mempool: &[Transaction], tx: &Transaction) ->
     Ok(())
 }
 
-fn validate_block(storage: &Storage, block: &Block) -> Result<()> {
-    if block.txs.is_empty() {
-        anyhow::bail!("block empty");
-    }
+/// Blames the sender for a block that failed validation, unless it does not
+/// extend our tip, which races and other branches cause, or storage failed.
+fn blame_invalid_block(
+    storage: &dyn ChainStore,
+    block: &Block,
+    err: anyhow::Error,
+) -> anyhow::Error {
+    let storage_failed = err.chain().any(|cause| cause.is::<StorageError>());
+    let extends_tip = storage
+        .get_tip()
+        .ok()
+        .flatten()
+        .is_some_and(|(height, hash)| {
+            block.header.height == height + 1 && block.header.prev_hash == hash
+        });
+    if storage_failed || !extends_tip {
+        return err;
+    }
+    misbehaving(BAN_THRESHOLD, format!("invalid block: {err}"))
+}
+
+fn validate_block(storage: &dyn ChainStore, block: &Block) -> Result<()> {
+    if block.txs.is_empty() {
+        anyhow::bail!("block empty");
+    }
     if block.header.height == 0 {
         if let Some((tip_height, tip_hash)) = storage.get_tip()? {
             if tip_height == 0 && tip_hash == block.header.hash() {
@@ -671,18 +1507,10 @@ fn validate_block(storage: &Storage, block: &Block) -> Result<()> {
         return Ok(());
     }
     let (tip_height, tip_hash) = storage.get_tip()?.context("tip missing")?;
-    if block.header.height != tip_height + 1 {
-        anyhow::bail!("unexpected height");
-    }
-    if block.header.prev_hash != tip_hash {
-        anyhow::bail!("prev hash mismatch");
-    }
+    validate_header(tip_height, tip_hash, &block.header)?;
     if merkle_root(&block.txs) != block.header.merkle_root {
         anyhow::bail!("merkle mismatch");
     }
-    if !check_pow(&block.header) {
-        anyhow::bail!("pow invalid");
-    }
     if COINBASE_MATURITY != 0 {
         anyhow::bail!("coinbase maturity not supported in v0");
     }
@@ -690,7 +1518,10 @@ fn validate_block(storage: &Storage, block: &Block) -> Result<()> {
     if !coinbase.inputs.is_empty() {
         anyhow::bail!("coinbase inputs present");
     }
-    let mut spent_in_block = std::collections::HashSet::new();
+    let mut spent_in_block = HashSet::new();
+    // Coinbase outputs are deliberately left out of the overlay: they only
+    // become spendable once the block is connected.
+    let mut utxos = UtxoOverlay::new(storage);
     let coinbase_total: u64 = coinbase.outputs.iter().map(|o| o.amount).sum();
     let mut total_fees = 0u64;
     for (idx, tx) in block.txs.iter().enumerate() {
@@ -700,8 +1531,9 @@ fn validate_block(storage: &Storage, block: &Block) -> Result<()> {
                     anyhow::bail!("intra-block double spend");
                 }
             }
-            validate_tx(storage, &[], tx)?;
-            total_fees = total_fees.saturating_add(tx_fee(storage, tx)?);
+            validate_tx(&utxos, &[], tx)?;
+            total_fees = total_fees.saturating_add(tx_fee(&utxos, tx)?);
+            utxos.apply_tx(tx);
         }
     }
     let max_reward = block_subsidy(block.header.height).saturating_add(total_fees);
@@ -711,10 +1543,10 @@ fn validate_block(storage: &Storage, block: &Block) -> Result<()> {
     Ok(())
 }
 
-fn tx_fee(storage: &Storage, tx: &Transaction) -> Result<u64> {
+fn tx_fee(utxos: &UtxoOverlay, tx: &Transaction) -> Result<u64> {
     let mut input_sum = 0u64;
     for input in &tx.inputs {
-        let utxo = storage.get_utxo(&input.outpoint)?.context("missing utxo")?;
+        let utxo = utxos.get_utxo(&input.outpoint)?.context("missing utxo")?;
         input_sum = input_sum.saturating_add(utxo.amount);
     }
     let output_sum: u64 = tx.outputs.iter().map(|o| o.amount).sum();
@@ -726,87 +1558,341 @@ async fn start_p2p_listener(state: AppState, addr: SocketAddr) -> Result<()> {
     info!("p2p listening on {addr}");
     loop {
         let (stream, peer_addr) = listener.accept().await?;
-        let peer_state = state.clone();
-        tokio::spawn(async move {
-            let peer_id = peer_addr.to_string();
-            {
-                let mut guard = peer_state.inner.lock().await;
-                guard.live_peers.insert(peer_id.clone());
-            }
-            let res = handle_peer(peer_state.clone(), stream).await;
-            {
-                let mut guard = peer_state.inner.lock().await;
-                guard.live_peers.remove(&peer_id);
-            }
-            if let Err(err) = res {
-                let s = err.to_string();
-                if s.contains("unexpected height")
-                    || s.contains("prev hash mismatch")
-                    || s.contains("message too large")
-                {
-                    debug!("peer sync noise: {s}");
-                } else {
-                    error!("peer error: {err}");
+        let banned = {
+            let guard = state.inner.lock().await;
+            guard.bans.is_banned(peer_addr.ip(), now_timestamp())
+        };
+        if banned {
+            debug!("refused connection from banned {peer_addr}");
+            continue;
+        }
+        tokio::spawn(accept_connection(state.clone(), stream, peer_addr));
+    }
+}
+
+async fn accept_connection(state: AppState, stream: TcpStream, peer_addr: SocketAddr) {
+    let admitted = {
+        let mut guard = state.inner.lock().await;
+        admit_inbound(&mut guard, &state.limits, peer_addr)
+    };
+    let peer = match admitted {
+        Ok(peer) => peer,
+        Err(reason) => {
+            debug!("refused connection from {peer_addr}: {reason}");
+            return;
+        }
+    };
+    let accept = Transport::accept(stream, &state.node_key, state.magic, state.encryption);
+    let err = match tokio::time::timeout(HANDSHAKE_TIMEOUT, accept).await {
+        Ok(Ok(transport)) => {
+            return run_connection(state, transport, peer_addr.to_string(), peer).await;
+        }
+        Ok(Err(err)) => err,
+        Err(_) => anyhow::anyhow!("timed out"),
+    };
+    debug!("transport handshake with {peer_addr} failed: {err}");
+    state
+        .inner
+        .lock()
+        .await
+        .live_peers
+        .remove(&peer_addr.to_string());
+}
+
+/// Lists an inbound connection from `addr` in `live_peers`, evicting another
+/// inbound peer if every inbound slot is taken. `Err` says why the
+/// connection is refused instead.
+fn admit_inbound(
+    chain: &mut ChainState,
+    limits: &ConnLimits,
+    addr: SocketAddr,
+) -> Result<Arc<LivePeer>, &'static str> {
+    let victim = {
+        // Inbound peers are listed under the address they connected from.
+        let inbound: Vec<(&String, IpAddr, &Arc<LivePeer>)> = chain
+            .live_peers
+            .iter()
+            .filter(|(_, peer)| !peer.outbound)
+            .filter_map(|(id, peer)| Some((id, id.parse::<SocketAddr>().ok()?.ip(), peer)))
+            .collect();
+        let from_ip = inbound.iter().filter(|(_, ip, _)| *ip == addr.ip());
+        if from_ip.count() >= limits.max_per_ip {
+            return Err("too many connections from its IP");
+        }
+        let from_subnet = inbound
+            .iter()
+            .filter(|(_, ip, _)| subnet(*ip) == subnet(addr.ip()));
+        if from_subnet.count() >= limits.max_per_subnet {
+            return Err("too many connections from its subnet");
+        }
+        if inbound.len() >= limits.max_inbound {
+            let candidates: Vec<EvictionCandidate> = inbound
+                .iter()
+                .map(|(_, ip, peer)| {
+                    let stats = peer.stats();
+                    EvictionCandidate {
+                        ip: *ip,
+                        connected_at: peer.connected_at,
+                        ping: stats.ping,
+                        last_block: stats.last_block,
+                    }
+                })
+                .collect();
+            let victim = select_victim(&candidates).ok_or("inbound slots are full")?;
+            Some(inbound[victim].0.clone())
+        } else {
+            None
+        }
+    };
+    if let Some(victim) = victim {
+        debug!("evicting {victim} to make room for {addr}");
+        if let Some(peer) = chain.live_peers.remove(&victim) {
+            peer.evicted.notify_one();
+        }
+    }
+    let peer = Arc::new(LivePeer::new(false));
+    chain.live_peers.insert(addr.to_string(), peer.clone());
+    Ok(peer)
+}
+
+/// Runs a peer connection to the end, listed in `live_peers` meanwhile as
+/// `peer`.
+async fn run_connection(
+    state: AppState,
+    transport: Transport,
+    peer_id: String,
+    peer: Arc<LivePeer>,
+) {
+    if let Some(key) = transport.remote_key {
+        let _ = peer.node_key.set(key);
+    }
+    let outbound = peer.outbound;
+    let ip = transport.remote.ip();
+    let res = handle_peer(state.clone(), transport, peer).await;
+    {
+        let mut guard = state.inner.lock().await;
+        guard.live_peers.remove(&peer_id);
+        if outbound && res.is_err() {
+            guard.addrs.failed(&peer_id);
+        }
+        let misbehavior = res.as_ref().err().and_then(|err| {
+            err.chain()
+                .find_map(|cause| cause.downcast_ref::<Misbehavior>())
+        });
+        if let Some(misbehavior) = misbehavior {
+            if guard.bans.misbehaved(ip, misbehavior, now_timestamp()) {
+                warn!("banned {ip} for misbehavior: {misbehavior}");
+                if let Err(err) = guard.bans.save() {
+                    error!("failed to save bans: {err}");
                 }
+                state.disconnect(ip);
             }
-        });
+        }
+    }
+    if let Err(err) = res {
+        let s = err.to_string();
+        if s.contains("unexpected height")
+            || s.contains("prev hash mismatch")
+            || s.contains("message too large")
+        {
+            debug!("peer sync noise: {s}");
+        } else if s == EVICTED {
+            debug!("disconnected peer {peer_id}: {err}");
+        } else {
+            warn!("disconnected peer {peer_id}: {err}");
+        }
     }
 }
 
+/// Why a connection is dropped for a new inbound peer.
+const EVICTED: &str = "evicted to make room for another peer";
+
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How often the address table is written to disk while running.
+const ADDRS_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
 async fn connect_peers(state: AppState) {
+    let mut last_save = tokio::time::Instant::now();
     loop {
         if let Err(err) = sync_with_peers(state.clone()).await {
             error!("peer sync error: {err}");
         }
+        if last_save.elapsed() >= ADDRS_SAVE_INTERVAL {
+            if let Err(err) = state.inner.lock().await.addrs.save() {
+                error!("failed to save peer addresses: {err}");
+            }
+            last_save = tokio::time::Instant::now();
+        }
+        // Cached UTXO changes otherwise only reach disk as blocks connect.
+        if let Err(err) = state.inner.lock().await.storage.flush_if_due() {
+            error!("utxo cache flush failed: {err}");
+        }
         tokio::time::sleep(std::time::Duration::from_secs(2)).await;
     }
 }
 
-async fn handle_peer(state: AppState, mut stream: TcpStream) -> Result<()> {
-    let (height, tip) = {
-        let guard = state.inner.lock().await;
-        guard.storage.get_tip()?.expect("tip")
-    };
-    let version = Message::Version { height, tip };
-    let data = encode_message(&version)?;
-    stream.write_all(&data).await?;
+async fn handle_peer(state: AppState, transport: Transport, peer: Arc<LivePeer>) -> Result<()> {
+    let Transport {
+        reader,
+        writer,
+        remote,
+        ..
+    } = transport;
+    let (reader, writer) =
+        PeerConnection::new(state.magic, reader, writer, PEER_READ_TIMEOUT).into_split();
+    let (sender, incoming) = mpsc::channel(16);
+    let reader = tokio::spawn(read_messages(reader, sender));
+    let result = run_peer(state, writer, incoming, remote, peer).await;
+    reader.abort();
+    result
+}
 
+/// Forwards the peer's messages until it disconnects, so [`run_peer`] can
+/// wait for them and for local announcements at the same time.
+async fn read_messages(
+    mut reader: MessageReader<PeerReader>,
+    sender: mpsc::Sender<Result<Message>>,
+) {
     loop {
-        let mut len_bytes = [0u8; 4];
-        match tokio::time::timeout(
-            std::time::Duration::from_secs(2),
-            stream.read_exact(&mut len_bytes),
-        )
-        .await
-        {
-            Ok(Ok(_n)) => {}     // got bytes, continue normal decode path
-            Ok(Err(_)) => break, // socket closed / read error
-            Err(_) => {
-                // idle: poll tip so new blocks propagate without reconnect storms
+        let message = match reader.recv().await {
+            Ok(Some(message)) => Ok(message),
+            // A failing socket is the peer disconnecting.
+            Ok(None) | Err(FrameError::Io(_)) => return,
+            // Another network's magic is a misconfiguration; only frames no
+            // node of our network sends are the peer misbehaving.
+            Err(err) if err.is_malformed() => Err(misbehaving(MALFORMED_PENALTY, err.to_string())),
+            Err(err) => Err(err.into()),
+        };
+        let failed = message.is_err();
+        if sender.send(message).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn run_peer(
+    state: AppState,
+    mut writer: MessageWriter<PeerWriter>,
+    mut incoming: mpsc::Receiver<Result<Message>>,
+    remote: SocketAddr,
+    peer: Arc<LivePeer>,
+) -> Result<()> {
+    // Subscribe before sending our tip so no block connected after it goes
+    // unannounced.
+    let mut announcements = state.announcements.subscribe();
+    let mut banned = state.banned.subscribe();
+    let version = local_version(&state).await?;
+    writer.send(&version).await?;
+    let connected_at = tokio::time::Instant::now();
+    let mut handshake = Handshake::default();
+    let mut known_txs = KnownTxs::default();
+    let mut sync = HeaderSync::default();
+    // Last block of the snapshot history batch requested from this peer.
+    let mut history_batch_end = None;
+    let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+    ping_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // Nonce and send time of the ping awaiting its pong.
+    let mut ping: Option<(u64, tokio::time::Instant)> = None;
+
+    loop {
+        let message = tokio::select! {
+            message = incoming.recv() => match message {
+                Some(message) => message?,
+                None => break, // socket closed / read error
+            },
+            _ = ping_timer.tick(), if handshake.is_done() => {
+                if let Some((_, sent)) = ping {
+                    if sent.elapsed() >= PING_TIMEOUT {
+                        anyhow::bail!("ping timeout");
+                    }
+                    continue;
+                }
+                let nonce = rand::random();
+                writer.send(&Message::Ping { nonce }).await?;
+                ping = Some((nonce, tokio::time::Instant::now()));
+                continue;
+            }
+            announcement = announcements.recv() => {
+                if !handshake.is_done() {
+                    continue;
+                }
+                match announcement {
+                    Ok(InvItem::Block(hash)) => {
+                        let msg = Message::Inv {
+                            items: vec![InvItem::Block(hash)],
+                        };
+                        writer.send(&msg).await?;
+                    }
+                    // Missed announcements are recovered by the mempool scan
+                    // for transactions and by the next tip poll for blocks.
+                    Ok(InvItem::Tx(_)) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                        announce_txs(&state, &mut writer, &mut known_txs).await?;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+                continue;
+            }
+            ip = banned.recv() => {
+                match ip {
+                    Ok(ip) if ip == remote.ip() => anyhow::bail!("peer banned"),
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        let guard = state.inner.lock().await;
+                        if guard.bans.is_banned(remote.ip(), now_timestamp()) {
+                            anyhow::bail!("peer banned");
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+                continue;
+            }
+            () = peer.evicted.notified() => anyhow::bail!(EVICTED),
+            () = tokio::time::sleep(std::time::Duration::from_secs(2)) => {
+                if !handshake.is_done() {
+                    if connected_at.elapsed() > HANDSHAKE_TIMEOUT {
+                        anyhow::bail!("handshake timed out");
+                    }
+                    continue;
+                }
+                // idle: poll tip so blocks we missed still propagate
                 let msg = Message::GetTip;
-                let data = encode_message(&msg)?;
-                if stream.write_all(&data).await.is_err() {
+                if writer.send(&msg).await.is_err() {
                     break;
                 }
                 continue;
             }
-        }
-
-        let len = u32::from_be_bytes(len_bytes) as usize;
-        if len > MAX_MESSAGE_SIZE {
-            anyhow::bail!("message too large");
-        }
-        let mut payload = vec![0u8; len];
-        stream.read_exact(&mut payload).await?;
-        let message = Message::try_from_slice(&payload)?;
+        };
+        peer.stats().last_seen = now_timestamp();
         match message {
             Message::Version {
+                protocol_version,
+                services,
+                genesis,
+                nonce,
+                user_agent,
+                listen_port,
                 height: peer_height,
                 tip: peer_tip,
             } => {
+                if handshake.services.is_some() {
+                    return Err(misbehaving(PROTOCOL_PENALTY, "duplicate version message"));
+                }
+                check_version(&state, protocol_version, genesis, nonce, &user_agent).await?;
+                debug!(
+                    "peer {user_agent} speaks protocol {protocol_version} at height {peer_height}"
+                );
+                handshake.services = Some(services);
+                handshake.listen_port = listen_port;
+                handshake.height = peer_height;
+                writer.send(&Message::Verack).await?;
+                if handshake.is_done() {
+                    handshake_done(&state, &handshake, remote, peer.outbound, &mut writer).await?;
+                    announce_txs(&state, &mut writer, &mut known_txs).await?;
+                }
                 let (local_height, local_tip) = {
                     let guard = state.inner.lock().await;
-                    guard.storage.get_tip()?.expect("tip")
+                    guard.storage.get_tip()?.context("tip missing")?
                 };
                 match decide_tip_action(
                     IncomingKind::Version,
@@ -815,18 +1901,34 @@ async fn handle_peer(state: AppState, mut stream: TcpStream) -> Result<()> {
                     local_height,
                     local_tip,
                 ) {
-                    TipAction::RequestBlocks { start_height } => {
-                        let msg = Message::GetBlocks { start_height };
-                        let data = encode_message(&msg)?;
-                        stream.write_all(&data).await?;
+                    TipAction::RequestHeaders => {
+                        request_headers(&state, &mut sync, &mut writer, Some(peer_tip)).await?;
                     }
                     TipAction::SendTip { height, tip } => {
                         let msg = Message::Tip { height, tip };
-                        let data = encode_message(&msg)?;
-                        stream.write_all(&data).await?;
+                        writer.send(&msg).await?;
                     }
                     TipAction::Noop => {}
                 }
+                if services & SERVICE_FULL_HISTORY != 0 {
+                    history_batch_end = request_history(&state, peer_height, &mut writer).await?;
+                }
+            }
+            Message::Verack => {
+                if handshake.acked {
+                    return Err(misbehaving(PROTOCOL_PENALTY, "duplicate verack message"));
+                }
+                handshake.acked = true;
+                if handshake.is_done() {
+                    handshake_done(&state, &handshake, remote, peer.outbound, &mut writer).await?;
+                    announce_txs(&state, &mut writer, &mut known_txs).await?;
+                }
+            }
+            _ if !handshake.is_done() => {
+                return Err(misbehaving(
+                    PROTOCOL_PENALTY,
+                    "message before handshake completed",
+                ));
             }
             Message::Tip {
                 height: peer_height,
@@ -834,7 +1936,7 @@ async fn handle_peer(state: AppState, mut stream: TcpStream) -> Result<()> {
             } => {
                 let (local_height, local_tip) = {
                     let guard = state.inner.lock().await;
-                    guard.storage.get_tip()?.expect("tip")
+                    guard.storage.get_tip()?.context("tip missing")?
                 };
                 match decide_tip_action(
                     IncomingKind::Tip,
@@ -843,49 +1945,100 @@ async fn handle_peer(state: AppState, mut stream: TcpStream) -> Result<()> {
                     local_height,
                     local_tip,
                 ) {
-                    TipAction::RequestBlocks { start_height } => {
-                        let msg = Message::GetBlocks { start_height };
-                        let data = encode_message(&msg)?;
-                        stream.write_all(&data).await?;
+                    TipAction::RequestHeaders => {
+                        request_headers(&state, &mut sync, &mut writer, Some(peer_tip)).await?;
                     }
                     TipAction::SendTip { height, tip } => {
                         let msg = Message::Tip { height, tip };
-                        let data = encode_message(&msg)?;
-                        stream.write_all(&data).await?;
+                        writer.send(&msg).await?;
                     }
                     TipAction::Noop => {}
                 }
             }
             Message::GetBlocks { start_height } => {
-                let tip_height = {
-                    let guard = state.inner.lock().await;
-                    guard.storage.get_tip()?.expect("tip").0
-                };
-                for height in start_height..=tip_height {
-                    let hash = {
-                        let guard = state.inner.lock().await;
-                        guard.storage.get_hash_by_height(height)?.expect("hash")
-                    };
-                    let block = {
-                        let guard = state.inner.lock().await;
-                        guard.storage.get_block(&hash)?.expect("block")
+                // Read without the chain lock, which sending a batch to a
+                // slow peer would otherwise hold up.
+                let blocks = state.inner.lock().await.storage.block_reader()?;
+                for height in (start_height..).take(MAX_BLOCKS_PER_GETBLOCKS) {
+                    let Some(block) = blocks.block_at(height)? else {
+                        break;
                     };
-                    let msg = Message::Block { block };
-                    let data = encode_message(&msg)?;
-                    stream.write_all(&data).await?;
+                    writer.send(&Message::Block { block }).await?;
                 }
             }
             Message::Block { block } => {
-                apply_block(state.clone(), block).await?;
+                // An announced block may be ahead of us by more than one;
+                // hold it and fetch its parents instead of rejecting it.
+                if is_ahead_of_tip(&state, &block).await? {
+                    match add_orphan(&state, block).await? {
+                        Some(parent) => {
+                            let msg = Message::GetData {
+                                items: vec![InvItem::Block(parent)],
+                            };
+                            writer.send(&msg).await?;
+                        }
+                        None => request_headers(&state, &mut sync, &mut writer, None).await?,
+                    }
+                    continue;
+                }
+                let (height, hash) = (block.header.height, block.header.hash());
+                if apply_block(state.clone(), block).await? {
+                    peer.stats().last_block = Some(now_timestamp());
+                }
+                if history_batch_end == Some(height) {
+                    history_batch_end =
+                        request_history(&state, handshake.height, &mut writer).await?;
+                }
+                if sync.received(&hash) {
+                    if let Some(target) = sync.best_height() {
+                        if height.is_multiple_of(1_000) || height == target {
+                            info!("synced block {height}/{target}");
+                        }
+                    }
+                    request_blocks(&mut sync, &mut writer).await?;
+                }
+            }
+            Message::GetHeaders { locator } => {
+                let headers = {
+                    let guard = state.inner.lock().await;
+                    let start_height = locate(&guard.storage, &locator)? + 1;
+                    let mut headers = Vec::new();
+                    for height in (start_height..).take(MAX_HEADERS) {
+                        match guard.storage.get_header(height)? {
+                            Some(header) => headers.push(header),
+                            None => break,
+                        }
+                    }
+                    headers
+                };
+                let msg = Message::Headers { headers };
+                writer.send(&msg).await?;
+            }
+            Message::Headers { headers } => {
+                let more = {
+                    let guard = state.inner.lock().await;
+                    sync.add_headers(&guard.storage, &headers)?
+                };
+                if let (Some(last), Some(target)) = (headers.last(), sync.best_height()) {
+                    if more.is_none() || last.height.is_multiple_of(10_000) {
+                        info!("synced headers to height {target}");
+                    }
+                }
+                if let Some(last) = more {
+                    let msg = Message::GetHeaders {
+                        locator: vec![last],
+                    };
+                    writer.send(&msg).await?;
+                }
+                request_blocks(&mut sync, &mut writer).await?;
             }
             Message::GetTip => {
                 let (height, tip) = {
                     let guard = state.inner.lock().await;
-                    guard.storage.get_tip()?.expect("tip")
+                    guard.storage.get_tip()?.context("tip missing")?
                 };
                 let msg = Message::Tip { height, tip };
-                let data = encode_message(&msg)?;
-                stream.write_all(&data).await?;
+                writer.send(&msg).await?;
             }
             Message::GetBlock { hash } => {
                 let block = {
@@ -894,62 +2047,447 @@ async fn handle_peer(state: AppState, mut stream: TcpStream) -> Result<()> {
                 };
                 if let Some(block) = block {
                     let msg = Message::Block { block };
-                    let data = encode_message(&msg)?;
-                    stream.write_all(&data).await?;
+                    writer.send(&msg).await?;
+                }
+            }
+            Message::Inv { items } => {
+                let wanted: Vec<InvItem> = {
+                    let guard = state.inner.lock().await;
+                    let mut wanted = Vec::new();
+                    for item in items.into_iter().take(MAX_INV_ITEMS) {
+                        let have = match item {
+                            InvItem::Block(hash) => guard.storage.get_block(&hash)?.is_some(),
+                            InvItem::Tx(txid) => {
+                                !known_txs.insert(txid) || guard.mempool.contains(&txid)
+                            }
+                        };
+                        if !have {
+                            wanted.push(item);
+                        }
+                    }
+                    wanted
+                };
+                if !wanted.is_empty() {
+                    let msg = Message::GetData { items: wanted };
+                    writer.send(&msg).await?;
+                }
+            }
+            Message::GetData { items } => {
+                let mut missing = Vec::new();
+                for item in items.into_iter().take(MAX_INV_ITEMS) {
+                    let msg = {
+                        let guard = state.inner.lock().await;
+                        match item {
+                            InvItem::Block(hash) => guard
+                                .storage
+                                .get_block(&hash)?
+                                .map(|block| Message::Block { block }),
+                            InvItem::Tx(txid) => guard
+                                .mempool
+                                .get(&txid)
+                                .map(|tx| Message::Tx { tx: tx.clone() }),
+                        }
+                    };
+                    match msg {
+                        Some(msg) => {
+                            writer.send(&msg).await?;
+                        }
+                        None => missing.push(item),
+                    }
+                }
+                if !missing.is_empty() {
+                    let msg = Message::NotFound { items: missing };
+                    writer.send(&msg).await?;
+                }
+            }
+            Message::Tx { tx } => {
+                let txid = tx.txid();
+                known_txs.insert(txid);
+                let mut guard = state.inner.lock().await;
+                // Relayed txs may already be confirmed or conflict with ours;
+                // that is not the peer misbehaving.
+                match accept_tx(&mut guard, tx) {
+                    Ok(true) => state.announce(InvItem::Tx(txid)),
+                    Ok(false) => {}
+                    Err(err) => debug!("ignoring relayed tx {}: {err}", hex::encode(txid.0)),
+                }
+            }
+            Message::GetAddr => {
+                let addrs = {
+                    let guard = state.inner.lock().await;
+                    guard.addrs.sample(now_timestamp())
+                };
+                let msg = Message::Addr { addrs };
+                writer.send(&msg).await?;
+            }
+            Message::Addr { addrs } => {
+                if addrs.len() > MAX_ADDRS {
+                    return Err(misbehaving(PROTOCOL_PENALTY, "too many addresses"));
                 }
+                let now = now_timestamp();
+                let mut guard = state.inner.lock().await;
+                let added = addrs
+                    .iter()
+                    .filter(|addr| guard.addrs.add(&addr.addr, addr.last_seen, now))
+                    .count();
+                debug!("learned {added} of {} peer addresses", addrs.len());
+            }
+            Message::NotFound { items } => {
+                debug!("peer no longer has {} requested items", items.len());
+                // Let a later announcement of these txs be requested again.
+                for item in &items {
+                    if let InvItem::Tx(txid) = item {
+                        known_txs.remove(txid);
+                    }
+                }
+                sync.not_found(&items);
+            }
+            Message::Ping { nonce } => {
+                writer.send(&Message::Pong { nonce }).await?;
             }
+            Message::Pong { nonce } => match ping {
+                Some((expected, sent)) if nonce == expected => {
+                    peer.stats().ping = Some(sent.elapsed());
+                    ping = None;
+                }
+                _ => debug!("ignoring unexpected pong"),
+            },
         }
     }
     Ok(())
 }
 
-async fn sync_with_peers(state: AppState) -> Result<()> {
-    let peers = {
+const USER_AGENT: &str = concat!("/kexa-node:", env!("CARGO_PKG_VERSION"), "/");
+
+/// How long a peer has to complete the handshake after connecting.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often each peer is pinged, starting right after the handshake.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Peers that leave a ping unanswered this long are disconnected.
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest wait for a peer's next message. Pings keep live peers well
+/// within it.
+const PEER_READ_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Handshake progress with one peer: its `Version`, and its `Verack` of ours.
+#[derive(Default)]
+struct Handshake {
+    /// Service bits from the peer's `Version`, once received.
+    services: Option<u64>,
+    listen_port: u16,
+    height: u64,
+    acked: bool,
+}
+
+impl Handshake {
+    fn is_done(&self) -> bool {
+        self.services.is_some() && self.acked
+    }
+}
+
+/// Records the peer's address once it has proven to speak our protocol.
+/// Outbound peers are asked for theirs; inbound ones are recorded under the
+/// port they said they listen on, for others to connect to.
+async fn handshake_done(
+    state: &AppState,
+    handshake: &Handshake,
+    remote: SocketAddr,
+    outbound: bool,
+    writer: &mut MessageWriter<PeerWriter>,
+) -> Result<()> {
+    let now = now_timestamp();
+    {
+        let mut guard = state.inner.lock().await;
+        if outbound {
+            guard.addrs.connected(remote, now);
+        } else if handshake.listen_port != 0 {
+            let listening = SocketAddr::new(remote.ip(), handshake.listen_port);
+            guard.addrs.add(&listening.to_string(), now, now);
+        }
+    }
+    if outbound {
+        writer.send(&Message::GetAddr).await?;
+    }
+    Ok(())
+}
+
+async fn local_version(state: &AppState) -> Result<Message> {
+    let guard = state.inner.lock().await;
+    let (height, tip) = guard.storage.get_tip()?.context("tip missing")?;
+    let genesis = guard
+        .storage
+        .get_hash_by_height(0)?
+        .context("genesis missing")?;
+    let services = if guard.history.is_some() {
+        0
+    } else {
+        SERVICE_FULL_HISTORY
+    };
+    Ok(Message::Version {
+        protocol_version: PROTOCOL_VERSION,
+        services,
+        genesis,
+        nonce: state.nonce,
+        user_agent: USER_AGENT.to_string(),
+        listen_port: state.listen_port,
+        height,
+        tip,
+    })
+}
+
+/// Checks a peer's `Version` against ours; the error is the reason to
+/// disconnect.
+async fn check_version(
+    state: &AppState,
+    protocol_version: u32,
+    genesis: Hash32,
+    nonce: u64,
+    user_agent: &str,
+) -> Result<()> {
+    if nonce == state.nonce {
+        anyhow::bail!("handshake failed: connected to ourselves");
+    }
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        anyhow::bail!(
+            "handshake failed: protocol version {protocol_version} is older than {MIN_PROTOCOL_VERSION}"
+        );
+    }
+    if user_agent.len() > MAX_USER_AGENT_LEN {
+        return Err(misbehaving(
+            PROTOCOL_PENALTY,
+            "handshake failed: user agent too long",
+        ));
+    }
+    let ours = {
         let guard = state.inner.lock().await;
-        guard.peers.clone()
+        guard
+            .storage
+            .get_hash_by_height(0)?
+            .context("genesis missing")?
     };
-    for peer in peers {
-        let already_connected = {
-            let guard = state.inner.lock().await;
-            guard.live_peers.contains(&peer)
-        };
-        if already_connected {
-            continue;
-        }
+    if genesis != ours {
+        anyhow::bail!(
+            "handshake failed: genesis mismatch, peer has {} and we have {}",
+            hex::encode(genesis.0),
+            hex::encode(ours.0)
+        );
+    }
+    Ok(())
+}
 
-        if let Ok(stream) = TcpStream::connect(&peer).await {
-            let peer_state = state.clone();
-            let peer_id = peer.clone();
-            tokio::spawn(async move {
-                {
-                    let mut guard = peer_state.inner.lock().await;
-                    guard.live_peers.insert(peer_id.clone());
-                }
-                let res = handle_peer(peer_state.clone(), stream).await;
-                {
-                    let mut guard = peer_state.inner.lock().await;
-                    guard.live_peers.remove(&peer_id);
-                }
-                if let Err(err) = res {
-                    let s = err.to_string();
-                    if s.contains("unexpected height")
-                        || s.contains("prev hash mismatch")
-                        || s.contains("message too large")
-                    {
-                        debug!("peer sync noise: {s}");
-                    } else {
-                        error!("peer error: {err}");
-                    }
-                }
-            });
+/// Sends the peer an `Inv` for mempool transactions it has not seen yet.
+async fn announce_txs(
+    state: &AppState,
+    writer: &mut MessageWriter<PeerWriter>,
+    known: &mut KnownTxs,
+) -> Result<()> {
+    let items: Vec<InvItem> = {
+        let guard = state.inner.lock().await;
+        known.retain(|txid| guard.mempool.contains(txid));
+        guard
+            .mempool
+            .iter()
+            .map(|tx| tx.txid())
+            .filter(|txid| known.insert(*txid))
+            .take(MAX_INV_ITEMS)
+            .map(InvItem::Tx)
+            .collect()
+    };
+    if items.is_empty() {
+        return Ok(());
+    }
+    let msg = Message::Inv { items };
+    writer.send(&msg).await?;
+    Ok(())
+}
+
+/// Starts a headers-first sync towards the peer's `tip`, if known, unless
+/// one is under way or that tip is on a branch we cannot switch to.
+async fn request_headers(
+    state: &AppState,
+    sync: &mut HeaderSync,
+    writer: &mut MessageWriter<PeerWriter>,
+    tip: Option<Hash32>,
+) -> Result<()> {
+    if !sync.start(tip) {
+        return Ok(());
+    }
+    let locator = {
+        let guard = state.inner.lock().await;
+        block_locator(&guard.storage)?
+    };
+    let msg = Message::GetHeaders { locator };
+    writer.send(&msg).await?;
+    Ok(())
+}
+
+/// Asks the peer for the next blocks of a headers-first sync.
+async fn request_blocks(
+    sync: &mut HeaderSync,
+    writer: &mut MessageWriter<PeerWriter>,
+) -> Result<()> {
+    let items = sync.next_blocks();
+    if items.is_empty() {
+        return Ok(());
+    }
+    let msg = Message::GetData { items };
+    writer.send(&msg).await?;
+    Ok(())
+}
+
+/// Whether `block` is further ahead of our tip than its next block, as
+/// announced blocks can be, so its parents need syncing first.
+async fn is_ahead_of_tip(state: &AppState, block: &Block) -> Result<bool> {
+    let guard = state.inner.lock().await;
+    if guard
+        .history
+        .as_ref()
+        .is_some_and(|history| block.header.height <= history.snapshot.base.height)
+    {
+        return Ok(false);
+    }
+    let (height, _) = guard.storage.get_tip()?.context("tip missing")?;
+    Ok(block.header.height > height + 1)
+}
+
+/// Holds `block`, which is ahead of our tip, in the orphan pool if its proof
+/// of work checks out. Returns the hash of its missing ancestor when that is
+/// the next block of our chain, the one gap a single request fills; longer
+/// gaps are left to a headers-first sync.
+async fn add_orphan(state: &AppState, block: Block) -> Result<Option<Hash32>> {
+    let mut guard = state.inner.lock().await;
+    let (tip_height, _) = guard.storage.get_tip()?.context("tip missing")?;
+    let (height, hash) = guard.orphans.missing_ancestor(&block.header);
+    if block.header.bits == DIFFICULTY_BITS && check_pow(&block.header) {
+        guard.orphans.insert(block, now_timestamp());
+    }
+    Ok((height == tip_height + 1).then_some(hash))
+}
+
+/// Asks the peer for the next batch of blocks below our UTXO snapshot, if
+/// any are missing and the peer has them. Returns the height of the last
+/// block asked for.
+async fn request_history(
+    state: &AppState,
+    peer_height: u64,
+    writer: &mut MessageWriter<PeerWriter>,
+) -> Result<Option<u64>> {
+    let Some(start_height) = history_request(state, peer_height).await? else {
+        return Ok(None);
+    };
+    writer.send(&Message::GetBlocks { start_height }).await?;
+    Ok(Some(start_height + MAX_BLOCKS_PER_GETBLOCKS as u64 - 1))
+}
+
+/// Height to request snapshot history from, when a peer that announced
+/// `peer_height` can serve the rest of it.
+async fn history_request(state: &AppState, peer_height: u64) -> Result<Option<u64>> {
+    let guard = state.inner.lock().await;
+    let Some(history) = &guard.history else {
+        return Ok(None);
+    };
+    if peer_height < history.snapshot.base.height {
+        return Ok(None);
+    }
+    let (height, _) = history.store.get_tip()?.context("tip missing")?;
+    Ok(Some(height + 1))
+}
+
+/// Dials configured `--peers` that are not connected, then tops outbound
+/// connections up to the limit from the address table.
+async fn sync_with_peers(state: AppState) -> Result<()> {
+    let now = now_timestamp();
+    let targets = {
+        let mut guard = state.inner.lock().await;
+        let outbound = guard
+            .live_peers
+            .values()
+            .filter(|peer| peer.outbound)
+            .count();
+        let slots = state.limits.max_outbound.saturating_sub(outbound);
+        let mut targets: Vec<String> = guard
+            .peers
+            .iter()
+            .filter(|peer| {
+                !guard.live_peers.contains_key(*peer) && !guard.bans.is_banned_addr(peer, now)
+            })
+            .take(slots)
+            .cloned()
+            .collect();
+        for _ in targets.len()..slots {
+            let Some(addr) = guard.addrs.select(
+                |addr| {
+                    guard.live_peers.contains_key(addr)
+                        || targets.iter().any(|t| t == addr)
+                        || guard.bans.is_banned_addr(addr, now)
+                },
+                now,
+            ) else {
+                break;
+            };
+            guard.addrs.attempt(&addr, now);
+            targets.push(addr);
         }
+        // Listed before connecting, so a slow dial is not repeated meanwhile.
+        targets
+            .into_iter()
+            .map(|addr| {
+                let peer = Arc::new(LivePeer::new(true));
+                guard.live_peers.insert(addr.clone(), peer.clone());
+                (addr, peer)
+            })
+            .collect::<Vec<_>>()
+    };
+    for (addr, peer) in targets {
+        tokio::spawn(dial(state.clone(), addr, peer));
     }
     Ok(())
 }
 
+async fn dial(state: AppState, addr: String, peer: Arc<LivePeer>) {
+    match open_transport(&state, &addr).await {
+        Ok(transport) => run_connection(state, transport, addr, peer).await,
+        Err(err) => {
+            debug!("failed to connect to {addr}: {err}");
+            let mut guard = state.inner.lock().await;
+            guard.live_peers.remove(&addr);
+            guard.addrs.failed(&addr);
+        }
+    }
+}
+
+/// Connects to `addr`, encrypted unless that is off. When encryption is only
+/// preferred, a peer that hangs up on the Noise handshake, as nodes without
+/// encryption support do, is reconnected to in plaintext.
+async fn open_transport(state: &AppState, addr: &str) -> Result<Transport> {
+    let connect = || async {
+        tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .context("connect timed out")?
+            .context("connect failed")
+    };
+    let stream = connect().await?;
+    if state.encryption == Encryption::Off {
+        return Transport::plain(stream);
+    }
+    let initiate = Transport::initiate(stream, &state.node_key, state.magic);
+    let err = match tokio::time::timeout(HANDSHAKE_TIMEOUT, initiate).await {
+        Ok(Ok(transport)) => return Ok(transport),
+        Ok(Err(err)) => err,
+        Err(_) => anyhow::anyhow!("encryption handshake timed out"),
+    };
+    let hung_up = err.chain().any(|cause| cause.is::<std::io::Error>());
+    if state.encryption == Encryption::Require || !hung_up {
+        return Err(err);
+    }
+    debug!("{addr} does not support encryption, reconnecting in plaintext");
+    Transport::plain(connect().await?)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum TipAction {
-    RequestBlocks { start_height: u64 },
+    RequestHeaders,
     SendTip { height: u64, tip: Hash32 },
     Noop,
 }
@@ -968,9 +2506,7 @@ fn decide_tip_action(
     local_tip: Hash32,
 ) -> TipAction {
     if peer_height > local_height {
-        return TipAction::RequestBlocks {
-            start_height: local_height + 1,
-        };
+        return TipAction::RequestHeaders;
     }
     if peer_height < local_height {
         return TipAction::SendTip {
@@ -1000,35 +2536,79 @@ mod tests {
     use ed25519_dalek::SigningKey;
     use http_body_util::BodyExt;
     use kexa_consensus::{MINEABLE_BLOCKS, SUBSIDY};
+    use kexa_p2p::PeerAddr;
+    use kexa_p2p::{decode_message, encode_message, FRAME_HEADER_LEN};
     use kexa_proto::TxIn;
+    use kexa_storage::MemoryStore;
     use rand::rngs::OsRng;
     use std::fs;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tower::ServiceExt;
 
     fn temp_storage() -> Storage {
+        temp_storage_with(StorageOptions::default())
+    }
+
+    fn temp_storage_with(options: StorageOptions) -> Storage {
         let mut path = std::env::temp_dir();
         path.push(format!("kexa-node-test-{}", rand::random::<u64>()));
         let _ = fs::create_dir_all(&path);
-        Storage::open(path.to_str().expect("path")).expect("storage")
+        Storage::open_with(path.to_str().expect("path"), options).expect("storage")
     }
 
-    fn test_state() -> AppState {
-        let storage = temp_storage();
-        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
-        AppState {
-            inner: Arc::new(Mutex::new(ChainState {
-                storage,
-                mempool: Vec::new(),
-                peers: Vec::new(),
-                live_peers: BTreeSet::new(),
-            })),
-        }
+    async fn send_json(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.expect("response");
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).expect("json"))
     }
 
-    #[test]
-    fn testnet_genesis_hash_locked() {
-        let (_block, hash) = build_testnet_genesis();
-        assert_eq!(hex::encode(hash.0), TESTNET_GENESIS_HASH_HEX);
+    async fn get_json(app: &Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .expect("response");
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).expect("json"))
+    }
+
+    /// Chain state around `storage` with nothing else known yet.
+    fn test_chain(storage: Storage) -> ChainState {
+        ChainState {
+            storage,
+            mempool: Mempool::default(),
+            peers: Vec::new(),
+            live_peers: BTreeMap::new(),
+            addrs: AddrMan::default(),
+            bans: BanMan::default(),
+            orphans: OrphanPool::default(),
+            history: None,
+        }
+    }
+
+    fn test_state() -> AppState {
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        AppState::new(test_chain(storage), TESTNET_MAGIC)
+    }
+
+    #[test]
+    fn testnet_genesis_hash_locked() {
+        let (_block, hash) = build_testnet_genesis();
+        assert_eq!(hex::encode(hash.0), TESTNET_GENESIS_HASH_HEX);
     }
 
     #[test]
@@ -1055,7 +2635,7 @@ mod tests {
 
     #[test]
     fn rejects_network_mismatch_on_existing_data() {
-        let storage = temp_storage();
+        let storage = MemoryStore::new();
         init_genesis(&storage, &NetworkMode::Testnet).expect("testnet genesis");
         let key = SigningKey::generate(&mut OsRng);
         let address = Address::from_pubkey(&key.verifying_key()).to_bech32();
@@ -1081,7 +2661,7 @@ mod tests {
 
     #[test]
     fn rejects_wrong_pubkey_for_utxo() {
-        let storage = temp_storage();
+        let storage = MemoryStore::new();
         let alice = SigningKey::generate(&mut OsRng);
         let bob = SigningKey::generate(&mut OsRng);
 
@@ -1110,13 +2690,13 @@ mod tests {
         let signing_hash = tx_signing_hash(&tx);
         tx.inputs[0].signature = kexa_proto::sign_tx(&bob, &signing_hash.0);
 
-        let err = validate_tx(&storage, &[], &tx).unwrap_err();
+        let err = validate_tx(&UtxoOverlay::new(&storage), &[], &tx).unwrap_err();
         assert!(err.to_string().contains("pubkey does not match utxo"));
     }
 
     #[test]
     fn rejects_coinbase_overpay() {
-        let storage = temp_storage();
+        let storage = MemoryStore::new();
         init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
         let (height, prev_hash) = storage.get_tip().expect("tip").expect("tip");
 
@@ -1150,7 +2730,7 @@ mod tests {
     }
     #[test]
     fn enforces_emission_end_boundary() {
-        let storage = temp_storage();
+        let storage = MemoryStore::new();
         init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
 
         // height = MINEABLE_BLOCKS: subsidy still allowed
@@ -1249,14 +2829,7 @@ mod tests {
             txs: vec![coinbase],
         };
 
-        let state = AppState {
-            inner: Arc::new(Mutex::new(ChainState {
-                storage,
-                mempool: Vec::new(),
-                peers: Vec::new(),
-                live_peers: BTreeSet::new(),
-            })),
-        };
+        let state = AppState::new(test_chain(storage), TESTNET_MAGIC);
 
         let err = apply_block(state.clone(), block).await.unwrap_err();
         assert!(err.to_string().contains("unexpected genesis block"));
@@ -1270,7 +2843,7 @@ mod tests {
 
     #[test]
     fn rejects_intra_block_double_spend() {
-        let storage = temp_storage();
+        let storage = MemoryStore::new();
         init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
         let (height, prev_hash) = storage.get_tip().expect("tip").expect("tip");
         let key = SigningKey::generate(&mut OsRng);
@@ -1341,6 +2914,93 @@ mod tests {
         assert!(err.to_string().contains("intra-block double spend"));
     }
 
+    #[tokio::test]
+    async fn accepts_chained_txs_in_block() {
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        let (height, prev_hash) = storage.get_tip().expect("tip").expect("tip");
+        let key = SigningKey::generate(&mut OsRng);
+        let address = Address::from_pubkey(&key.verifying_key()).payload;
+        let funding = OutPoint {
+            txid: Hash32([6u8; 32]),
+            index: 0,
+        };
+        storage
+            .put_utxo(
+                &funding,
+                &TxOut {
+                    amount: 50,
+                    address,
+                },
+            )
+            .expect("utxo");
+
+        let spend = |outpoint: OutPoint, amount: u64| {
+            let mut tx = Transaction {
+                version: 0,
+                inputs: vec![TxIn {
+                    outpoint,
+                    signature: [0u8; 64],
+                    pubkey: key.verifying_key().to_bytes(),
+                }],
+                outputs: vec![TxOut { amount, address }],
+            };
+            let signing_hash = tx_signing_hash(&tx);
+            tx.inputs[0].signature = kexa_proto::sign_tx(&key, &signing_hash.0);
+            tx
+        };
+        let parent = spend(funding, 45);
+        let child = spend(
+            OutPoint {
+                txid: parent.txid(),
+                index: 0,
+            },
+            40,
+        );
+
+        let mine = |txs: Vec<Transaction>| {
+            let mut header = BlockHeader {
+                version: 0,
+                prev_hash,
+                merkle_root: merkle_root(&txs),
+                timestamp: now_timestamp(),
+                bits: DIFFICULTY_BITS,
+                nonce: 0,
+                height: height + 1,
+            };
+            while !check_pow(&header) {
+                header.nonce = header.nonce.wrapping_add(1);
+            }
+            Block { header, txs }
+        };
+        let coinbase = Transaction {
+            version: 0,
+            inputs: vec![],
+            outputs: vec![TxOut {
+                amount: SUBSIDY + 10,
+                address: [9u8; 32],
+            }],
+        };
+
+        let reversed = mine(vec![coinbase.clone(), child.clone(), parent.clone()]);
+        let err = validate_block(&storage, &reversed).unwrap_err();
+        assert!(err.to_string().contains("missing utxo"));
+
+        let block = mine(vec![coinbase, parent, child.clone()]);
+        validate_block(&storage, &block).expect("chained block valid");
+
+        let state = AppState::new(test_chain(storage), TESTNET_MAGIC);
+        apply_block(state.clone(), block).await.expect("apply");
+        let guard = state.inner.lock().await;
+        let utxos = guard
+            .storage
+            .list_utxos_by_address(&address)
+            .expect("utxos");
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].0.txid, child.txid());
+        assert_eq!(utxos[0].1.amount, 40);
+    }
+
     #[tokio::test]
     async fn invalid_hash_returns_4xx() {
         let app = build_router(test_state());
@@ -1399,6 +3059,136 @@ mod tests {
         assert!(body.contains("invalid miner address"));
     }
 
+    #[test]
+    fn storage_corruption_is_reported_as_such() {
+        let corrupt = StorageError::Corruption {
+            tree: "meta",
+            key: "746970".to_string(),
+            detail: "malformed tip record".to_string(),
+        };
+        let (status, Json(body)) = internal_error(anyhow::Error::new(corrupt).context("tip"));
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.error.starts_with("storage corruption"));
+        assert!(body.error.contains("malformed tip record"));
+        assert!(body.error.contains("reindex"));
+
+        let (_, Json(body)) = internal_error(StorageError::Rejected("tip missing"));
+        assert_eq!(body.error, "internal error: tip missing");
+    }
+
+    #[tokio::test]
+    async fn tx_endpoint_reports_mempool_and_confirmed() {
+        let storage = temp_storage_with(StorageOptions {
+            tx_index: true,
+            ..StorageOptions::default()
+        });
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        let state = AppState::new(test_chain(storage), TESTNET_MAGIC);
+        let app = build_router(state.clone());
+        let alice = SigningKey::generate(&mut OsRng);
+        let alice_addr = Address::from_pubkey(&alice.verifying_key());
+
+        let hash = mine_one_block(state.clone(), &alice_addr.to_bech32())
+            .await
+            .expect("mine");
+        let coinbase = {
+            let guard = state.inner.lock().await;
+            guard
+                .storage
+                .get_block(&hash)
+                .expect("get")
+                .expect("block")
+                .txs[0]
+                .clone()
+        };
+        let coinbase_uri = format!("/tx/{}", hex::encode(coinbase.txid().0));
+        let (status, body) = get_json(&app, &coinbase_uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "confirmed");
+        assert_eq!(body["coinbase"], true);
+        assert_eq!(body["height"], 1);
+        assert_eq!(body["confirmations"], 1);
+        assert_eq!(body["fee"], 0);
+
+        let mut tx = Transaction {
+            version: 0,
+            inputs: vec![TxIn {
+                outpoint: OutPoint {
+                    txid: coinbase.txid(),
+                    index: 0,
+                },
+                signature: [0u8; 64],
+                pubkey: alice.verifying_key().to_bytes(),
+            }],
+            outputs: vec![TxOut {
+                amount: SUBSIDY - 1,
+                address: [3u8; 32],
+            }],
+        };
+        let signing_hash = tx_signing_hash(&tx);
+        tx.inputs[0].signature = kexa_proto::sign_tx(&alice, &signing_hash.0);
+        state.inner.lock().await.mempool.insert(tx.clone());
+
+        let tx_uri = format!("/tx/{}", hex::encode(tx.txid().0));
+        let (status, body) = get_json(&app, &tx_uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "mempool");
+        assert_eq!(body["confirmations"], 0);
+        assert_eq!(body["fee"], 1);
+        assert_eq!(body["inputs"][0]["amount"], SUBSIDY);
+        assert_eq!(body["inputs"][0]["address"], alice_addr.to_bech32());
+
+        mine_one_block(state.clone(), &alice_addr.to_bech32())
+            .await
+            .expect("mine");
+        let (status, body) = get_json(&app, &tx_uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "confirmed");
+        assert_eq!(body["height"], 2);
+        assert_eq!(body["position"], 1);
+        assert_eq!(body["fee"], 1);
+        assert_eq!(body["inputs"][0]["amount"], SUBSIDY);
+        let (_, body) = get_json(&app, &coinbase_uri).await;
+        assert_eq!(body["confirmations"], 2);
+
+        let (status, body) = get_json(&app, &format!("/tx/{}", hex::encode([1u8; 32]))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "transaction not found");
+    }
+
+    #[tokio::test]
+    async fn address_history_endpoint_pages_newest_first() {
+        let key = SigningKey::generate(&mut OsRng);
+        let address = Address::from_pubkey(&key.verifying_key()).to_bech32();
+        let uri = format!("/address/{address}/history?limit=1");
+
+        let (status, body) = get_json(&build_router(test_state()), &uri).await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("--address-history"));
+
+        let storage = temp_storage_with(StorageOptions {
+            address_history: true,
+            ..StorageOptions::default()
+        });
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        let state = AppState::new(test_chain(storage), TESTNET_MAGIC);
+        let app = build_router(state.clone());
+        mine_one_block(state.clone(), &address).await.expect("mine");
+        mine_one_block(state.clone(), &address).await.expect("mine");
+
+        let (status, body) = get_json(&app, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["entries"][0]["height"], 2);
+        assert_eq!(body["entries"][0]["net"], SUBSIDY);
+        assert_eq!(body["next_offset"], 1);
+        let (_, body) = get_json(&app, &format!("{uri}&offset=1")).await;
+        assert_eq!(body["entries"][0]["height"], 1);
+        assert!(body["next_offset"].is_null());
+    }
+
     #[test]
     fn equal_height_mismatch_does_not_tip_ping_pong() {
         let local_tip = Hash32([1u8; 32]);
@@ -1415,4 +3205,891 @@ mod tests {
             }
         );
     }
+
+    /// A chain of three mined blocks, each paying a different address.
+    fn snapshot_source() -> (Storage, Vec<Block>) {
+        let source = temp_storage();
+        init_genesis(&source, &NetworkMode::Testnet).expect("genesis");
+        let mut blocks = Vec::new();
+        for address in 1..=3u8 {
+            let block = mine_coinbase_block(&source, address);
+            source.connect_block(&block).expect("connect");
+            blocks.push(block);
+        }
+        (source, blocks)
+    }
+
+    /// A block on the tip of `storage` holding only a coinbase to `address`.
+    fn mine_coinbase_block(storage: &Storage, address: u8) -> Block {
+        let (height, prev_hash) = storage.get_tip().expect("tip").expect("tip");
+        let coinbase = Transaction {
+            version: 0,
+            inputs: vec![],
+            outputs: vec![TxOut {
+                amount: block_subsidy(height + 1),
+                address: [address; 32],
+            }],
+        };
+        let mut header = BlockHeader {
+            version: 0,
+            prev_hash,
+            merkle_root: merkle_root(std::slice::from_ref(&coinbase)),
+            timestamp: now_timestamp(),
+            bits: DIFFICULTY_BITS,
+            nonce: 0,
+            height: height + 1,
+        };
+        while !check_pow(&header) {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        Block {
+            header,
+            txs: vec![coinbase],
+        }
+    }
+
+    #[tokio::test]
+    async fn contradicted_utxo_snapshot_stops_the_node() {
+        let (source, blocks) = snapshot_source();
+        let mut file = Vec::new();
+        let snapshot = source.write_utxo_snapshot(2, &mut file).expect("snapshot");
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        storage
+            .load_utxo_snapshot(&mut file.as_slice(), &snapshot.utxo_hash)
+            .expect("load");
+        let dir = std::env::temp_dir().join(format!("kexa-node-history-{}", rand::random::<u64>()));
+        let store = Storage::open(dir.to_str().expect("path")).expect("history");
+        init_genesis(&store, &NetworkMode::Testnet).expect("genesis");
+        let state = AppState::new(
+            ChainState {
+                history: Some(HistorySync {
+                    store,
+                    snapshot,
+                    dir: dir.clone(),
+                }),
+                ..test_chain(storage)
+            },
+            TESTNET_MAGIC,
+        );
+
+        // Peers serve history whose block at the base is not the snapshot's.
+        apply_block(state.clone(), blocks[0].clone())
+            .await
+            .expect("history block");
+        let other = {
+            let guard = state.inner.lock().await;
+            let history = guard.history.as_ref().expect("history");
+            mine_coinbase_block(&history.store, 9)
+        };
+        let err = apply_block(state.clone(), other).await.unwrap_err();
+        assert!(format!("{err:#}").contains("snapshot base is"), "{err:#}");
+        tokio::time::timeout(Duration::from_secs(1), state.shutdown.notified())
+            .await
+            .expect("node stopping");
+
+        let mut guard = state.inner.lock().await;
+        assert!(guard.history.is_some());
+        assert!(guard.storage.snapshot_failure().expect("failure").is_some());
+        // Checking again, as on the next start, fails the same way.
+        assert!(complete_history(&mut guard).is_err());
+        drop(guard);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn utxo_snapshot_history_is_validated_in_background() {
+        let (source, blocks) = snapshot_source();
+        let mut file = Vec::new();
+        let snapshot = source.write_utxo_snapshot(2, &mut file).expect("snapshot");
+
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        storage
+            .load_utxo_snapshot(&mut file.as_slice(), &snapshot.utxo_hash)
+            .expect("load");
+        let dir = std::env::temp_dir().join(format!("kexa-node-history-{}", rand::random::<u64>()));
+        let store = Storage::open(dir.to_str().expect("path")).expect("history");
+        init_genesis(&store, &NetworkMode::Testnet).expect("genesis");
+        let state = AppState::new(
+            ChainState {
+                history: Some(HistorySync {
+                    store,
+                    snapshot,
+                    dir: dir.clone(),
+                }),
+                ..test_chain(storage)
+            },
+            TESTNET_MAGIC,
+        );
+
+        // The chain continues from the snapshot while history catches up.
+        apply_block(state.clone(), blocks[2].clone())
+            .await
+            .expect("tip block");
+        assert_eq!(history_request(&state, 3).await.expect("request"), Some(1));
+        apply_block(state.clone(), blocks[0].clone())
+            .await
+            .expect("history block");
+        assert!(state.inner.lock().await.history.is_some());
+        apply_block(state.clone(), blocks[1].clone())
+            .await
+            .expect("base block");
+
+        let guard = state.inner.lock().await;
+        assert!(guard.history.is_none());
+        assert!(guard.storage.snapshot_base().expect("base").is_none());
+        assert_eq!(
+            guard.storage.list_utxos().expect("utxos"),
+            source.list_utxos().expect("utxos")
+        );
+        let first = blocks[0].header.hash();
+        assert!(guard.storage.get_block(&first).expect("block").is_some());
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn snapshot_nodes_serve_blocks_above_their_base() {
+        let (source, blocks) = snapshot_source();
+        let mut file = Vec::new();
+        let snapshot = source.write_utxo_snapshot(2, &mut file).expect("snapshot");
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        storage
+            .load_utxo_snapshot(&mut file.as_slice(), &snapshot.utxo_hash)
+            .expect("load");
+        let state = AppState::new(test_chain(storage), TESTNET_MAGIC);
+        let app = build_router(state.clone());
+
+        // Freshly loaded, there are no blocks to list yet.
+        let (status, body) = get_json(&app, "/blocks").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!([]));
+
+        apply_block(state.clone(), blocks[2].clone())
+            .await
+            .expect("tip block");
+        let (status, body) = get_json(&app, "/blocks").await;
+        assert_eq!(status, StatusCode::OK);
+        let listed = body.as_array().expect("list");
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["height"], 3);
+
+        // Nothing is served up to the base, and the peer stays connected.
+        let mut peer = connect_peer(&state).await;
+        send_message(&mut peer, Message::GetBlocks { start_height: 1 }).await;
+        send_message(&mut peer, Message::GetBlocks { start_height: 3 }).await;
+        match next_message(&mut peer).await {
+            Message::Block { block } => assert_eq!(block, blocks[2]),
+            other => panic!("expected Block, got {other:?}"),
+        }
+    }
+
+    /// Next message from `stream`, skipping the tip polling and keepalive
+    /// pings both sides do.
+    async fn next_message(stream: &mut TcpStream) -> Message {
+        loop {
+            match next_frame(stream).await {
+                Message::GetTip | Message::Tip { .. } | Message::Ping { .. } => continue,
+                message => return message,
+            }
+        }
+    }
+
+    async fn next_frame(stream: &mut TcpStream) -> Message {
+        let mut frame = vec![0u8; FRAME_HEADER_LEN];
+        stream.read_exact(&mut frame).await.expect("header");
+        let len = u32::from_be_bytes(frame[4..].try_into().expect("length")) as usize;
+        frame.resize(FRAME_HEADER_LEN + len, 0);
+        stream
+            .read_exact(&mut frame[FRAME_HEADER_LEN..])
+            .await
+            .expect("payload");
+        decode_message(TESTNET_MAGIC, &mut frame[..].into())
+            .expect("frame")
+            .expect("message")
+    }
+
+    async fn send_message(stream: &mut TcpStream, message: Message) {
+        let data = encode_message(TESTNET_MAGIC, &message).expect("encode");
+        stream.write_all(&data).await.expect("send");
+    }
+
+    /// Port test peers claim to accept connections on.
+    const TEST_LISTEN_PORT: u16 = 18_031;
+
+    fn test_version(genesis: Hash32) -> Message {
+        Message::Version {
+            protocol_version: PROTOCOL_VERSION,
+            services: SERVICE_FULL_HISTORY,
+            genesis,
+            nonce: rand::random(),
+            user_agent: "/test/".to_string(),
+            listen_port: TEST_LISTEN_PORT,
+            height: 0,
+            tip: genesis,
+        }
+    }
+
+    /// Connects to a node serving `state` and completes the handshake.
+    async fn connect_peer(state: &AppState) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let node_state = state.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("accept");
+            let transport = Transport::plain(stream).expect("transport");
+            let _ = handle_peer(node_state, transport, Arc::new(LivePeer::new(false))).await;
+        });
+        let mut peer = TcpStream::connect(addr).await.expect("connect");
+        handshake(&mut peer).await;
+        peer
+    }
+
+    /// Speaks first, as a dialling node does, since a listening node waits
+    /// for the first bytes to tell plaintext from encrypted connections.
+    async fn handshake(peer: &mut TcpStream) {
+        let (_, genesis) = build_testnet_genesis();
+        send_message(peer, test_version(genesis)).await;
+        send_message(peer, Message::Verack).await;
+        assert!(matches!(next_message(peer).await, Message::Version { .. }));
+        assert!(matches!(next_message(peer).await, Message::Verack));
+    }
+
+    /// Accepts one inbound connection the way the P2P listener does.
+    async fn serve_connection(state: &AppState) -> (SocketAddr, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let node_state = state.clone();
+        let node = tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.expect("accept");
+            accept_connection(node_state, stream, peer_addr).await;
+        });
+        (addr, node)
+    }
+
+    /// Waits for the node to close the connection, skipping what it sends.
+    async fn wait_closed(peer: &mut TcpStream) {
+        let mut buf = [0u8; 1024];
+        let closed = async { while matches!(peer.read(&mut buf).await, Ok(n) if n > 0) {} };
+        tokio::time::timeout(std::time::Duration::from_secs(5), closed)
+            .await
+            .expect("node drops the peer");
+    }
+
+    #[tokio::test]
+    async fn transactions_are_relayed_to_and_from_peers() {
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        let key = SigningKey::generate(&mut OsRng);
+        let address = Address::from_pubkey(&key.verifying_key()).payload;
+        let mut txs = Vec::new();
+        for funding in 1..=2u8 {
+            let outpoint = OutPoint {
+                txid: Hash32([funding; 32]),
+                index: 0,
+            };
+            storage
+                .put_utxo(
+                    &outpoint,
+                    &TxOut {
+                        amount: 50,
+                        address,
+                    },
+                )
+                .expect("utxo");
+            let mut tx = Transaction {
+                version: 0,
+                inputs: vec![TxIn {
+                    outpoint,
+                    signature: [0u8; 64],
+                    pubkey: key.verifying_key().to_bytes(),
+                }],
+                outputs: vec![TxOut {
+                    amount: 45,
+                    address: [funding; 32],
+                }],
+            };
+            let signing_hash = tx_signing_hash(&tx);
+            tx.inputs[0].signature = kexa_proto::sign_tx(&key, &signing_hash.0);
+            txs.push(tx);
+        }
+        let (ours, theirs) = (txs[0].clone(), txs[1].clone());
+        let state = AppState::new(
+            ChainState {
+                mempool: Mempool::from_iter([ours.clone()]),
+                ..test_chain(storage)
+            },
+            TESTNET_MAGIC,
+        );
+        let mut peer = connect_peer(&state).await;
+
+        // The node offers its mempool and serves what the peer asks for.
+        match next_message(&mut peer).await {
+            Message::Inv { items } => assert_eq!(items, vec![InvItem::Tx(ours.txid())]),
+            other => panic!("expected Inv, got {other:?}"),
+        }
+        send_message(
+            &mut peer,
+            Message::GetData {
+                items: vec![InvItem::Tx(ours.txid())],
+            },
+        )
+        .await;
+        match next_message(&mut peer).await {
+            Message::Tx { tx } => assert_eq!(tx, ours),
+            other => panic!("expected Tx, got {other:?}"),
+        }
+
+        // And fetches, validates and keeps what the peer offers.
+        send_message(
+            &mut peer,
+            Message::Inv {
+                items: vec![InvItem::Tx(theirs.txid())],
+            },
+        )
+        .await;
+        match next_message(&mut peer).await {
+            Message::GetData { items } => assert_eq!(items, vec![InvItem::Tx(theirs.txid())]),
+            other => panic!("expected GetData, got {other:?}"),
+        }
+        send_message(&mut peer, Message::Tx { tx: theirs.clone() }).await;
+        for _ in 0..50 {
+            if state.inner.lock().await.mempool.contains(&theirs.txid()) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            state.inner.lock().await.mempool.as_slice(),
+            &[ours.clone(), theirs]
+        );
+
+        // A block confirming `ours` evicts it.
+        let mut guard = state.inner.lock().await;
+        let block = Block {
+            header: BlockHeader {
+                version: 0,
+                prev_hash: Hash32::zero(),
+                merkle_root: Hash32::zero(),
+                timestamp: 0,
+                bits: 0,
+                nonce: 0,
+                height: 1,
+            },
+            txs: vec![ours],
+        };
+        evict_spent(&mut guard.mempool, &block);
+        assert_eq!(guard.mempool.as_slice().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn connected_blocks_are_announced_without_polling() {
+        let state = test_state();
+        let mut peer = connect_peer(&state).await;
+
+        let key = SigningKey::generate(&mut OsRng);
+        let miner = Address::from_pubkey(&key.verifying_key()).to_bech32();
+        let hash = mine_one_block(state.clone(), &miner).await.expect("mine");
+        // Well inside the idle tip poll, so only a push can satisfy it.
+        let announced = tokio::time::timeout(
+            std::time::Duration::from_millis(500),
+            next_message(&mut peer),
+        )
+        .await
+        .expect("announced before the tip poll");
+        match announced {
+            Message::Inv { items } => assert_eq!(items, vec![InvItem::Block(hash)]),
+            other => panic!("expected Inv, got {other:?}"),
+        }
+
+        let unknown = InvItem::Tx(Hash32([7u8; 32]));
+        send_message(
+            &mut peer,
+            Message::GetData {
+                items: vec![InvItem::Block(hash), unknown],
+            },
+        )
+        .await;
+        match next_message(&mut peer).await {
+            Message::Block { block } => assert_eq!(block.header.hash(), hash),
+            other => panic!("expected Block, got {other:?}"),
+        }
+        match next_message(&mut peer).await {
+            Message::NotFound { items } => assert_eq!(items, vec![unknown]),
+            other => panic!("expected NotFound, got {other:?}"),
+        }
+
+        let (_, genesis) = build_testnet_genesis();
+        send_message(
+            &mut peer,
+            Message::GetHeaders {
+                locator: vec![Hash32([9u8; 32]), genesis],
+            },
+        )
+        .await;
+        match next_message(&mut peer).await {
+            Message::Headers { headers } => {
+                assert_eq!(headers.len(), 1);
+                assert_eq!(headers[0].hash(), hash);
+            }
+            other => panic!("expected Headers, got {other:?}"),
+        }
+
+        // A block announced further ahead makes the node sync headers first.
+        let (_, tip) = state
+            .inner
+            .lock()
+            .await
+            .storage
+            .get_tip()
+            .expect("tip")
+            .expect("tip");
+        let ahead = Block {
+            header: BlockHeader {
+                version: 0,
+                prev_hash: tip,
+                merkle_root: Hash32::zero(),
+                timestamp: 0,
+                bits: DIFFICULTY_BITS,
+                nonce: 0,
+                height: 5,
+            },
+            txs: Vec::new(),
+        };
+        send_message(&mut peer, Message::Block { block: ahead }).await;
+        match next_message(&mut peer).await {
+            Message::GetHeaders { locator } => assert_eq!(locator, vec![hash, genesis]),
+            other => panic!("expected GetHeaders, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn headers_first_sync_catches_up_with_peer() {
+        let ahead = test_state();
+        let key = SigningKey::generate(&mut OsRng);
+        let miner = Address::from_pubkey(&key.verifying_key()).to_bech32();
+        let mut tip = Hash32::zero();
+        for _ in 0..20 {
+            tip = mine_one_block(ahead.clone(), &miner).await.expect("mine");
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.expect("accept");
+            accept_connection(ahead, stream, peer_addr).await;
+        });
+        // Over an encrypted connection, as between two nodes by default.
+        let behind = test_state();
+        let stream = TcpStream::connect(addr).await.expect("connect");
+        let transport = Transport::initiate(stream, &behind.node_key, behind.magic)
+            .await
+            .expect("encrypted");
+        let peer_state = behind.clone();
+        tokio::spawn(async move {
+            let peer = Arc::new(LivePeer::new(true));
+            let _ = handle_peer(peer_state, transport, peer).await;
+        });
+        for _ in 0..250 {
+            if behind.inner.lock().await.storage.get_tip().expect("tip") == Some((20, tip)) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("node did not sync to the peer's tip");
+    }
+
+    #[tokio::test]
+    async fn blocks_are_served_in_batches() {
+        let state = test_state();
+        let tip_height = MAX_BLOCKS_PER_GETBLOCKS as u64 + 2;
+        {
+            // Serving does not validate, so the blocks need no proof of work.
+            let guard = state.inner.lock().await;
+            for _ in 0..tip_height {
+                let (height, prev_hash) = guard.storage.get_tip().expect("tip").expect("tip");
+                let coinbase = Transaction {
+                    version: 0,
+                    inputs: vec![],
+                    outputs: vec![TxOut {
+                        amount: block_subsidy(height + 1),
+                        address: [1u8; 32],
+                    }],
+                };
+                let header = BlockHeader {
+                    version: 0,
+                    prev_hash,
+                    merkle_root: merkle_root(std::slice::from_ref(&coinbase)),
+                    timestamp: now_timestamp(),
+                    bits: DIFFICULTY_BITS,
+                    nonce: 0,
+                    height: height + 1,
+                };
+                let block = Block {
+                    header,
+                    txs: vec![coinbase],
+                };
+                guard.storage.connect_block(&block).expect("connect");
+            }
+        }
+        let mut peer = connect_peer(&state).await;
+
+        let mut next = 1;
+        for batch in [MAX_BLOCKS_PER_GETBLOCKS as u64, 2] {
+            send_message(&mut peer, Message::GetBlocks { start_height: next }).await;
+            for _ in 0..batch {
+                match next_message(&mut peer).await {
+                    Message::Block { block } => assert_eq!(block.header.height, next),
+                    other => panic!("unexpected {other:?}"),
+                }
+                next += 1;
+            }
+            // Nothing follows the batch until it is asked for.
+            send_message(&mut peer, Message::Ping { nonce: next }).await;
+            assert!(matches!(
+                next_message(&mut peer).await,
+                Message::Pong { nonce } if nonce == next
+            ));
+        }
+        assert_eq!(next, tip_height + 1);
+    }
+
+    #[tokio::test]
+    async fn orphan_blocks_connect_once_their_parents_arrive() {
+        let ahead = test_state();
+        let key = SigningKey::generate(&mut OsRng);
+        let miner = Address::from_pubkey(&key.verifying_key()).to_bech32();
+        for _ in 0..3 {
+            mine_one_block(ahead.clone(), &miner).await.expect("mine");
+        }
+        let blocks: Vec<Block> = {
+            let guard = ahead.inner.lock().await;
+            let reader = guard.storage.block_reader().expect("reader");
+            (1..=3)
+                .map(|height| reader.block_at(height).expect("read").expect("block"))
+                .collect()
+        };
+
+        let state = test_state();
+        let mut peer = connect_peer(&state).await;
+        // Two blocks short, the node syncs headers to fill the gap.
+        send_message(
+            &mut peer,
+            Message::Block {
+                block: blocks[2].clone(),
+            },
+        )
+        .await;
+        assert!(matches!(
+            next_message(&mut peer).await,
+            Message::GetHeaders { .. }
+        ));
+        // One block short, it asks for the missing parent.
+        send_message(
+            &mut peer,
+            Message::Block {
+                block: blocks[1].clone(),
+            },
+        )
+        .await;
+        match next_message(&mut peer).await {
+            Message::GetData { items } => {
+                assert_eq!(items, vec![InvItem::Block(blocks[0].header.hash())]);
+            }
+            other => panic!("expected GetData, got {other:?}"),
+        }
+
+        send_message(
+            &mut peer,
+            Message::Block {
+                block: blocks[0].clone(),
+            },
+        )
+        .await;
+        for block in &blocks {
+            match next_message(&mut peer).await {
+                Message::Inv { items } => {
+                    assert_eq!(items, vec![InvItem::Block(block.header.hash())]);
+                }
+                other => panic!("expected Inv, got {other:?}"),
+            }
+        }
+        let tip = state.inner.lock().await.storage.get_tip().expect("tip");
+        assert_eq!(tip, Some((3, blocks[2].header.hash())));
+    }
+
+    #[tokio::test]
+    async fn forked_peers_are_not_asked_for_the_same_headers_again() {
+        let state = test_state();
+        {
+            let guard = state.inner.lock().await;
+            let ours = mine_coinbase_block(&guard.storage, 1);
+            guard.storage.connect_block(&ours).expect("connect");
+        }
+        // The peer's chain branches off ours at genesis.
+        let theirs = temp_storage();
+        init_genesis(&theirs, &NetworkMode::Testnet).expect("genesis");
+        let mut headers = Vec::new();
+        for _ in 0..2 {
+            let block = mine_coinbase_block(&theirs, 2);
+            theirs.connect_block(&block).expect("connect");
+            headers.push(block.header);
+        }
+        let tip = Message::Tip {
+            height: 2,
+            tip: headers[1].hash(),
+        };
+
+        let mut peer = connect_peer(&state).await;
+        send_message(&mut peer, tip.clone()).await;
+        assert!(matches!(
+            next_message(&mut peer).await,
+            Message::GetHeaders { .. }
+        ));
+        send_message(&mut peer, Message::Headers { headers }).await;
+
+        // Polled again at the same tip, the node does not ask again.
+        send_message(&mut peer, tip).await;
+        send_message(&mut peer, Message::Ping { nonce: 1 }).await;
+        match next_message(&mut peer).await {
+            Message::Pong { nonce } => assert_eq!(nonce, 1),
+            other => panic!("expected Pong, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_addresses_are_exchanged_and_recorded() {
+        let state = test_state();
+        let mut peer = connect_peer(&state).await;
+        let advertised = PeerAddr {
+            addr: "10.1.2.3:8031".to_string(),
+            last_seen: now_timestamp() - 60,
+        };
+        send_message(
+            &mut peer,
+            Message::Addr {
+                addrs: vec![
+                    advertised.clone(),
+                    PeerAddr {
+                        addr: "not an address".to_string(),
+                        last_seen: 0,
+                    },
+                ],
+            },
+        )
+        .await;
+        send_message(&mut peer, Message::GetAddr).await;
+        let addrs = loop {
+            if let Message::Addr { addrs } = next_message(&mut peer).await {
+                break addrs;
+            }
+        };
+        let mut known: Vec<&str> = addrs.iter().map(|addr| addr.addr.as_str()).collect();
+        known.sort();
+        // The peer itself is recorded under the port it listens on.
+        let listening = format!("127.0.0.1:{TEST_LISTEN_PORT}");
+        assert_eq!(known, vec![advertised.addr.as_str(), listening.as_str()]);
+        assert!(addrs.contains(&advertised));
+
+        let oversized = Message::Addr {
+            addrs: vec![advertised; MAX_ADDRS + 1],
+        };
+        send_message(&mut peer, oversized).await;
+        wait_closed(&mut peer).await;
+    }
+
+    /// Runs a node connection against frames from a test peer and returns why
+    /// the node dropped it.
+    async fn rejection(state: &AppState, frames: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let node_state = state.clone();
+        let node = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("accept");
+            let transport = Transport::plain(stream).expect("transport");
+            handle_peer(node_state, transport, Arc::new(LivePeer::new(false))).await
+        });
+        let mut peer = TcpStream::connect(addr).await.expect("connect");
+        for frame in frames {
+            peer.write_all(&frame).await.expect("send");
+        }
+        let err = node.await.expect("join").unwrap_err();
+        err.to_string()
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_mismatched_peers() {
+        let state = test_state();
+        let (_, genesis) = build_testnet_genesis();
+        let frame = |magic, message: Message| encode_message(magic, &message).expect("encode");
+
+        let other_chain = test_version(Hash32([3u8; 32]));
+        let err = rejection(&state, vec![frame(TESTNET_MAGIC, other_chain)]).await;
+        assert!(err.contains("genesis mismatch"), "{err}");
+
+        let mainnet = test_version(genesis);
+        let err = rejection(&state, vec![frame(MAINNET_MAGIC, mainnet)]).await;
+        assert!(err.contains("network magic mismatch"), "{err}");
+
+        let mut ourselves = test_version(genesis);
+        if let Message::Version { nonce, .. } = &mut ourselves {
+            *nonce = state.nonce;
+        }
+        let err = rejection(&state, vec![frame(TESTNET_MAGIC, ourselves)]).await;
+        assert!(err.contains("connected to ourselves"), "{err}");
+
+        let early = vec![
+            frame(TESTNET_MAGIC, test_version(genesis)),
+            frame(TESTNET_MAGIC, Message::GetTip),
+        ];
+        let err = rejection(&state, early).await;
+        assert!(err.contains("before handshake"), "{err}");
+
+        // Each message type has its own size limit, checked from the header.
+        let mut oversized = frame(TESTNET_MAGIC, Message::Ping { nonce: 1 });
+        oversized[4..FRAME_HEADER_LEN].copy_from_slice(&100_000u32.to_be_bytes());
+        let err = rejection(&state, vec![oversized]).await;
+        assert!(err.contains("message too large: 100000 byte ping"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn bans_are_managed_over_rpc() {
+        let app = build_router(test_state());
+        let (status, ban) = send_json(
+            &app,
+            "POST",
+            "/bans",
+            r#"{"address":"10.0.0.1:8031","duration_secs":60}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ban["address"], "10.0.0.1");
+        assert_eq!(ban["reason"], "banned over rpc");
+        let (status, _) = send_json(&app, "POST", "/bans", r#"{"address":"nope"}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send_json(
+            &app,
+            "POST",
+            "/bans",
+            r#"{"address":"::1","reason":"testing"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, bans) = get_json(&app, "/bans").await;
+        assert_eq!(bans.as_array().expect("list").len(), 2);
+        let (_, removed) = send_json(&app, "DELETE", "/bans/10.0.0.1", "").await;
+        assert_eq!(removed["removed"], 1);
+        let (_, removed) = send_json(&app, "DELETE", "/bans/10.0.0.1", "").await;
+        assert_eq!(removed["removed"], 0);
+        let (_, bans) = get_json(&app, "/bans").await;
+        assert_eq!(bans[0]["address"], "::1");
+        assert_eq!(bans[0]["reason"], "testing");
+        let (_, removed) = send_json(&app, "DELETE", "/bans", "").await;
+        assert_eq!(removed["removed"], 1);
+        let (_, bans) = get_json(&app, "/bans").await;
+        assert!(bans.as_array().expect("list").is_empty());
+    }
+
+    #[tokio::test]
+    async fn peers_sending_invalid_blocks_are_banned() {
+        let state = test_state();
+        let mut bystander = connect_peer(&state).await;
+
+        let (addr, node) = serve_connection(&state).await;
+        let mut peer = TcpStream::connect(addr).await.expect("connect");
+        handshake(&mut peer).await;
+        let (genesis, genesis_hash) = build_testnet_genesis();
+        let block = Block {
+            header: BlockHeader {
+                version: 0,
+                prev_hash: genesis_hash,
+                merkle_root: Hash32::zero(),
+                timestamp: genesis.header.timestamp + 1,
+                bits: DIFFICULTY_BITS,
+                nonce: 0,
+                height: 1,
+            },
+            txs: Vec::new(),
+        };
+        send_message(&mut peer, Message::Block { block }).await;
+        node.await.expect("join");
+
+        {
+            let mut guard = state.inner.lock().await;
+            let now = now_timestamp();
+            assert!(guard.bans.is_banned("127.0.0.1".parse().expect("ip"), now));
+            let bans = guard.bans.list(now);
+            assert!(bans[0].1.reason.contains("block empty"), "{bans:?}");
+        }
+        // Other connections from the banned address are dropped too.
+        wait_closed(&mut bystander).await;
+    }
+
+    #[tokio::test]
+    async fn inbound_peers_are_limited_and_evicted_when_full() {
+        let mut state = test_state();
+        state.limits.max_inbound = 1;
+        let (addr, _node) = serve_connection(&state).await;
+        let mut first = TcpStream::connect(addr).await.expect("connect");
+        handshake(&mut first).await;
+
+        // With its one slot taken by an idle peer, the node makes room.
+        let (addr, _node) = serve_connection(&state).await;
+        let mut second = TcpStream::connect(addr).await.expect("connect");
+        handshake(&mut second).await;
+        wait_closed(&mut first).await;
+        let second_id = second.local_addr().expect("addr").to_string();
+        let live: Vec<String> = state
+            .inner
+            .lock()
+            .await
+            .live_peers
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(live, vec![second_id]);
+
+        // A host at its limit is refused outright.
+        state.limits = ConnLimits {
+            max_inbound: 8,
+            max_per_ip: 1,
+            ..ConnLimits::default()
+        };
+        let (addr, _node) = serve_connection(&state).await;
+        let mut third = TcpStream::connect(addr).await.expect("connect");
+        wait_closed(&mut third).await;
+        assert_eq!(state.inner.lock().await.live_peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pings_measure_latency_of_live_peers() {
+        let state = test_state();
+        let (addr, _node) = serve_connection(&state).await;
+        let mut peer = TcpStream::connect(addr).await.expect("connect");
+        handshake(&mut peer).await;
+        let nonce = loop {
+            if let Message::Ping { nonce } = next_frame(&mut peer).await {
+                break nonce;
+            }
+        };
+        send_message(&mut peer, Message::Pong { nonce }).await;
+        send_message(&mut peer, Message::Ping { nonce: 7 }).await;
+        loop {
+            if let Message::Pong { nonce } = next_message(&mut peer).await {
+                assert_eq!(nonce, 7);
+                break;
+            }
+        }
+
+        let app = build_router(state);
+        let (status, peers) = get_json(&app, "/peers/live").await;
+        assert_eq!(status, StatusCode::OK);
+        let local = peer.local_addr().expect("addr").to_string();
+        assert_eq!(peers[0]["address"], local.as_str());
+        assert_eq!(peers[0]["outbound"], false);
+        assert_eq!(peers[0]["node_key"], serde_json::Value::Null);
+        assert!(peers[0]["ping_ms"].as_f64().is_some(), "{peers}");
+        assert!(peers[0]["last_seen"].as_u64().expect("last seen") >= now_timestamp() - 5);
+    }
 }
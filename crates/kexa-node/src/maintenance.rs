@@ -0,0 +1,424 @@
+use anyhow::{Context, Result};
+use borsh::BorshDeserialize;
+use kexa_p2p::MAX_MESSAGE_SIZE;
+use kexa_proto::{Block, Hash32, OutPoint, TxOut};
+use kexa_storage::{ChainStore, MemoryStore, Storage};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+use crate::validate_block;
+
+/// How often block export and import report progress.
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Fails for data dirs started from a UTXO snapshot whose history is still
+/// being validated, since their blocks below the snapshot are missing.
+fn require_history(storage: &Storage) -> Result<()> {
+    if let Some(snapshot) = storage.snapshot_base()? {
+        anyhow::bail!(
+            "data dir was started from a utxo snapshot at height {} and has no blocks below it yet; run the node until it has validated that history",
+            snapshot.base.height
+        );
+    }
+    Ok(())
+}
+
+/// Rebuilds the UTXO set and indexes from the blocks already in `storage`.
+pub(crate) fn reindex(storage: &Storage) -> Result<()> {
+    let tip_height = storage.reindex()?;
+    let (_, tip_hash) = storage.get_tip()?.context("tip missing")?;
+    println!(
+        "reindexed {} blocks, tip {} {}",
+        tip_height + 1,
+        tip_height,
+        hex::encode(tip_hash.0)
+    );
+    Ok(())
+}
+
+/// Replays the stored chain into memory, fully re-validating the newest
+/// `depth` blocks on the way, then checks the stored UTXO set against the
+/// replayed one. Fails with the first inconsistency found.
+pub(crate) fn verify_chain(storage: &Storage, depth: u64) -> Result<()> {
+    require_history(storage)?;
+    let (tip_height, tip_hash) = storage.get_tip()?.context("tip missing")?;
+    let first_checked = (tip_height + 1).saturating_sub(depth).max(1);
+    let replay = MemoryStore::new();
+    for height in 0..=tip_height {
+        let hash = storage
+            .get_hash_by_height(height)?
+            .with_context(|| format!("height {height}: missing from height index"))?;
+        let block = storage
+            .get_block(&hash)?
+            .with_context(|| format!("height {height}: block {} missing", hex::encode(hash.0)))?;
+        if block.header.hash() != hash || block.header.height != height {
+            anyhow::bail!(
+                "height {height}: stored block does not match height index entry {}",
+                hex::encode(hash.0)
+            );
+        }
+        if height == 0 {
+            replay.put_genesis(&block)?;
+            continue;
+        }
+        if height >= first_checked {
+            if storage.get_header(height)?.as_ref() != Some(&block.header) {
+                anyhow::bail!("height {height}: header index disagrees with stored block");
+            }
+            validate_block(&replay, &block).with_context(|| {
+                format!("height {height}: block {} is invalid", hex::encode(hash.0))
+            })?;
+        }
+        replay.connect_block(&block).with_context(|| {
+            format!(
+                "height {height}: block {} does not connect",
+                hex::encode(hash.0)
+            )
+        })?;
+    }
+    if let Some(mismatch) = first_utxo_mismatch(&storage.list_utxos()?, &replay.list_utxos()?) {
+        anyhow::bail!("utxo set mismatch: {mismatch}");
+    }
+    println!(
+        "verified {} blocks (re-validated {}), tip {} {}, utxo set matches replay",
+        tip_height + 1,
+        (tip_height + 1).saturating_sub(first_checked),
+        tip_height,
+        hex::encode(tip_hash.0)
+    );
+    Ok(())
+}
+
+/// Writes the active chain, genesis first, to `path` as a sequence of
+/// blocks, each a big-endian u32 length followed by the borsh encoding.
+pub(crate) fn export_blocks(storage: &Storage, path: &Path) -> Result<()> {
+    require_history(storage)?;
+    let (tip_height, tip_hash) = storage.get_tip()?.context("tip missing")?;
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    for height in 0..=tip_height {
+        let hash = storage
+            .get_hash_by_height(height)?
+            .with_context(|| format!("height {height}: missing from height index"))?;
+        let block = storage
+            .get_block(&hash)?
+            .with_context(|| format!("height {height}: block {} missing", hex::encode(hash.0)))?;
+        let bytes = borsh::to_vec(&block)?;
+        writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(&bytes)?;
+        if height > 0 && height.is_multiple_of(PROGRESS_INTERVAL) {
+            info!("exported to height {height}/{tip_height}");
+        }
+    }
+    writer.flush()?;
+    println!(
+        "exported {} blocks to {}, tip {} {}",
+        tip_height + 1,
+        path.display(),
+        tip_height,
+        hex::encode(tip_hash.0)
+    );
+    Ok(())
+}
+
+/// Validates and connects the blocks of an [`export_blocks`] file on top of
+/// the local chain. Blocks the local chain already has are skipped, so an
+/// interrupted import can simply be rerun; a block that conflicts with the
+/// local chain stops the import.
+pub(crate) fn import_blocks(storage: &Storage, path: &Path) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let (mut skipped, mut connected) = (0u64, 0u64);
+    while let Some(block) = read_block(&mut reader)
+        .with_context(|| format!("block {} of {}", skipped + connected, path.display()))?
+    {
+        let height = block.header.height;
+        let hash = block.header.hash();
+        let (tip_height, _) = storage.get_tip()?.context("tip missing")?;
+        if height <= tip_height {
+            if storage.get_hash_by_height(height)? != Some(hash) {
+                anyhow::bail!(
+                    "height {height}: block {} conflicts with the local chain",
+                    hex::encode(hash.0)
+                );
+            }
+            skipped += 1;
+            continue;
+        }
+        validate_block(storage, &block).with_context(|| {
+            format!("height {height}: block {} is invalid", hex::encode(hash.0))
+        })?;
+        storage.connect_block(&block)?;
+        connected += 1;
+        if height.is_multiple_of(PROGRESS_INTERVAL) {
+            info!("imported to height {height}");
+        }
+    }
+    storage.flush()?;
+    let (tip_height, tip_hash) = storage.get_tip()?.context("tip missing")?;
+    println!(
+        "imported {connected} blocks ({skipped} already present), tip {} {}",
+        tip_height,
+        hex::encode(tip_hash.0)
+    );
+    Ok(())
+}
+
+/// Writes the UTXO set at `height` (the tip by default) to `path` and prints
+/// the commitment hash operators pass to `load-utxo-snapshot`.
+pub(crate) fn export_utxo_snapshot(
+    storage: &Storage,
+    path: &Path,
+    height: Option<u64>,
+) -> Result<()> {
+    let (tip_height, _) = storage.get_tip()?.context("tip missing")?;
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let snapshot =
+        storage.write_utxo_snapshot(height.unwrap_or(tip_height), &mut BufWriter::new(file))?;
+    println!(
+        "wrote {} utxos at height {} (block {}) to {}\ncommitment {}",
+        snapshot.utxo_count,
+        snapshot.base.height,
+        hex::encode(snapshot.base.hash().0),
+        path.display(),
+        hex::encode(snapshot.utxo_hash.0)
+    );
+    Ok(())
+}
+
+/// Loads a UTXO snapshot into a data dir that holds only genesis, provided
+/// its commitment equals `expect_hash`.
+pub(crate) fn load_utxo_snapshot(storage: &Storage, path: &Path, expect_hash: &str) -> Result<()> {
+    let expected: [u8; 32] = hex::decode(expect_hash)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("--expect-hash must be 32 bytes of hex")?;
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let snapshot = storage.load_utxo_snapshot(&mut BufReader::new(file), &Hash32(expected))?;
+    println!(
+        "loaded {} utxos, tip {} {}; history below it is validated once the node runs",
+        snapshot.utxo_count,
+        snapshot.base.height,
+        hex::encode(snapshot.base.hash().0)
+    );
+    Ok(())
+}
+
+/// Reads the next length-prefixed block, or `None` at a clean end of file.
+fn read_block(reader: &mut impl BufRead) -> Result<Option<Block>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).context("truncated length")?;
+    let len = u32::from_be_bytes(len) as usize;
+    // Anything larger could never have been relayed over p2p either.
+    if len > MAX_MESSAGE_SIZE {
+        anyhow::bail!("block length {len} exceeds {MAX_MESSAGE_SIZE}");
+    }
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).context("truncated block")?;
+    Ok(Some(
+        Block::try_from_slice(&bytes).context("malformed block")?,
+    ))
+}
+
+/// Walks two outpoint-ordered UTXO lists and describes the first difference.
+fn first_utxo_mismatch(
+    stored: &[(OutPoint, TxOut)],
+    replayed: &[(OutPoint, TxOut)],
+) -> Option<String> {
+    let describe =
+        |outpoint: &OutPoint| format!("{}:{}", hex::encode(outpoint.txid.0), outpoint.index);
+    let (mut stored, mut replayed) = (stored.iter().peekable(), replayed.iter().peekable());
+    loop {
+        let order = match (stored.peek(), replayed.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((a, _)), Some((b, _))) => (a.txid.0, a.index).cmp(&(b.txid.0, b.index)),
+        };
+        match order {
+            Ordering::Less => {
+                let (outpoint, _) = stored.next()?;
+                return Some(format!(
+                    "{} is stored but not created by the chain",
+                    describe(outpoint)
+                ));
+            }
+            Ordering::Greater => {
+                let (outpoint, _) = replayed.next()?;
+                return Some(format!(
+                    "{} is missing from the stored set",
+                    describe(outpoint)
+                ));
+            }
+            Ordering::Equal => {
+                let (outpoint, stored_output) = stored.next()?;
+                let (_, replayed_output) = replayed.next()?;
+                if stored_output != replayed_output {
+                    return Some(format!(
+                        "{} stored as {} to {}, chain says {} to {}",
+                        describe(outpoint),
+                        stored_output.amount,
+                        hex::encode(stored_output.address),
+                        replayed_output.amount,
+                        hex::encode(replayed_output.address)
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_genesis, NetworkMode};
+    use kexa_consensus::{block_subsidy, check_pow, merkle_root, DIFFICULTY_BITS};
+    use kexa_proto::{Block, BlockHeader, Transaction};
+
+    fn temp_storage() -> Storage {
+        let mut path = std::env::temp_dir();
+        path.push(format!("kexa-node-maint-{}", rand::random::<u64>()));
+        Storage::open(path.to_str().expect("path")).expect("storage")
+    }
+
+    fn mine_block(storage: &Storage, address: u8) -> Block {
+        let (height, prev_hash) = storage.get_tip().expect("tip").expect("tip");
+        let coinbase = Transaction {
+            version: 0,
+            inputs: vec![],
+            outputs: vec![TxOut {
+                amount: block_subsidy(height + 1),
+                address: [address; 32],
+            }],
+        };
+        let mut header = BlockHeader {
+            version: 0,
+            prev_hash,
+            merkle_root: merkle_root(std::slice::from_ref(&coinbase)),
+            timestamp: crate::now_timestamp(),
+            bits: DIFFICULTY_BITS,
+            nonce: 0,
+            height: height + 1,
+        };
+        while !check_pow(&header) {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        let block = Block {
+            header,
+            txs: vec![coinbase],
+        };
+        storage.connect_block(&block).expect("connect");
+        block
+    }
+
+    #[test]
+    fn verify_chain_reports_tampered_utxo_set_until_reindexed() {
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        mine_block(&storage, 1);
+        let block = mine_block(&storage, 2);
+        verify_chain(&storage, 10).expect("clean chain verifies");
+
+        let reward = OutPoint {
+            txid: block.txs[0].txid(),
+            index: 0,
+        };
+        storage.delete_utxo(&reward).expect("delete");
+        let err = verify_chain(&storage, 10).unwrap_err();
+        assert!(err.to_string().contains("missing from the stored set"));
+
+        reindex(&storage).expect("reindex");
+        verify_chain(&storage, 10).expect("reindexed chain verifies");
+    }
+
+    fn temp_file() -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("kexa-node-blocks-{}", rand::random::<u64>()));
+        path
+    }
+
+    #[test]
+    fn exported_blocks_import_into_fresh_node() {
+        let source = temp_storage();
+        init_genesis(&source, &NetworkMode::Testnet).expect("genesis");
+        mine_block(&source, 1);
+        mine_block(&source, 2);
+        let path = temp_file();
+        export_blocks(&source, &path).expect("export");
+
+        let target = temp_storage();
+        init_genesis(&target, &NetworkMode::Testnet).expect("genesis");
+        import_blocks(&target, &path).expect("import");
+        assert_eq!(
+            target.get_tip().expect("tip"),
+            source.get_tip().expect("tip")
+        );
+        assert_eq!(
+            target.list_utxos().expect("utxos"),
+            source.list_utxos().expect("utxos")
+        );
+        // Rerunning skips everything already connected.
+        import_blocks(&target, &path).expect("reimport");
+
+        let diverged = temp_storage();
+        init_genesis(&diverged, &NetworkMode::Testnet).expect("genesis");
+        mine_block(&diverged, 3);
+        let err = import_blocks(&diverged, &path).unwrap_err();
+        assert!(err.to_string().contains("height 1"));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn import_rejects_invalid_and_truncated_files() {
+        let source = temp_storage();
+        init_genesis(&source, &NetworkMode::Testnet).expect("genesis");
+        let mut block = mine_block(&source, 1);
+        block.txs[0].outputs[0].amount += 1;
+        let bytes = borsh::to_vec(&block).expect("encode");
+        let path = temp_file();
+        let mut file = (bytes.len() as u32).to_be_bytes().to_vec();
+        file.extend_from_slice(&bytes);
+        std::fs::write(&path, &file).expect("write");
+
+        let target = temp_storage();
+        init_genesis(&target, &NetworkMode::Testnet).expect("genesis");
+        let err = import_blocks(&target, &path).unwrap_err();
+        assert!(format!("{err:#}").contains("merkle mismatch"));
+
+        std::fs::write(&path, &file[..file.len() - 1]).expect("write");
+        let err = import_blocks(&target, &path).unwrap_err();
+        assert!(format!("{err:#}").contains("truncated block"));
+        assert_eq!(target.get_tip().expect("tip").map(|(h, _)| h), Some(0));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn verify_chain_reports_first_invalid_block() {
+        let storage = temp_storage();
+        init_genesis(&storage, &NetworkMode::Testnet).expect("genesis");
+        mine_block(&storage, 1);
+        let mut bad = mine_block(&storage, 2);
+        storage.disconnect_block().expect("disconnect");
+        // Same block with an inflated coinbase, stored as if it were valid.
+        bad.txs[0].outputs[0].amount += 1;
+        bad.header.merkle_root = merkle_root(&bad.txs);
+        bad.header.nonce = 0;
+        while !check_pow(&bad.header) {
+            bad.header.nonce = bad.header.nonce.wrapping_add(1);
+        }
+        storage.connect_block(&bad).expect("connect");
+        mine_block(&storage, 3);
+
+        let err = verify_chain(&storage, 10).unwrap_err();
+        assert!(format!("{err:#}").contains("height 2"));
+        assert!(format!("{err:#}").contains("coinbase exceeds subsidy"));
+        // Blocks below the depth window are replayed, not re-validated.
+        verify_chain(&storage, 1).expect("shallow verify");
+    }
+}
@@ -0,0 +1,113 @@
+use kexa_proto::{Hash32, Transaction};
+use std::collections::HashMap;
+
+/// Transactions waiting to be mined, in arrival order and indexed by txid
+/// so announcements and requests are answered without scanning the pool.
+#[derive(Default)]
+pub(crate) struct Mempool {
+    txs: Vec<Transaction>,
+    /// Position in `txs` of each transaction.
+    index: HashMap<Hash32, usize>,
+}
+
+impl Mempool {
+    /// Adds `tx`. Returns false if it was held already.
+    pub(crate) fn insert(&mut self, tx: Transaction) -> bool {
+        let txid = tx.txid();
+        if self.index.contains_key(&txid) {
+            return false;
+        }
+        self.index.insert(txid, self.txs.len());
+        self.txs.push(tx);
+        true
+    }
+
+    pub(crate) fn contains(&self, txid: &Hash32) -> bool {
+        self.index.contains_key(txid)
+    }
+
+    pub(crate) fn get(&self, txid: &Hash32) -> Option<&Transaction> {
+        self.index.get(txid).map(|&pos| &self.txs[pos])
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.txs.iter()
+    }
+
+    pub(crate) fn as_slice(&self) -> &[Transaction] {
+        &self.txs
+    }
+
+    /// Removes and returns every transaction, in arrival order.
+    pub(crate) fn take_all(&mut self) -> Vec<Transaction> {
+        self.index.clear();
+        std::mem::take(&mut self.txs)
+    }
+
+    /// Keeps only the transactions for which `keep` returns true.
+    pub(crate) fn retain(&mut self, keep: impl FnMut(&Transaction) -> bool) {
+        self.txs.retain(keep);
+        self.reindex();
+    }
+
+    fn reindex(&mut self) {
+        self.index = self
+            .txs
+            .iter()
+            .enumerate()
+            .map(|(pos, tx)| (tx.txid(), pos))
+            .collect();
+    }
+}
+
+impl FromIterator<Transaction> for Mempool {
+    fn from_iter<I: IntoIterator<Item = Transaction>>(txs: I) -> Self {
+        let mut mempool = Mempool::default();
+        for tx in txs {
+            mempool.insert(tx);
+        }
+        mempool
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kexa_proto::{OutPoint, TxIn, TxOut};
+
+    fn tx(n: u8) -> Transaction {
+        Transaction {
+            version: 0,
+            inputs: vec![TxIn {
+                outpoint: OutPoint {
+                    txid: Hash32([n; 32]),
+                    index: 0,
+                },
+                signature: [0u8; 64],
+                pubkey: [0u8; 32],
+            }],
+            outputs: vec![TxOut {
+                amount: 1,
+                address: [0u8; 32],
+            }],
+        }
+    }
+
+    #[test]
+    fn lookups_follow_inserts_and_removals() {
+        let mut mempool: Mempool = (1..=3).map(tx).collect();
+        assert!(!mempool.insert(tx(2)));
+        assert_eq!(mempool.as_slice().len(), 3);
+        assert_eq!(mempool.get(&tx(3).txid()), Some(&tx(3)));
+
+        mempool.retain(|held| *held != tx(1));
+        assert!(!mempool.contains(&tx(1).txid()));
+        assert_eq!(mempool.get(&tx(2).txid()), Some(&tx(2)));
+        assert_eq!(mempool.get(&tx(3).txid()), Some(&tx(3)));
+        assert_eq!(mempool.as_slice(), &[tx(2), tx(3)]);
+
+        assert_eq!(mempool.take_all(), vec![tx(2), tx(3)]);
+        assert!(!mempool.contains(&tx(2).txid()));
+        assert!(mempool.insert(tx(2)));
+    }
+}
@@ -0,0 +1,184 @@
+use kexa_proto::{Block, BlockHeader, Hash32};
+use std::collections::HashMap;
+
+/// Most blocks held; beyond this the oldest are dropped.
+const MAX_ORPHANS: usize = 100;
+/// Most bytes of blocks held, room for eight blocks of the largest size.
+const MAX_ORPHAN_BYTES: usize = 8 * kexa_p2p::MAX_MESSAGE_SIZE;
+/// Blocks whose parents have not arrived in this long are dropped.
+const ORPHAN_EXPIRY_SECS: u64 = 20 * 60;
+
+struct Orphan {
+    block: Block,
+    size: usize,
+    /// Unix time the block arrived.
+    received_at: u64,
+    /// Arrival order, to drop the oldest first.
+    seq: u64,
+}
+
+/// Blocks received before their parents, such as ones announced while we
+/// are still syncing or sent out of order by different peers. They are
+/// connected once their parents are, or dropped when the pool is full or
+/// they have waited too long.
+#[derive(Default)]
+pub(crate) struct OrphanPool {
+    blocks: HashMap<Hash32, Orphan>,
+    /// Hashes of the held blocks by the hash of their parent.
+    by_parent: HashMap<Hash32, Vec<Hash32>>,
+    bytes: usize,
+    next_seq: u64,
+}
+
+impl OrphanPool {
+    /// Holds `block` until its parent is connected. Returns false if it was
+    /// held already.
+    pub(crate) fn insert(&mut self, block: Block, now: u64) -> bool {
+        let hash = block.header.hash();
+        if self.blocks.contains_key(&hash) {
+            return false;
+        }
+        let Ok(size) = borsh::object_length(&block) else {
+            return false;
+        };
+        if size > MAX_ORPHAN_BYTES {
+            return false;
+        }
+        self.expire(now);
+        while self.blocks.len() >= MAX_ORPHANS || self.bytes + size > MAX_ORPHAN_BYTES {
+            let Some(oldest) = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, orphan)| orphan.seq)
+                .map(|(hash, _)| *hash)
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+        self.by_parent
+            .entry(block.header.prev_hash)
+            .or_default()
+            .push(hash);
+        self.bytes += size;
+        self.blocks.insert(
+            hash,
+            Orphan {
+                block,
+                size,
+                received_at: now,
+                seq: self.next_seq,
+            },
+        );
+        self.next_seq += 1;
+        true
+    }
+
+    /// Removes and returns the held blocks whose parent is `parent`.
+    pub(crate) fn take_children(&mut self, parent: &Hash32) -> Vec<Block> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        children
+            .iter()
+            .filter_map(|hash| self.remove(hash))
+            .map(|orphan| orphan.block)
+            .collect()
+    }
+
+    /// Height and hash of the first block missing below `header`, following
+    /// its parents through the pool.
+    pub(crate) fn missing_ancestor(&self, header: &BlockHeader) -> (u64, Hash32) {
+        let mut header = header;
+        while let Some(parent) = self.blocks.get(&header.prev_hash) {
+            header = &parent.block.header;
+        }
+        (header.height.saturating_sub(1), header.prev_hash)
+    }
+
+    fn expire(&mut self, now: u64) {
+        let expired: Vec<Hash32> = self
+            .blocks
+            .iter()
+            .filter(|(_, orphan)| now.saturating_sub(orphan.received_at) > ORPHAN_EXPIRY_SECS)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &Hash32) -> Option<Orphan> {
+        let orphan = self.blocks.remove(hash)?;
+        let parent = orphan.block.header.prev_hash;
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+        self.bytes -= orphan.size;
+        Some(orphan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn block(prev_hash: Hash32, height: u64) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 0,
+                prev_hash,
+                merkle_root: Hash32::zero(),
+                timestamp: 0,
+                bits: 0,
+                nonce: 0,
+                height,
+            },
+            txs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn orphans_are_released_to_their_parents() {
+        let parent = Hash32([1u8; 32]);
+        let first = block(parent, 5);
+        let second = block(first.header.hash(), 6);
+        let mut pool = OrphanPool::default();
+        assert!(pool.insert(second.clone(), NOW));
+        assert_eq!(
+            pool.missing_ancestor(&second.header),
+            (5, first.header.hash())
+        );
+        assert!(pool.insert(first.clone(), NOW));
+        assert!(!pool.insert(first.clone(), NOW));
+        assert_eq!(pool.missing_ancestor(&second.header), (4, parent));
+
+        assert_eq!(pool.take_children(&parent), vec![first.clone()]);
+        assert_eq!(pool.take_children(&first.header.hash()), vec![second]);
+        assert!(pool.blocks.is_empty() && pool.by_parent.is_empty());
+        assert_eq!(pool.bytes, 0);
+    }
+
+    #[test]
+    fn pool_is_bounded_and_expires() {
+        let mut pool = OrphanPool::default();
+        let blocks: Vec<Block> = (0..=MAX_ORPHANS as u64)
+            .map(|i| block(Hash32([i as u8; 32]), i + 10))
+            .collect();
+        for (i, orphan) in blocks.iter().enumerate() {
+            assert!(pool.insert(orphan.clone(), NOW + i as u64));
+        }
+        // The oldest made room for the last.
+        assert_eq!(pool.blocks.len(), MAX_ORPHANS);
+        assert!(!pool.blocks.contains_key(&blocks[0].header.hash()));
+        assert!(pool.blocks.contains_key(&blocks[1].header.hash()));
+
+        let late = block(Hash32([200u8; 32]), 500);
+        assert!(pool.insert(late.clone(), NOW + 2 * ORPHAN_EXPIRY_SECS));
+        assert_eq!(pool.blocks.len(), 1);
+        assert_eq!(pool.take_children(&Hash32([200u8; 32])), vec![late]);
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use tracing::warn;
+
+/// Reads the JSON value saved at `path`. A missing or unreadable file gives
+/// the default, for state the node can rebuild as it runs.
+pub(crate) fn load_json_or_default<T: DeserializeOwned + Default>(path: &Path) -> T {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+            warn!("ignoring unreadable {}: {err}", path.display());
+            T::default()
+        }),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => T::default(),
+        Err(err) => {
+            warn!("ignoring unreadable {}: {err}", path.display());
+            T::default()
+        }
+    }
+}
+
+/// Writes `value` as JSON to `path` via a temporary file, so a crash never
+/// leaves it half written.
+pub(crate) fn save_json_atomic<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn saved_values_load_and_bad_files_fall_back_to_default() {
+        let dir = std::env::temp_dir().join(format!("kexa-persist-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).expect("dir");
+        let path = dir.join("table.json");
+        let empty: BTreeMap<String, u64> = load_json_or_default(&path);
+        assert!(empty.is_empty());
+
+        let table = BTreeMap::from([("a".to_string(), 1u64)]);
+        save_json_atomic(&path, &table).expect("save");
+        assert_eq!(load_json_or_default::<BTreeMap<String, u64>>(&path), table);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        std::fs::write(&path, b"{not json").expect("write");
+        assert!(load_json_or_default::<BTreeMap<String, u64>>(&path).is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
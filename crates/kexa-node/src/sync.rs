@@ -0,0 +1,401 @@
+use crate::banman::{misbehaving, BAN_THRESHOLD};
+use anyhow::{Context, Result};
+use kexa_consensus::{check_pow, DIFFICULTY_BITS};
+use kexa_p2p::{InvItem, MAX_HEADERS, MAX_LOCATOR_HASHES};
+use kexa_proto::{BlockHeader, Hash32};
+use kexa_storage::ChainStore;
+use std::collections::{HashSet, VecDeque};
+use tracing::info;
+
+/// Most blocks requested from one peer before earlier ones have arrived.
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+
+/// Checks everything about `header` that does not need its transactions:
+/// that it extends the parent at `parent_height`, its difficulty and its
+/// proof of work.
+pub(crate) fn validate_header(
+    parent_height: u64,
+    parent_hash: Hash32,
+    header: &BlockHeader,
+) -> Result<()> {
+    if header.height != parent_height + 1 {
+        anyhow::bail!("unexpected height");
+    }
+    if header.prev_hash != parent_hash {
+        anyhow::bail!("prev hash mismatch");
+    }
+    if header.bits != DIFFICULTY_BITS {
+        anyhow::bail!("difficulty mismatch");
+    }
+    if !check_pow(header) {
+        anyhow::bail!("pow invalid");
+    }
+    Ok(())
+}
+
+/// Hashes of our active chain for a `GetHeaders` locator: the last ten
+/// blocks, then ever wider gaps back to genesis, so a peer on another branch
+/// finds a recent shared block in one round trip.
+pub(crate) fn block_locator(storage: &dyn ChainStore) -> Result<Vec<Hash32>> {
+    let (mut height, _) = storage.get_tip()?.context("tip missing")?;
+    let mut locator = Vec::new();
+    let mut step = 1;
+    for entry in 1..MAX_LOCATOR_HASHES {
+        // Snapshot-based chains lack hashes below the snapshot until their
+        // history is validated; those are left out.
+        if let Some(hash) = storage.get_hash_by_height(height)? {
+            locator.push(hash);
+        }
+        if height == 0 {
+            break;
+        }
+        if entry >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+    let genesis = storage.get_hash_by_height(0)?.context("genesis missing")?;
+    if locator.last() != Some(&genesis) {
+        locator.push(genesis);
+    }
+    Ok(locator)
+}
+
+/// Height of the first `locator` hash on our active chain, the last block
+/// we share with the peer. Genesis if none is, which a peer on another
+/// network then fails to connect headers to.
+pub(crate) fn locate(storage: &dyn ChainStore, locator: &[Hash32]) -> Result<u64> {
+    for hash in locator.iter().take(MAX_LOCATOR_HASHES) {
+        if let Some(height) = storage.get_height_by_hash(hash)? {
+            return Ok(height);
+        }
+    }
+    Ok(0)
+}
+
+/// Headers-first download from one peer. The peer's header chain is fetched
+/// and checked ahead of its blocks, so a bogus chain costs a few hundred
+/// bytes per block to reject and the sync target is known up front. Blocks
+/// are then requested a few at a time, in height order.
+#[derive(Default)]
+pub(crate) struct HeaderSync {
+    /// Validated headers whose blocks have not been requested yet.
+    queue: VecDeque<BlockHeader>,
+    /// Blocks requested and not received yet.
+    in_flight: HashSet<Hash32>,
+    /// Last validated header, which the next `Headers` batch continues.
+    best: Option<(u64, Hash32)>,
+    awaiting_headers: bool,
+    /// Height of the last block shared with a peer found on another branch.
+    fork: Option<u64>,
+    /// Peer tip the last `GetHeaders` was sent for, if known.
+    requested_tip: Option<Hash32>,
+    /// Peer tip its branch was found at. Its headers are not asked for
+    /// again until it reports another tip.
+    fork_tip: Option<Hash32>,
+}
+
+impl HeaderSync {
+    /// Marks a `GetHeaders` as sent for the peer's `tip`, unless a sync is
+    /// already under way or that tip was found on another branch.
+    pub(crate) fn start(&mut self, tip: Option<Hash32>) -> bool {
+        if self.awaiting_headers || !self.queue.is_empty() || !self.in_flight.is_empty() {
+            return false;
+        }
+        if tip.is_some() && tip == self.fork_tip {
+            return false;
+        }
+        self.awaiting_headers = true;
+        self.requested_tip = tip;
+        true
+    }
+
+    /// Height of the best header the peer has sent, the sync target.
+    pub(crate) fn best_height(&self) -> Option<u64> {
+        self.best.map(|(height, _)| height)
+    }
+
+    /// Validates a `Headers` batch against our chain, or the previous batch,
+    /// and queues the blocks we lack. Returns the hash to locate more headers
+    /// from when the batch was full.
+    pub(crate) fn add_headers(
+        &mut self,
+        storage: &dyn ChainStore,
+        headers: &[BlockHeader],
+    ) -> Result<Option<Hash32>> {
+        self.awaiting_headers = false;
+        let Some(first) = headers.first() else {
+            return Ok(None);
+        };
+        let parent_height = first
+            .height
+            .checked_sub(1)
+            .context("headers start at genesis")?;
+        let mut parent = match self.best {
+            Some((height, hash)) if height == parent_height => (height, hash),
+            _ => {
+                let hash = storage
+                    .get_hash_by_height(parent_height)?
+                    .context("headers start above our chain")?;
+                (parent_height, hash)
+            }
+        };
+        let continues_batch = self.best == Some(parent);
+        for header in headers {
+            validate_header(parent.0, parent.1, header).map_err(|err| {
+                misbehaving(BAN_THRESHOLD, format!("invalid header chain: {err}"))
+            })?;
+            parent = (header.height, header.hash());
+        }
+        let mut lacking = Vec::new();
+        for header in headers {
+            if storage.get_hash_by_height(header.height)? != Some(header.hash()) {
+                lacking.push(header.clone());
+            }
+        }
+        // Headers follow the last block we share, though our tip may have
+        // moved on since we asked. One we lack at or below our tip means the
+        // peer's chain branches off ours there.
+        let (tip_height, _) = storage.get_tip()?.context("tip missing")?;
+        let branch = lacking
+            .first()
+            .filter(|header| !continues_batch && header.height <= tip_height);
+        if let Some(branch) = branch {
+            let fork_height = branch.height - 1;
+            if self.fork != Some(fork_height) {
+                info!(
+                    "peer is on another branch from height {fork_height}; switching needs reorg support"
+                );
+            }
+            self.fork = Some(fork_height);
+            self.fork_tip = self.requested_tip;
+            self.best = None;
+            return Ok(None);
+        }
+        self.queue.extend(lacking);
+        self.best = Some(parent);
+        if headers.len() < MAX_HEADERS {
+            return Ok(None);
+        }
+        self.awaiting_headers = true;
+        Ok(Some(parent.1))
+    }
+
+    /// Blocks to request next, keeping at most [`MAX_BLOCKS_IN_FLIGHT`]
+    /// outstanding.
+    pub(crate) fn next_blocks(&mut self) -> Vec<InvItem> {
+        let mut items = Vec::new();
+        while self.in_flight.len() < MAX_BLOCKS_IN_FLIGHT {
+            let Some(header) = self.queue.pop_front() else {
+                break;
+            };
+            let hash = header.hash();
+            self.in_flight.insert(hash);
+            items.push(InvItem::Block(hash));
+        }
+        items
+    }
+
+    /// Records a received block; `false` if it was not one we requested.
+    pub(crate) fn received(&mut self, hash: &Hash32) -> bool {
+        self.in_flight.remove(hash)
+    }
+
+    /// Abandons the sync when the peer cannot serve a block it sent the
+    /// header for; the next tip poll starts over.
+    pub(crate) fn not_found(&mut self, items: &[InvItem]) {
+        let missing_block = items.iter().any(|item| match item {
+            InvItem::Block(hash) => self.in_flight.contains(hash),
+            InvItem::Tx(_) => false,
+        });
+        if missing_block {
+            *self = Self {
+                fork: self.fork,
+                fork_tip: self.fork_tip,
+                ..Self::default()
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::build_testnet_genesis;
+    use kexa_proto::Block;
+    use kexa_storage::MemoryStore;
+
+    fn mine_header(parent: &BlockHeader) -> BlockHeader {
+        mine_branch(parent, Hash32::zero())
+    }
+
+    /// Children of the same parent on different branches differ in `branch`.
+    fn mine_branch(parent: &BlockHeader, branch: Hash32) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: 0,
+            prev_hash: parent.hash(),
+            merkle_root: branch,
+            timestamp: parent.timestamp + 1,
+            bits: DIFFICULTY_BITS,
+            nonce: 0,
+            height: parent.height + 1,
+        };
+        while !check_pow(&header) {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        header
+    }
+
+    /// Stores `header` as the next active block, without transactions.
+    fn extend(store: &MemoryStore, header: &BlockHeader) {
+        let hash = header.hash();
+        let block = Block {
+            header: header.clone(),
+            txs: Vec::new(),
+        };
+        store.put_block(&hash, &block).expect("block");
+        store.put_height_hash(header.height, &hash).expect("height");
+        store.set_tip(header.height, &hash).expect("tip");
+    }
+
+    fn genesis_store() -> (MemoryStore, BlockHeader) {
+        let (genesis, _) = build_testnet_genesis();
+        let store = MemoryStore::new();
+        store.put_genesis(&genesis).expect("genesis");
+        (store, genesis.header)
+    }
+
+    #[test]
+    fn headers_are_checked_before_blocks_are_requested() {
+        let (store, genesis) = genesis_store();
+        let first = mine_header(&genesis);
+        let second = mine_header(&first);
+        let mut sync = HeaderSync::default();
+        assert!(sync.start(None));
+        assert!(!sync.start(None));
+        let more = sync
+            .add_headers(&store, &[first.clone(), second.clone()])
+            .expect("valid headers");
+        assert_eq!(more, None);
+        assert_eq!(sync.best_height(), Some(2));
+        assert_eq!(
+            sync.next_blocks(),
+            vec![InvItem::Block(first.hash()), InvItem::Block(second.hash())]
+        );
+        assert!(!sync.start(None));
+        assert!(sync.received(&first.hash()));
+        assert!(!sync.received(&first.hash()));
+        assert!(sync.received(&second.hash()));
+        assert!(sync.start(None));
+    }
+
+    #[test]
+    fn headers_we_connected_meanwhile_are_not_a_fork() {
+        let (store, genesis) = genesis_store();
+        let first = mine_header(&genesis);
+        let second = mine_header(&first);
+        let mut sync = HeaderSync::default();
+        assert!(sync.start(None));
+        // Another peer's block extends our tip before the headers arrive.
+        extend(&store, &first);
+        let more = sync
+            .add_headers(&store, &[first, second.clone()])
+            .expect("valid headers");
+        assert_eq!(more, None);
+        assert_eq!(sync.fork, None);
+        assert_eq!(sync.best_height(), Some(2));
+        assert_eq!(sync.next_blocks(), vec![InvItem::Block(second.hash())]);
+    }
+
+    #[test]
+    fn bogus_header_chains_are_rejected() {
+        let (store, genesis) = genesis_store();
+        let first = mine_header(&genesis);
+
+        let mut easy = first.clone();
+        easy.bits = 0;
+        let err = HeaderSync::default()
+            .add_headers(&store, &[easy])
+            .unwrap_err();
+        assert!(err.to_string().contains("difficulty mismatch"));
+
+        let mut unlinked = mine_header(&first);
+        unlinked.prev_hash = Hash32::zero();
+        let err = HeaderSync::default()
+            .add_headers(&store, &[first.clone(), unlinked])
+            .unwrap_err();
+        assert!(err.to_string().contains("prev hash mismatch"));
+
+        let mut forged = first.clone();
+        while check_pow(&forged) {
+            forged.nonce = forged.nonce.wrapping_add(1);
+        }
+        let err = HeaderSync::default()
+            .add_headers(&store, &[forged])
+            .unwrap_err();
+        assert!(err.to_string().contains("pow invalid"));
+    }
+
+    #[test]
+    fn locator_spaces_hashes_exponentially_back_to_genesis() {
+        let (store, _) = genesis_store();
+        let hash_at = |height: u64| {
+            let mut hash = [1u8; 32];
+            hash[..8].copy_from_slice(&height.to_be_bytes());
+            Hash32(hash)
+        };
+        for height in 1..=100 {
+            store
+                .put_height_hash(height, &hash_at(height))
+                .expect("height");
+        }
+        store.set_tip(100, &hash_at(100)).expect("tip");
+        let mut expected: Vec<Hash32> = (91..=100).rev().map(hash_at).collect();
+        expected.extend([89, 85, 77, 61, 29].map(hash_at));
+        expected.push(store.get_hash_by_height(0).expect("read").expect("genesis"));
+        assert_eq!(block_locator(&store).expect("locator"), expected);
+    }
+
+    #[test]
+    fn forks_are_located_at_the_last_shared_block() {
+        let (ours, genesis) = genesis_store();
+        let shared = mine_header(&genesis);
+        let our_block = mine_branch(&shared, Hash32([1u8; 32]));
+        extend(&ours, &shared);
+        extend(&ours, &our_block);
+
+        // The peer knows our branch's block but builds on another one.
+        let (theirs, _) = genesis_store();
+        let their_block = mine_branch(&shared, Hash32([2u8; 32]));
+        let their_next = mine_header(&their_block);
+        extend(&theirs, &shared);
+        extend(&theirs, &their_block);
+        extend(&theirs, &their_next);
+        theirs
+            .put_block(
+                &our_block.hash(),
+                &Block {
+                    header: our_block.clone(),
+                    txs: Vec::new(),
+                },
+            )
+            .expect("side block");
+
+        let locator = block_locator(&ours).expect("locator");
+        assert_eq!(locate(&theirs, &locator).expect("locate"), 1);
+
+        // Their headers after the shared block are recognised as a fork
+        // rather than queued for download.
+        let mut sync = HeaderSync::default();
+        assert!(sync.start(Some(their_next.hash())));
+        let more = sync
+            .add_headers(&ours, &[their_block, their_next.clone()])
+            .expect("valid headers");
+        assert_eq!(more, None);
+        assert!(sync.next_blocks().is_empty());
+        assert_eq!(sync.fork, Some(1));
+        // Nor asked for again until the peer's tip moves.
+        assert!(!sync.start(Some(their_next.hash())));
+        assert!(sync.start(Some(mine_header(&their_next).hash())));
+    }
+}
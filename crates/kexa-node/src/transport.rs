@@ -0,0 +1,473 @@
+use anyhow::{Context, Result};
+use bytes::{Buf, BytesMut};
+use clap::ValueEnum;
+use kexa_p2p::Magic;
+use serde::{Deserialize, Serialize};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, ready, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Sent first by a node opening an encrypted connection, where a plaintext
+/// one sends the network magic of its first frame.
+const NOISE_PREAMBLE: [u8; 4] = *b"kxnx";
+const MAX_NOISE_MESSAGE: usize = 65_535;
+const TAG_LEN: usize = 16;
+/// Most frame bytes one Noise message carries; longer frames are split.
+const MAX_CHUNK: usize = MAX_NOISE_MESSAGE - TAG_LEN;
+
+/// Whether P2P connections are encrypted.
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq, Eq)]
+pub(crate) enum Encryption {
+    /// Dial in plaintext; encrypted inbound connections are still accepted.
+    Off,
+    /// Dial encrypted, falling back to plaintext for peers without support.
+    Prefer,
+    /// Only encrypted connections, in both directions.
+    Require,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NodeKeyFile {
+    secret: [u8; 32],
+}
+
+/// The node's static x25519 key, which identifies it to encrypted peers.
+pub(crate) struct NodeKey {
+    secret: [u8; 32],
+    public: [u8; 32],
+}
+
+impl NodeKey {
+    pub(crate) fn generate() -> Self {
+        Self::from_secret(rand::random())
+    }
+
+    /// Loads the key at `path`, creating it on first start.
+    pub(crate) fn load_or_create(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => {
+                let file: NodeKeyFile = serde_json::from_slice(&data)
+                    .with_context(|| format!("invalid node key {}", path.display()))?;
+                Ok(Self::from_secret(file.secret))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let key = Self::generate();
+                let file = NodeKeyFile { secret: key.secret };
+                write_private(path, &serde_json::to_vec_pretty(&file)?)
+                    .with_context(|| format!("writing {}", path.display()))?;
+                Ok(key)
+            }
+            Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    fn from_secret(secret: [u8; 32]) -> Self {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("x25519 is built in");
+        dh.set(&secret);
+        let mut public = [0u8; 32];
+        public.copy_from_slice(dh.pubkey());
+        Self { secret, public }
+    }
+
+    pub(crate) fn public(&self) -> &[u8; 32] {
+        &self.public
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    std::fs::write(path, data)
+}
+
+/// A connected peer's byte stream, encrypted or not. On encrypted
+/// connections bytes are sent as Noise messages of a `u16` BE length and the
+/// ciphertext.
+pub(crate) struct Transport {
+    pub(crate) reader: PeerReader,
+    pub(crate) writer: PeerWriter,
+    pub(crate) remote: SocketAddr,
+    /// The peer's static key, on encrypted connections.
+    pub(crate) remote_key: Option<[u8; 32]>,
+}
+
+impl Transport {
+    pub(crate) fn plain(stream: TcpStream) -> Result<Self> {
+        let remote = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            reader: PeerReader::Plain(reader),
+            writer: PeerWriter::Plain(writer),
+            remote,
+            remote_key: None,
+        })
+    }
+
+    /// Opens an encrypted connection with Noise XX. A peer without
+    /// encryption support hangs up on the preamble.
+    pub(crate) async fn initiate(
+        mut stream: TcpStream,
+        key: &NodeKey,
+        magic: Magic,
+    ) -> Result<Self> {
+        let mut noise = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&key.secret)
+            .prologue(&prologue(magic))
+            .build_initiator()?;
+        stream.write_all(&NOISE_PREAMBLE).await?;
+        send_handshake(&mut stream, &mut noise).await?;
+        recv_handshake(&mut stream, &mut noise).await?;
+        send_handshake(&mut stream, &mut noise).await?;
+        Self::encrypted(stream, noise)
+    }
+
+    /// Answers a new inbound connection: encrypted if it opens with the
+    /// Noise preamble, plaintext otherwise unless encryption is required.
+    pub(crate) async fn accept(
+        mut stream: TcpStream,
+        key: &NodeKey,
+        magic: Magic,
+        encryption: Encryption,
+    ) -> Result<Self> {
+        let mut first = [0u8; NOISE_PREAMBLE.len()];
+        loop {
+            let read = stream.peek(&mut first).await?;
+            if read == 0 || read == first.len() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        if first != NOISE_PREAMBLE {
+            if encryption == Encryption::Require {
+                anyhow::bail!("plaintext connection refused, encryption is required");
+            }
+            return Self::plain(stream);
+        }
+        stream.read_exact(&mut first).await?;
+        let mut noise = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&key.secret)
+            .prologue(&prologue(magic))
+            .build_responder()?;
+        recv_handshake(&mut stream, &mut noise).await?;
+        send_handshake(&mut stream, &mut noise).await?;
+        recv_handshake(&mut stream, &mut noise).await?;
+        Self::encrypted(stream, noise)
+    }
+
+    fn encrypted(stream: TcpStream, noise: HandshakeState) -> Result<Self> {
+        let mut remote_key = [0u8; 32];
+        remote_key.copy_from_slice(
+            noise
+                .get_remote_static()
+                .context("peer sent no static key")?,
+        );
+        let noise = Arc::new(noise.into_stateless_transport_mode()?);
+        let remote = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            reader: PeerReader::Noise(NoiseReader {
+                inner: reader,
+                noise: noise.clone(),
+                nonce: 0,
+                ciphertext: BytesMut::new(),
+                plaintext: BytesMut::new(),
+            }),
+            writer: PeerWriter::Noise(NoiseWriter {
+                inner: writer,
+                noise,
+                nonce: 0,
+                pending: BytesMut::new(),
+            }),
+            remote,
+            remote_key: Some(remote_key),
+        })
+    }
+}
+
+/// Binds the handshake to the network, so peers of another one fail it.
+fn prologue(magic: Magic) -> Vec<u8> {
+    [b"kexa-p2p".as_slice(), &magic].concat()
+}
+
+async fn send_handshake(stream: &mut TcpStream, noise: &mut HandshakeState) -> Result<()> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = noise.write_message(&[], &mut message)?;
+    stream.write_u16(len as u16).await?;
+    stream.write_all(&message[..len]).await?;
+    Ok(())
+}
+
+async fn recv_handshake(stream: &mut TcpStream, noise: &mut HandshakeState) -> Result<()> {
+    let len = stream.read_u16().await? as usize;
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).await?;
+    let mut payload = vec![0u8; len];
+    noise.read_message(&message, &mut payload)?;
+    Ok(())
+}
+
+fn invalid_data(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+pub(crate) enum PeerReader {
+    Plain(OwnedReadHalf),
+    Noise(NoiseReader),
+}
+
+impl AsyncRead for PeerReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_read(cx, buf),
+            Self::Noise(inner) => inner.poll_read(cx, buf),
+        }
+    }
+}
+
+pub(crate) struct NoiseReader {
+    inner: OwnedReadHalf,
+    noise: Arc<StatelessTransportState>,
+    nonce: u64,
+    /// Received bytes not decrypted yet, short of a whole Noise message.
+    ciphertext: BytesMut,
+    /// Decrypted bytes not read yet.
+    plaintext: BytesMut,
+}
+
+impl NoiseReader {
+    fn poll_read(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.plaintext.is_empty() {
+            if self.decrypt_next()? {
+                continue;
+            }
+            let mut chunk = [0u8; 8 * 1024];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                if self.ciphertext.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.ciphertext.extend_from_slice(read.filled());
+        }
+        let len = buf.remaining().min(self.plaintext.len());
+        buf.put_slice(&self.plaintext.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+
+    /// Decrypts the next Noise message into `plaintext`; `false` until one
+    /// has been received whole.
+    fn decrypt_next(&mut self) -> io::Result<bool> {
+        let Some(len) = self
+            .ciphertext
+            .get(..2)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        else {
+            return Ok(false);
+        };
+        if self.ciphertext.len() < 2 + len {
+            return Ok(false);
+        }
+        self.ciphertext.advance(2);
+        let message = self.ciphertext.split_to(len);
+        self.plaintext.resize(len, 0);
+        let read = self
+            .noise
+            .read_message(self.nonce, &message, &mut self.plaintext)
+            .map_err(invalid_data)?;
+        self.plaintext.truncate(read);
+        self.nonce += 1;
+        Ok(true)
+    }
+}
+
+pub(crate) enum PeerWriter {
+    Plain(OwnedWriteHalf),
+    Noise(NoiseWriter),
+}
+
+impl AsyncWrite for PeerWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_write(cx, data),
+            Self::Noise(inner) => inner.poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_flush(cx),
+            Self::Noise(inner) => {
+                ready!(inner.poll_send_pending(cx))?;
+                Pin::new(&mut inner.inner).poll_flush(cx)
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_shutdown(cx),
+            Self::Noise(inner) => {
+                ready!(inner.poll_send_pending(cx))?;
+                Pin::new(&mut inner.inner).poll_shutdown(cx)
+            }
+        }
+    }
+}
+
+/// Encrypts each write of up to [`MAX_CHUNK`] bytes as one Noise message.
+pub(crate) struct NoiseWriter {
+    inner: OwnedWriteHalf,
+    noise: Arc<StatelessTransportState>,
+    nonce: u64,
+    /// The last Noise message, as far as it is not sent yet.
+    pending: BytesMut,
+}
+
+impl NoiseWriter {
+    fn poll_write(&mut self, cx: &mut task::Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_send_pending(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let chunk = &data[..data.len().min(MAX_CHUNK)];
+        self.pending.resize(2 + chunk.len() + TAG_LEN, 0);
+        let len = self
+            .noise
+            .write_message(self.nonce, chunk, &mut self.pending[2..])
+            .map_err(invalid_data)?;
+        self.nonce += 1;
+        self.pending[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        self.pending.truncate(2 + len);
+        Poll::Ready(Ok(chunk.len()))
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let sent = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if sent == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(sent);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kexa_p2p::{MAINNET_MAGIC, TESTNET_MAGIC};
+    use tokio::net::TcpListener;
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.expect("connect"), server.expect("accept").0)
+    }
+
+    #[tokio::test]
+    async fn encrypted_connections_authenticate_both_ends() {
+        let (client, server) = tcp_pair().await;
+        let (ours, theirs) = (NodeKey::generate(), NodeKey::generate());
+        let (dialled, accepted) = tokio::join!(
+            Transport::initiate(client, &ours, TESTNET_MAGIC),
+            Transport::accept(server, &theirs, TESTNET_MAGIC, Encryption::Require),
+        );
+        let (mut dialled, mut accepted) = (dialled.expect("initiate"), accepted.expect("accept"));
+        assert_eq!(dialled.remote_key, Some(*theirs.public()));
+        assert_eq!(accepted.remote_key, Some(*ours.public()));
+
+        // Frames larger than one Noise message arrive whole.
+        let frame: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        dialled.writer.write_all(&frame).await.expect("write");
+        dialled.writer.write_all(b"next").await.expect("write");
+        dialled.writer.flush().await.expect("flush");
+        let mut received = vec![0u8; frame.len()];
+        accepted
+            .reader
+            .read_exact(&mut received)
+            .await
+            .expect("read");
+        assert_eq!(received, frame);
+        let mut next = [0u8; 4];
+        accepted.reader.read_exact(&mut next).await.expect("read");
+        assert_eq!(&next, b"next");
+    }
+
+    #[tokio::test]
+    async fn handshakes_fail_across_networks() {
+        let (client, server) = tcp_pair().await;
+        let (ours, theirs) = (NodeKey::generate(), NodeKey::generate());
+        let (dialled, accepted) = tokio::join!(
+            Transport::initiate(client, &ours, TESTNET_MAGIC),
+            Transport::accept(server, &theirs, MAINNET_MAGIC, Encryption::Prefer),
+        );
+        assert!(dialled.is_err() || accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn plaintext_peers_are_accepted_unless_encryption_is_required() {
+        let key = NodeKey::generate();
+        for (encryption, accepted) in [(Encryption::Prefer, true), (Encryption::Require, false)] {
+            let (mut client, server) = tcp_pair().await;
+            client.write_all(&TESTNET_MAGIC).await.expect("write");
+            let transport = Transport::accept(server, &key, TESTNET_MAGIC, encryption).await;
+            assert_eq!(transport.is_ok(), accepted);
+            if let Ok(mut transport) = transport {
+                assert_eq!(transport.remote_key, None);
+                let mut magic = [0u8; 4];
+                transport.reader.read_exact(&mut magic).await.expect("read");
+                assert_eq!(magic, TESTNET_MAGIC);
+            }
+        }
+    }
+
+    #[test]
+    fn node_key_is_created_once() {
+        let dir = std::env::temp_dir().join(format!("kexa-node-key-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).expect("dir");
+        let path = dir.join("p2p_key.json");
+        let created = NodeKey::load_or_create(&path).expect("create");
+        let loaded = NodeKey::load_or_create(&path).expect("load");
+        assert_eq!(created.public(), loaded.public());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::{
+    Magic, Message, FRAME_HEADER_LEN, MAX_ADDRS, MAX_HEADERS, MAX_INV_ITEMS, MAX_LOCATOR_HASHES,
+    MAX_MESSAGE_SIZE, MAX_USER_AGENT_LEN,
+};
+use borsh::BorshDeserialize;
+use bytes::{Buf, BufMut, BytesMut};
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Borsh size of a `BlockHeader`.
+const HEADER_LEN: usize = 93;
+/// Longest `ip:port` in an `Addr`, a bracketed IPv6 address with a port.
+const MAX_ADDR_LEN: usize = 47;
+/// Limit for messages of a few fixed-size fields.
+const SMALL_LIMIT: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    /// The frame was sent by a node of another network.
+    #[error("network magic mismatch: expected {expected:02x?}, got {got:02x?}")]
+    MagicMismatch { expected: Magic, got: Magic },
+    /// The payload exceeds the limit for its message type.
+    #[error("message too large: {len} byte {kind}, limit {limit}")]
+    TooLarge {
+        kind: &'static str,
+        len: usize,
+        limit: usize,
+    },
+    /// The payload does not decode as a message.
+    #[error("malformed message: {0}")]
+    Malformed(String),
+    /// No whole message arrived within the read timeout.
+    #[error("timed out waiting for a message")]
+    Timeout,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl FrameError {
+    /// Whether the peer sent something no node of our network would, as
+    /// opposed to being of another network or its connection failing.
+    pub fn is_malformed(&self) -> bool {
+        matches!(self, Self::TooLarge { .. } | Self::Malformed(_))
+    }
+}
+
+/// Payload of a list message of at most `items` of `item_len` bytes: the
+/// tag, a u32 item count, then the items.
+const fn list(items: usize, item_len: usize) -> usize {
+    5 + items * item_len
+}
+
+/// Name and largest payload of each message type, indexed by its borsh
+/// variant tag, the first payload byte, so a frame can be refused before its
+/// payload is buffered.
+const LIMITS: [(&str, usize); 17] = [
+    ("version", SMALL_LIMIT + MAX_USER_AGENT_LEN + 64),
+    ("getblock", SMALL_LIMIT),
+    ("getblocks", SMALL_LIMIT),
+    ("block", MAX_MESSAGE_SIZE),
+    ("gettip", SMALL_LIMIT),
+    ("tip", SMALL_LIMIT),
+    ("inv", list(MAX_INV_ITEMS, 33)),
+    ("getdata", list(MAX_INV_ITEMS, 33)),
+    ("tx", MAX_MESSAGE_SIZE),
+    ("notfound", list(MAX_INV_ITEMS, 33)),
+    ("getheaders", list(MAX_LOCATOR_HASHES, 32)),
+    ("headers", list(MAX_HEADERS, HEADER_LEN)),
+    ("verack", SMALL_LIMIT),
+    ("getaddr", SMALL_LIMIT),
+    ("addr", list(MAX_ADDRS, 4 + MAX_ADDR_LEN + 8)),
+    ("ping", SMALL_LIMIT),
+    ("pong", SMALL_LIMIT),
+];
+
+/// Borsh variant tag of `message`, following the order of [`Message`]
+/// variants. Exhaustive, so a new message type needs an entry in [`LIMITS`]
+/// before it can be sent; a test checks the tags against borsh.
+fn message_tag(message: &Message) -> u8 {
+    match message {
+        Message::Version { .. } => 0,
+        Message::GetBlock { .. } => 1,
+        Message::GetBlocks { .. } => 2,
+        Message::Block { .. } => 3,
+        Message::GetTip => 4,
+        Message::Tip { .. } => 5,
+        Message::Inv { .. } => 6,
+        Message::GetData { .. } => 7,
+        Message::Tx { .. } => 8,
+        Message::NotFound { .. } => 9,
+        Message::GetHeaders { .. } => 10,
+        Message::Headers { .. } => 11,
+        Message::Verack => 12,
+        Message::GetAddr => 13,
+        Message::Addr { .. } => 14,
+        Message::Ping { .. } => 15,
+        Message::Pong { .. } => 16,
+    }
+}
+
+/// Name and largest payload of the type of `message`.
+fn message_limit(message: &Message) -> (&'static str, usize) {
+    LIMITS[message_tag(message) as usize]
+}
+
+/// [`LIMITS`] entry for the borsh variant `tag`; `None` for unknown types.
+fn payload_limit(tag: u8) -> Option<(&'static str, usize)> {
+    LIMITS.get(tag as usize).copied()
+}
+
+/// Frames [`Message`]s as the network magic, the payload length as u32 BE
+/// and the borsh payload. Oversized payloads are refused from the frame
+/// header and message type, before their bytes are buffered.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageCodec {
+    magic: Magic,
+}
+
+impl MessageCodec {
+    pub fn new(magic: Magic) -> Self {
+        Self { magic }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = FrameError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Message>, FrameError> {
+        if buf.len() >= 4 && buf[..4] != self.magic {
+            let mut got = [0u8; 4];
+            got.copy_from_slice(&buf[..4]);
+            return Err(FrameError::MagicMismatch {
+                expected: self.magic,
+                got,
+            });
+        }
+        if buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        if len == 0 {
+            return Err(FrameError::Malformed("empty payload".to_string()));
+        }
+        let Some(&tag) = buf.get(FRAME_HEADER_LEN) else {
+            return Ok(None);
+        };
+        let (kind, limit) = payload_limit(tag)
+            .ok_or_else(|| FrameError::Malformed(format!("unknown message type {tag}")))?;
+        if len > limit {
+            return Err(FrameError::TooLarge { kind, len, limit });
+        }
+        if buf.len() < FRAME_HEADER_LEN + len {
+            buf.reserve(FRAME_HEADER_LEN + len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(FRAME_HEADER_LEN);
+        let payload = buf.split_to(len);
+        Message::try_from_slice(&payload)
+            .map(Some)
+            .map_err(|err| FrameError::Malformed(err.to_string()))
+    }
+}
+
+impl Encoder<&Message> for MessageCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, message: &Message, buf: &mut BytesMut) -> Result<(), FrameError> {
+        let payload = borsh::to_vec(message)?;
+        // Never send what the peer would refuse.
+        let (kind, limit) = message_limit(message);
+        if payload.len() > limit {
+            return Err(FrameError::TooLarge {
+                kind,
+                len: payload.len(),
+                limit,
+            });
+        }
+        buf.reserve(FRAME_HEADER_LEN + payload.len());
+        buf.extend_from_slice(&self.magic);
+        buf.put_u32(payload.len() as u32);
+        buf.extend_from_slice(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InvItem, PeerAddr, TESTNET_MAGIC};
+    use kexa_proto::{Block, BlockHeader, Hash32, Transaction};
+
+    fn encode(message: &Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        MessageCodec::new(TESTNET_MAGIC)
+            .encode(message, &mut buf)
+            .expect("encode");
+        buf
+    }
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            version: 0,
+            prev_hash: Hash32::zero(),
+            merkle_root: Hash32::zero(),
+            timestamp: 0,
+            bits: 0,
+            nonce: 0,
+            height: 0,
+        }
+    }
+
+    /// The largest allowed message of every type but blocks and
+    /// transactions, whose size the limits do not depend on.
+    fn largest_messages() -> Vec<Message> {
+        let header = header();
+        let items = vec![InvItem::Block(Hash32::zero()); MAX_INV_ITEMS];
+        let addr = format!("[{}]:65535", ["ffff"; 8].join(":"));
+        assert_eq!(addr.len(), MAX_ADDR_LEN);
+        vec![
+            Message::Version {
+                protocol_version: u32::MAX,
+                services: u64::MAX,
+                genesis: Hash32::zero(),
+                nonce: u64::MAX,
+                user_agent: "x".repeat(MAX_USER_AGENT_LEN),
+                listen_port: u16::MAX,
+                height: u64::MAX,
+                tip: Hash32::zero(),
+            },
+            Message::GetBlock {
+                hash: Hash32::zero(),
+            },
+            Message::GetBlocks { start_height: 0 },
+            Message::GetTip,
+            Message::Tip {
+                height: 0,
+                tip: Hash32::zero(),
+            },
+            Message::Inv {
+                items: items.clone(),
+            },
+            Message::GetData {
+                items: items.clone(),
+            },
+            Message::NotFound { items },
+            Message::GetHeaders {
+                locator: vec![Hash32::zero(); MAX_LOCATOR_HASHES],
+            },
+            Message::Headers {
+                headers: vec![header; MAX_HEADERS],
+            },
+            Message::Verack,
+            Message::GetAddr,
+            Message::Addr {
+                addrs: vec![
+                    PeerAddr {
+                        addr,
+                        last_seen: u64::MAX,
+                    };
+                    MAX_ADDRS
+                ],
+            },
+            Message::Ping { nonce: 0 },
+            Message::Pong { nonce: 0 },
+        ]
+    }
+
+    #[test]
+    fn largest_allowed_messages_fit_their_limits() {
+        assert_eq!(borsh::to_vec(&header()).expect("header").len(), HEADER_LEN);
+        let largest = largest_messages();
+        for message in &largest {
+            let mut buf = encode(message);
+            let decoded = MessageCodec::new(TESTNET_MAGIC)
+                .decode(&mut buf)
+                .expect("decode")
+                .expect("whole message");
+            assert_eq!(format!("{decoded:?}"), format!("{message:?}"));
+        }
+    }
+
+    #[test]
+    fn tag_table_matches_every_message_type() {
+        let mut messages = largest_messages();
+        messages.push(Message::Block {
+            block: Block {
+                header: header(),
+                txs: Vec::new(),
+            },
+        });
+        messages.push(Message::Tx {
+            tx: Transaction {
+                version: 0,
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+            },
+        });
+        let mut tags = Vec::new();
+        for message in &messages {
+            let tag = borsh::to_vec(message).expect("encode")[0];
+            assert_eq!(message_tag(message), tag, "{message:?}");
+            tags.push(tag);
+        }
+        // One message of each type, and no table entries beyond them.
+        tags.sort_unstable();
+        let count = tags.len() as u8;
+        assert_eq!(tags, (0..count).collect::<Vec<_>>());
+        assert_eq!(payload_limit(count), None);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_from_their_header() {
+        let mut codec = MessageCodec::new(TESTNET_MAGIC);
+        let mut buf = encode(&Message::Ping { nonce: 1 });
+        buf[4..8].copy_from_slice(&1_000u32.to_be_bytes());
+        match codec.decode(&mut buf) {
+            Err(FrameError::TooLarge { kind, len, limit }) => {
+                assert_eq!((kind, len, limit), ("ping", 1_000, SMALL_LIMIT));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        // A partial frame is waited on, and the next one decodes after it.
+        let first = encode(&Message::Ping { nonce: 2 });
+        let mut buf = BytesMut::from(&first[..first.len() - 1]);
+        assert!(codec.decode(&mut buf).expect("partial").is_none());
+        buf.extend_from_slice(&first[first.len() - 1..]);
+        buf.extend_from_slice(&encode(&Message::Verack));
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Ok(Some(Message::Ping { nonce: 2 }))
+        ));
+        assert!(matches!(codec.decode(&mut buf), Ok(Some(Message::Verack))));
+        assert!(buf.is_empty());
+
+        let mut buf = encode(&Message::GetTip);
+        buf[FRAME_HEADER_LEN] = 200;
+        assert!(codec.decode(&mut buf).unwrap_err().is_malformed());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::codec::{FrameError, MessageCodec};
+use crate::{Magic, Message};
+use bytes::BytesMut;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A connection to a peer that carries [`Message`]s over any byte stream,
+/// such as the halves of a TCP socket or an encrypted channel on one.
+pub struct PeerConnection<R, W> {
+    reader: MessageReader<R>,
+    writer: MessageWriter<W>,
+}
+
+impl<R, W> PeerConnection<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Reads wait at most `read_timeout` for each message.
+    pub fn new(magic: Magic, reader: R, writer: W, read_timeout: Duration) -> Self {
+        Self {
+            reader: MessageReader::new(magic, reader, read_timeout),
+            writer: MessageWriter::new(magic, writer),
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<Option<Message>, FrameError> {
+        self.reader.recv().await
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<(), FrameError> {
+        self.writer.send(message).await
+    }
+
+    /// Splits the connection, to read and write from different tasks.
+    pub fn into_split(self) -> (MessageReader<R>, MessageWriter<W>) {
+        (self.reader, self.writer)
+    }
+}
+
+pub struct MessageReader<R> {
+    inner: R,
+    codec: MessageCodec,
+    buf: BytesMut,
+    read_timeout: Duration,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(magic: Magic, inner: R, read_timeout: Duration) -> Self {
+        Self {
+            inner,
+            codec: MessageCodec::new(magic),
+            buf: BytesMut::new(),
+            read_timeout,
+        }
+    }
+
+    /// Next message, or `None` once the peer closes the connection between
+    /// messages. Fails with [`FrameError::Timeout`] if no whole message
+    /// arrives within the read timeout.
+    pub async fn recv(&mut self) -> Result<Option<Message>, FrameError> {
+        tokio::time::timeout(self.read_timeout, self.next())
+            .await
+            .map_err(|_| FrameError::Timeout)?
+    }
+
+    async fn next(&mut self) -> Result<Option<Message>, FrameError> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.buf)? {
+                return Ok(Some(message));
+            }
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
+
+pub struct MessageWriter<W> {
+    inner: W,
+    codec: MessageCodec,
+    buf: BytesMut,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    pub fn new(magic: Magic, inner: W) -> Self {
+        Self {
+            inner,
+            codec: MessageCodec::new(magic),
+            buf: BytesMut::new(),
+        }
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<(), FrameError> {
+        self.buf.clear();
+        self.codec.encode(message, &mut self.buf)?;
+        self.inner.write_all(&self.buf).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TESTNET_MAGIC;
+
+    #[tokio::test]
+    async fn messages_cross_a_connection_and_idle_reads_time_out() {
+        let (ours, theirs) = tokio::io::duplex(64);
+        let (our_reader, our_writer) = tokio::io::split(ours);
+        let (their_reader, their_writer) = tokio::io::split(theirs);
+        let timeout = Duration::from_millis(100);
+        let mut ours = PeerConnection::new(TESTNET_MAGIC, our_reader, our_writer, timeout);
+        let mut theirs = PeerConnection::new(TESTNET_MAGIC, their_reader, their_writer, timeout);
+
+        let locator = vec![kexa_proto::Hash32([7u8; 32]); 10];
+        let message = Message::GetHeaders {
+            locator: locator.clone(),
+        };
+        let (sent, received) = tokio::join!(ours.send(&message), theirs.recv());
+        sent.expect("send");
+        match received.expect("recv") {
+            Some(Message::GetHeaders { locator: received }) => assert_eq!(received, locator),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        assert!(matches!(theirs.recv().await, Err(FrameError::Timeout)));
+        drop(ours);
+        assert!(theirs.recv().await.expect("closed").is_none());
+    }
+}
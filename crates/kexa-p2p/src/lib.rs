@@ -1,69 +1,201 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use borsh::{BorshDeserialize, BorshSerialize};
-use bytes::{Buf, BufMut, BytesMut};
-use kexa_proto::{Block, Hash32};
+use bytes::BytesMut;
+use kexa_proto::{Block, BlockHeader, Hash32, Transaction};
+use tokio_util::codec::{Decoder, Encoder};
 
+mod codec;
+mod connection;
+
+pub use codec::{FrameError, MessageCodec};
+pub use connection::{MessageReader, MessageWriter, PeerConnection};
+
+/// Largest payload of any message; most message types have a lower limit.
 pub const MAX_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
+/// Network magic, then the payload length as u32 BE.
+pub const FRAME_HEADER_LEN: usize = 8;
+
+/// Starts every frame, so nodes of different networks tell each other apart
+/// before decoding anything.
+pub type Magic = [u8; 4];
+pub const TESTNET_MAGIC: Magic = *b"kxat";
+pub const MAINNET_MAGIC: Magic = *b"kxam";
+
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version a node still talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// `Version` service bit: serves every block from genesis. Unset while a node
+/// started from a UTXO snapshot is still validating the history below it.
+pub const SERVICE_FULL_HISTORY: u64 = 1 << 0;
+pub const MAX_USER_AGENT_LEN: usize = 256;
+/// Most items a single `Inv`, `GetData` or `NotFound` may carry.
+pub const MAX_INV_ITEMS: usize = 1_000;
+/// Most headers a single `Headers` may carry.
+pub const MAX_HEADERS: usize = 2_000;
+/// Most hashes a `GetHeaders` locator may carry; enough for any chain when
+/// spaced exponentially.
+pub const MAX_LOCATOR_HASHES: usize = 64;
+/// Most addresses a single `Addr` may carry.
+pub const MAX_ADDRS: usize = 1_000;
+/// Most blocks sent for one `GetBlocks`.
+pub const MAX_BLOCKS_PER_GETBLOCKS: usize = 64;
+
+/// A peer address as exchanged in `Addr`.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct PeerAddr {
+    /// `ip:port` the peer accepts connections on.
+    pub addr: String,
+    /// Unix time the sender last heard of the peer being reachable.
+    pub last_seen: u64,
+}
+
+/// Names a block or transaction in inventory messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub enum InvItem {
+    Block(Hash32),
+    Tx(Hash32),
+}
 
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
 pub enum Message {
-    Version { height: u64, tip: Hash32 },
-    GetBlock { hash: Hash32 },
-    GetBlocks { start_height: u64 },
-    Block { block: Block },
+    /// First message in each direction. Nothing but `Version` and `Verack`
+    /// may be sent until both sides have acknowledged each other's.
+    Version {
+        protocol_version: u32,
+        services: u64,
+        genesis: Hash32,
+        /// Random per process, to detect connections to ourselves.
+        nonce: u64,
+        user_agent: String,
+        /// Port the sender accepts connections on, 0 if it does not.
+        listen_port: u16,
+        height: u64,
+        tip: Hash32,
+    },
+    GetBlock {
+        hash: Hash32,
+    },
+    /// Requests active chain blocks from `start_height`, answered with up to
+    /// [`MAX_BLOCKS_PER_GETBLOCKS`] `Block`s in height order. Fewer means the
+    /// sender has no more; after a full batch the requester asks again.
+    GetBlocks {
+        start_height: u64,
+    },
+    Block {
+        block: Block,
+    },
     GetTip,
-    Tip { height: u64, tip: Hash32 },
+    Tip {
+        height: u64,
+        tip: Hash32,
+    },
+    /// Announces blocks and transactions the receiver may not have.
+    Inv {
+        items: Vec<InvItem>,
+    },
+    /// Requests announced items; answered with a `Block` or `Tx` each and a
+    /// `NotFound` for the rest.
+    GetData {
+        items: Vec<InvItem>,
+    },
+    Tx {
+        tx: Transaction,
+    },
+    /// Requested items the sender no longer has.
+    NotFound {
+        items: Vec<InvItem>,
+    },
+    /// Requests active chain headers following the first `locator` hash the
+    /// receiver has on its active chain. Locators list block hashes from the
+    /// sender's tip back to genesis, exponentially spaced.
+    GetHeaders {
+        locator: Vec<Hash32>,
+    },
+    /// Consecutive headers in height order; fewer than [`MAX_HEADERS`] means
+    /// the sender has no more.
+    Headers {
+        headers: Vec<BlockHeader>,
+    },
+    /// Accepts the peer's `Version`.
+    Verack,
+    /// Asks for addresses of other peers; answered with `Addr`.
+    GetAddr,
+    Addr {
+        addrs: Vec<PeerAddr>,
+    },
+    /// Keepalive; answered with a `Pong` carrying the same nonce.
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
 }
 
-pub fn encode_message(message: &Message) -> Result<Vec<u8>> {
-    let payload = borsh::to_vec(message)?;
-    if payload.len() > MAX_MESSAGE_SIZE {
-        bail!("message too large");
-    }
-    let mut buf = Vec::with_capacity(4 + payload.len());
-    buf.put_u32(payload.len() as u32);
-    buf.extend_from_slice(&payload);
-    Ok(buf)
+pub fn encode_message(magic: Magic, message: &Message) -> Result<Vec<u8>> {
+    let mut buf = BytesMut::new();
+    MessageCodec::new(magic).encode(message, &mut buf)?;
+    Ok(buf.to_vec())
 }
 
-pub fn decode_message(buf: &mut BytesMut) -> Result<Option<Message>> {
-    if buf.len() < 4 {
-        return Ok(None);
-    }
-    let mut len_bytes = [0u8; 4];
-    len_bytes.copy_from_slice(&buf[..4]);
-    let len = u32::from_be_bytes(len_bytes) as usize;
-    if len > MAX_MESSAGE_SIZE {
-        bail!("message too large");
-    }
-    if buf.len() < 4 + len {
-        return Ok(None);
-    }
-    buf.advance(4);
-    let payload = buf.split_to(len);
-    let message = Message::try_from_slice(&payload)?;
-    Ok(Some(message))
+pub fn decode_message(magic: Magic, buf: &mut BytesMut) -> Result<Option<Message>> {
+    Ok(MessageCodec::new(magic).decode(buf)?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::BufMut;
 
     #[test]
     fn encode_decode_round_trip() {
         let msg = Message::GetTip;
-        let data = encode_message(&msg).expect("encode");
+        let data = encode_message(TESTNET_MAGIC, &msg).expect("encode");
         let mut buf = BytesMut::from(&data[..]);
-        let decoded = decode_message(&mut buf).expect("decode").expect("msg");
+        let decoded = decode_message(TESTNET_MAGIC, &mut buf)
+            .expect("decode")
+            .expect("msg");
         matches!(decoded, Message::GetTip);
     }
 
+    #[test]
+    fn inventory_messages_round_trip() {
+        let items = vec![
+            InvItem::Block(Hash32([1u8; 32])),
+            InvItem::Tx(Hash32([2u8; 32])),
+        ];
+        let data = encode_message(
+            TESTNET_MAGIC,
+            &Message::GetData {
+                items: items.clone(),
+            },
+        )
+        .expect("encode");
+        let mut buf = BytesMut::from(&data[..]);
+        match decode_message(TESTNET_MAGIC, &mut buf)
+            .expect("decode")
+            .expect("msg")
+        {
+            Message::GetData { items: decoded } => assert_eq!(decoded, items),
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
     #[test]
     fn reject_large_message() {
         let mut buf = BytesMut::new();
+        buf.extend_from_slice(&TESTNET_MAGIC);
         buf.put_u32((MAX_MESSAGE_SIZE as u32) + 1);
         buf.extend_from_slice(&[0u8; 4]);
-        let err = decode_message(&mut buf).unwrap_err();
+        let err = decode_message(TESTNET_MAGIC, &mut buf).unwrap_err();
         assert!(err.to_string().contains("too large"));
     }
+
+    #[test]
+    fn reject_other_network_magic() {
+        let data = encode_message(MAINNET_MAGIC, &Message::Verack).expect("encode");
+        let mut buf = BytesMut::from(&data[..]);
+        let err = decode_message(TESTNET_MAGIC, &mut buf).unwrap_err();
+        assert!(err.to_string().contains("network magic mismatch"));
+    }
 }
@@ -0,0 +1,282 @@
+use crate::error::{corruption, decode, encode};
+use crate::{
+    abort, address_index_key, outpoint_from_key, outpoint_key, parse_tip, tip_value,
+    transaction_error, ChainStore, Result, Storage,
+};
+use kexa_proto::{OutPoint, TxOut};
+use sled::transaction::Transactional;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Meta key holding the tip the on-disk UTXO set reflects while the cache
+/// holds unflushed changes. Absent means the UTXO set matches `tip`.
+pub(crate) const UTXO_TIP_KEY: &[u8] = b"utxo_tip";
+
+/// Longest the cache holds changes before flushing, whatever its size.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rough heap cost of one cached entry: the outpoint key, the output and
+/// the hash map slot around them.
+const ENTRY_BYTES: usize = 160;
+
+/// A UTXO change made since the last flush.
+enum CacheEntry {
+    /// Created since the last flush; not on disk yet.
+    Fresh(TxOut),
+    /// On disk but spent since the last flush. The output is kept so the
+    /// flush can also drop its `addr_utxo` entry.
+    Spent(TxOut),
+}
+
+/// Write-back cache of UTXO set changes, keyed like the `utxo` tree.
+///
+/// Connecting a block only touches the cache for its UTXO changes; they
+/// reach disk in one batch when the cache outgrows its budget, once held for
+/// [`FLUSH_INTERVAL`] (checked as blocks connect and on
+/// [`Storage::flush_if_due`]), before a disconnect, and on [`Storage::flush`].
+/// Outputs created and spent between two flushes never touch disk at all.
+pub(crate) struct UtxoCache {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    budget: usize,
+    pub(crate) last_flush: Instant,
+    /// Changes were made since the last flush, even if they cancelled out.
+    dirty: bool,
+}
+
+impl UtxoCache {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            budget,
+            last_flush: Instant::now(),
+            dirty: false,
+        }
+    }
+
+    /// `Some(None)` when the cache knows the output is spent, `None` when
+    /// the answer is on disk.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Option<&TxOut>> {
+        match self.entries.get(key)? {
+            CacheEntry::Fresh(output) => Some(Some(output)),
+            CacheEntry::Spent(_) => Some(None),
+        }
+    }
+
+    pub(crate) fn create(&mut self, key: Vec<u8>, output: TxOut) {
+        self.dirty = true;
+        self.entries.insert(key, CacheEntry::Fresh(output));
+    }
+
+    pub(crate) fn spend(&mut self, key: Vec<u8>, output: TxOut) {
+        self.dirty = true;
+        if let Some(CacheEntry::Fresh(_)) = self.entries.remove(&key) {
+            return;
+        }
+        self.entries.insert(key, CacheEntry::Spent(output));
+    }
+
+    /// Drops any pending change for `key`, for writes that go straight to disk.
+    pub(crate) fn forget(&mut self, key: &[u8]) {
+        self.entries.remove(key);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.last_flush = Instant::now();
+        self.dirty = false;
+    }
+
+    fn should_flush(&self) -> bool {
+        self.dirty
+            && (self.entries.len() * ENTRY_BYTES > self.budget
+                || self.last_flush.elapsed() >= FLUSH_INTERVAL)
+    }
+
+    /// Applies pending changes to `stored`, a key-ordered listing from disk,
+    /// keeping only outputs that pay `address` when one is given.
+    fn overlay(
+        &self,
+        stored: Vec<(OutPoint, TxOut)>,
+        address: Option<&[u8; 32]>,
+    ) -> Result<Vec<(OutPoint, TxOut)>> {
+        let mut merged: BTreeMap<Vec<u8>, (OutPoint, TxOut)> = stored
+            .into_iter()
+            .map(|(outpoint, output)| (outpoint_key(&outpoint), (outpoint, output)))
+            .collect();
+        for (key, entry) in &self.entries {
+            match entry {
+                CacheEntry::Fresh(output) if address.is_none_or(|a| &output.address == a) => {
+                    let outpoint = outpoint_from_key("utxo", key, 0)?;
+                    merged.insert(key.clone(), (outpoint, output.clone()));
+                }
+                CacheEntry::Fresh(_) => {}
+                CacheEntry::Spent(_) => {
+                    merged.remove(key);
+                }
+            }
+        }
+        Ok(merged.into_values().collect())
+    }
+}
+
+impl Storage {
+    /// Writes pending UTXO changes and flushes sled, so nothing is lost if
+    /// the process exits afterwards. Called on drop as well.
+    pub fn flush(&self) -> Result<()> {
+        if let Some(mut cache) = self.utxo_cache() {
+            self.flush_utxo_cache(&mut cache)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Flushes pending UTXO changes held for [`FLUSH_INTERVAL`], for calling
+    /// periodically so they reach disk while no blocks connect.
+    pub fn flush_if_due(&self) -> Result<()> {
+        if let Some(mut cache) = self.utxo_cache() {
+            if cache.should_flush() {
+                self.flush_utxo_cache(&mut cache)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn utxo_cache(&self) -> Option<MutexGuard<'_, UtxoCache>> {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Flushes `cache` after a connect if it is due. A failed flush keeps
+    /// the changes cached and is retried after the next block.
+    pub(crate) fn maybe_flush_utxo_cache(&self, cache: &mut UtxoCache) {
+        if cache.should_flush() {
+            if let Err(err) = self.flush_utxo_cache(cache) {
+                warn!("utxo cache flush failed, will retry: {err}");
+            }
+        }
+    }
+
+    /// Writes every pending change and clears the `utxo_tip` marker in one
+    /// transaction, so the on-disk set is either fully caught up to the tip
+    /// or still at the marker.
+    pub(crate) fn flush_utxo_cache(&self, cache: &mut UtxoCache) -> Result<()> {
+        let trees = (
+            &self.tree("utxo")?,
+            &self.tree("addr_utxo")?,
+            &self.tree("meta")?,
+        );
+        trees
+            .transaction(|(utxo, addr_utxo, meta)| {
+                for (key, entry) in &cache.entries {
+                    match entry {
+                        CacheEntry::Fresh(output) => {
+                            let value = encode(output).map_err(abort)?;
+                            if let Some(previous) = utxo.insert(key.as_slice(), value.clone())? {
+                                let previous: TxOut =
+                                    decode("utxo", key, &previous).map_err(abort)?;
+                                addr_utxo.remove(address_index_key(&previous.address, key))?;
+                            }
+                            addr_utxo.insert(address_index_key(&output.address, key), value)?;
+                        }
+                        CacheEntry::Spent(output) => {
+                            utxo.remove(key.as_slice())?;
+                            addr_utxo.remove(address_index_key(&output.address, key))?;
+                        }
+                    }
+                }
+                meta.remove(UTXO_TIP_KEY)?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        cache.clear();
+        Ok(())
+    }
+
+    /// `utxo_cache` aware version of [`ChainStore::list_utxos`] and
+    /// [`ChainStore::list_utxos_by_address`].
+    pub(crate) fn overlay_utxos(
+        &self,
+        stored: Vec<(OutPoint, TxOut)>,
+        address: Option<&[u8; 32]>,
+    ) -> Result<Vec<(OutPoint, TxOut)>> {
+        match self.utxo_cache() {
+            Some(cache) => cache.overlay(stored, address),
+            None => Ok(stored),
+        }
+    }
+
+    /// Brings the on-disk UTXO set up to the tip when a previous run stopped
+    /// with unflushed cache changes, by reapplying the blocks after the
+    /// `utxo_tip` marker. The marker advances with each block, so an
+    /// interrupted recovery resumes where it left off.
+    pub(crate) fn recover_utxo_set(&self) -> Result<()> {
+        let meta = self.tree("meta")?;
+        let Some(value) = meta.get(UTXO_TIP_KEY)? else {
+            return Ok(());
+        };
+        let (mut height, hash) = parse_tip(UTXO_TIP_KEY, &value)?;
+        let (tip_height, _) = self.get_tip()?.ok_or_else(|| {
+            corruption(
+                "meta",
+                UTXO_TIP_KEY,
+                "utxo tip recorded without a chain tip",
+            )
+        })?;
+        if height > tip_height || self.get_hash_by_height(height)? != Some(hash) {
+            return Err(corruption(
+                "meta",
+                UTXO_TIP_KEY,
+                "utxo tip is not on the active chain",
+            ));
+        }
+        if height < tip_height {
+            info!(
+                "utxo set is behind the tip after an unclean shutdown, replaying {} blocks",
+                tip_height - height
+            );
+        }
+        let trees = (&self.tree("utxo")?, &self.tree("addr_utxo")?, &meta);
+        while height < tip_height {
+            height += 1;
+            let (hash, block) = self.block_at_height(height)?;
+            let changes = self.block_changes(&block, None).map_err(|err| {
+                corruption(
+                    "blocks",
+                    hash.0,
+                    format!("block at height {height} does not apply to the utxo set: {err}"),
+                )
+            })?;
+            let spent = changes.spent_entries()?;
+            let created = changes.created_entries()?;
+            trees
+                .transaction(|(utxo, addr_utxo, meta)| {
+                    for entry in &spent {
+                        entry.remove(utxo, addr_utxo)?;
+                    }
+                    for entry in &created {
+                        entry.insert(utxo, addr_utxo)?;
+                    }
+                    meta.insert(UTXO_TIP_KEY, tip_value(height, &hash))?;
+                    Ok(())
+                })
+                .map_err(transaction_error)?;
+            if height.is_multiple_of(crate::schema::PROGRESS_INTERVAL) {
+                info!("utxo set replayed to height {height}/{tip_height}");
+            }
+        }
+        meta.remove(UTXO_TIP_KEY)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("failed to flush storage on shutdown: {err}");
+        }
+    }
+}
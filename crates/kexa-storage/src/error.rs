@@ -0,0 +1,78 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use thiserror::Error;
+
+pub type Result<T, E = StorageError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// The sled backend failed to read or write.
+    #[error("storage i/o error: {0}")]
+    Io(#[from] sled::Error),
+    /// A record is missing, malformed, or contradicts another record.
+    #[error("storage corruption in `{tree}` at key {key}: {detail}")]
+    Corruption {
+        tree: &'static str,
+        key: String,
+        detail: String,
+    },
+    /// A stored value could not be deserialized.
+    #[error("failed to decode `{tree}` record at key {key}: {source}")]
+    Decode {
+        tree: &'static str,
+        key: String,
+        source: std::io::Error,
+    },
+    /// A value could not be serialized for writing.
+    #[error("failed to encode record: {0}")]
+    Encode(std::io::Error),
+    /// The data dir was written by a newer build.
+    #[error(
+        "data dir schema version {found} is newer than supported version {supported}; upgrade kexa-node"
+    )]
+    UnsupportedSchema { found: u32, supported: u32 },
+    /// The requested change does not apply to the stored chain.
+    #[error("{0}")]
+    Rejected(&'static str),
+    /// A UTXO snapshot file could not be read or written.
+    #[error("snapshot i/o error: {0}")]
+    SnapshotIo(std::io::Error),
+    /// A UTXO snapshot is malformed or does not match its commitment.
+    #[error("invalid utxo snapshot: {0}")]
+    InvalidSnapshot(String),
+}
+
+impl StorageError {
+    /// True when the data dir itself is damaged, as opposed to a failed
+    /// operation; `reindex` or a resync is the way out.
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, Self::Corruption { .. } | Self::Decode { .. })
+    }
+}
+
+pub(crate) fn corruption(
+    tree: &'static str,
+    key: impl AsRef<[u8]>,
+    detail: impl Into<String>,
+) -> StorageError {
+    StorageError::Corruption {
+        tree,
+        key: hex::encode(key),
+        detail: detail.into(),
+    }
+}
+
+pub(crate) fn decode<T: BorshDeserialize>(
+    tree: &'static str,
+    key: impl AsRef<[u8]>,
+    value: &[u8],
+) -> Result<T> {
+    T::try_from_slice(value).map_err(|source| StorageError::Decode {
+        tree,
+        key: hex::encode(key),
+        source,
+    })
+}
+
+pub(crate) fn encode<T: BorshSerialize>(value: &T) -> Result<Vec<u8>> {
+    borsh::to_vec(value).map_err(StorageError::Encode)
+}
@@ -1,119 +1,1085 @@
-use anyhow::Result;
-use borsh::BorshDeserialize;
-use kexa_proto::{Block, BlockHeader, Hash32, OutPoint, TxOut};
+use borsh::{BorshDeserialize, BorshSerialize};
+use error::{corruption, decode, encode};
+use kexa_proto::{Block, BlockHeader, Hash32, OutPoint, Transaction, TxOut};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree,
+};
 use sled::Db;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+mod cache;
+mod error;
+mod memory;
+mod schema;
+mod snapshot;
+
+pub use error::{Result, StorageError};
+pub use memory::MemoryStore;
+pub use schema::SCHEMA_VERSION;
+pub use snapshot::SnapshotHeader;
+
+use cache::{UtxoCache, UTXO_TIP_KEY};
+
+/// Optional indexes and caching maintained alongside the chain.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StorageOptions {
+    /// Maintain the `tx_index` tree (txid -> block location).
+    pub tx_index: bool,
+    /// Maintain the `addr_history` tree (address -> transactions touching it).
+    pub address_history: bool,
+    /// Memory budget in bytes for buffering UTXO set changes between disk
+    /// flushes; `0` writes them with every block.
+    pub utxo_cache_bytes: usize,
+}
+
+/// Where a confirmed transaction sits in the active chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct TxLocation {
+    pub block_hash: Hash32,
+    pub height: u64,
+    pub position: u32,
+}
+
+/// One transaction that credited or debited an address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressHistoryEntry {
+    pub txid: Hash32,
+    pub height: u64,
+    pub position: u32,
+    pub timestamp: u64,
+    /// Sum of the transaction's outputs paying the address.
+    pub received: u64,
+    /// Sum of the address's outputs the transaction spent.
+    pub sent: u64,
+}
+
+/// Chain data a node needs to validate and extend the active chain: blocks,
+/// headers and the height index, the tip, and the UTXO set.
+///
+/// [`Storage`] keeps it in sled; [`MemoryStore`] keeps it in process memory
+/// for tests and simulations.
+pub trait ChainStore: Send + Sync {
+    fn put_block(&self, hash: &Hash32, block: &Block) -> Result<()>;
+    fn get_block(&self, hash: &Hash32) -> Result<Option<Block>>;
+    fn put_header(&self, height: u64, header: &BlockHeader) -> Result<()>;
+    fn get_header(&self, height: u64) -> Result<Option<BlockHeader>>;
+    fn put_height_hash(&self, height: u64, hash: &Hash32) -> Result<()>;
+    fn get_hash_by_height(&self, height: u64) -> Result<Option<Hash32>>;
+    /// Height of `hash` if it is on the active chain, without reading the
+    /// block.
+    fn get_height_by_hash(&self, hash: &Hash32) -> Result<Option<u64>>;
+    fn set_tip(&self, height: u64, hash: &Hash32) -> Result<()>;
+    fn get_tip(&self) -> Result<Option<(u64, Hash32)>>;
+    fn put_utxo(&self, outpoint: &OutPoint, output: &TxOut) -> Result<()>;
+    fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<TxOut>>;
+    fn delete_utxo(&self, outpoint: &OutPoint) -> Result<()>;
+    fn list_utxos_by_address(&self, address: &[u8; 32]) -> Result<Vec<(OutPoint, TxOut)>>;
+
+    /// Every unspent output, ordered by txid then output index.
+    fn list_utxos(&self) -> Result<Vec<(OutPoint, TxOut)>>;
+
+    /// Connects `block` on top of the current tip. Either every write lands
+    /// or none does; a block that does not extend the tip, or spends a
+    /// missing output, is rejected.
+    fn connect_block(&self, block: &Block) -> Result<Hash32>;
+
+    /// Disconnects the current tip block, restoring the outputs it spent.
+    /// Refuses to disconnect genesis.
+    fn disconnect_block(&self) -> Result<Block>;
+
+    /// Writes the genesis block as the chain's first block. Its coinbase
+    /// outputs are not added to the UTXO set.
+    fn put_genesis(&self, block: &Block) -> Result<Hash32>;
+}
 
 pub struct Storage {
     db: Db,
+    options: StorageOptions,
+    cache: Option<Mutex<UtxoCache>>,
 }
 
 impl Storage {
     pub fn open(path: &str) -> Result<Self> {
-        let db = sled::open(path)?;
-        Ok(Self { db })
+        Self::open_with(path, StorageOptions::default())
     }
 
-    fn tree(&self, name: &str) -> sled::Tree {
-        self.db.open_tree(name).expect("tree")
+    pub fn open_with(path: &str, options: StorageOptions) -> Result<Self> {
+        Self::from_db(sled::open(path)?, options)
     }
 
-    pub fn put_block(&self, hash: &Hash32, block: &Block) -> Result<()> {
-        self.tree("blocks").insert(hash.0, borsh::to_vec(block)?)?;
+    fn from_db(db: Db, options: StorageOptions) -> Result<Self> {
+        let cache = (options.utxo_cache_bytes > 0)
+            .then(|| Mutex::new(UtxoCache::new(options.utxo_cache_bytes)));
+        let storage = Self { db, options, cache };
+        storage.migrate()?;
+        storage.recover_utxo_set()?;
+        storage.discard_partial_snapshot()?;
+        if (options.tx_index || options.address_history) && storage.snapshot_base()?.is_some() {
+            return Err(StorageError::Rejected(snapshot::INDEXES_NEED_HISTORY));
+        }
+        storage.ensure_tx_index()?;
+        storage.ensure_address_history()?;
+        Ok(storage)
+    }
+
+    pub fn options(&self) -> StorageOptions {
+        self.options
+    }
+
+    fn tree(&self, name: &'static str) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+}
+
+impl ChainStore for Storage {
+    fn put_block(&self, hash: &Hash32, block: &Block) -> Result<()> {
+        self.tree("blocks")?.insert(hash.0, encode(block)?)?;
         Ok(())
     }
 
-    pub fn get_block(&self, hash: &Hash32) -> Result<Option<Block>> {
-        if let Some(value) = self.tree("blocks").get(hash.0)? {
-            let block = Block::try_from_slice(&value)?;
-            Ok(Some(block))
+    fn get_block(&self, hash: &Hash32) -> Result<Option<Block>> {
+        if let Some(value) = self.tree("blocks")?.get(hash.0)? {
+            Ok(Some(decode("blocks", hash.0, &value)?))
         } else {
             Ok(None)
         }
     }
 
-    pub fn put_header(&self, height: u64, header: &BlockHeader) -> Result<()> {
-        self.tree("headers")
-            .insert(height.to_be_bytes(), borsh::to_vec(header)?)?;
+    fn put_header(&self, height: u64, header: &BlockHeader) -> Result<()> {
+        self.tree("headers")?
+            .insert(height.to_be_bytes(), encode(header)?)?;
         Ok(())
     }
 
-    pub fn put_height_hash(&self, height: u64, hash: &Hash32) -> Result<()> {
-        self.tree("height_hash")
-            .insert(height.to_be_bytes(), hash.0.to_vec())?;
-        Ok(())
+    fn put_height_hash(&self, height: u64, hash: &Hash32) -> Result<()> {
+        (&self.tree("height_hash")?, &self.tree("hash_height")?)
+            .transaction(|(height_hash, hash_height)| {
+                if let Some(replaced) =
+                    height_hash.insert(&height.to_be_bytes(), hash.0.to_vec())?
+                {
+                    hash_height.remove(replaced)?;
+                }
+                hash_height.insert(&hash.0, height.to_be_bytes().to_vec())?;
+                Ok(())
+            })
+            .map_err(transaction_error)
     }
 
-    pub fn get_hash_by_height(&self, height: u64) -> Result<Option<Hash32>> {
-        if let Some(value) = self.tree("height_hash").get(height.to_be_bytes())? {
-            let mut hash = [0u8; 32];
-            hash.copy_from_slice(&value);
+    fn get_hash_by_height(&self, height: u64) -> Result<Option<Hash32>> {
+        if let Some(value) = self.tree("height_hash")?.get(height.to_be_bytes())? {
+            let hash = value
+                .as_ref()
+                .try_into()
+                .map_err(|_| corruption("height_hash", height.to_be_bytes(), "malformed hash"))?;
             Ok(Some(Hash32(hash)))
         } else {
             Ok(None)
         }
     }
 
-    pub fn get_header(&self, height: u64) -> Result<Option<BlockHeader>> {
-        if let Some(value) = self.tree("headers").get(height.to_be_bytes())? {
-            let header = BlockHeader::try_from_slice(&value)?;
-            Ok(Some(header))
+    fn get_height_by_hash(&self, hash: &Hash32) -> Result<Option<u64>> {
+        if let Some(value) = self.tree("hash_height")?.get(hash.0)? {
+            let height = value
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| corruption("hash_height", hash.0, "malformed height"))?;
+            Ok(Some(height))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_header(&self, height: u64) -> Result<Option<BlockHeader>> {
+        if let Some(value) = self.tree("headers")?.get(height.to_be_bytes())? {
+            Ok(Some(decode("headers", height.to_be_bytes(), &value)?))
         } else {
             Ok(None)
         }
     }
 
-    pub fn set_tip(&self, height: u64, hash: &Hash32) -> Result<()> {
-        let mut data = Vec::new();
-        data.extend_from_slice(&height.to_be_bytes());
-        data.extend_from_slice(&hash.0);
-        self.tree("meta").insert(b"tip", data)?;
+    fn set_tip(&self, height: u64, hash: &Hash32) -> Result<()> {
+        self.tree("meta")?.insert(b"tip", tip_value(height, hash))?;
         Ok(())
     }
 
-    pub fn get_tip(&self) -> Result<Option<(u64, Hash32)>> {
-        if let Some(value) = self.tree("meta").get(b"tip")? {
-            let height = u64::from_be_bytes(value[0..8].try_into().expect("height"));
-            let mut hash = [0u8; 32];
-            hash.copy_from_slice(&value[8..40]);
-            Ok(Some((height, Hash32(hash))))
+    fn get_tip(&self) -> Result<Option<(u64, Hash32)>> {
+        match self.tree("meta")?.get(b"tip")? {
+            Some(value) => parse_tip(b"tip", &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_utxo(&self, outpoint: &OutPoint, output: &TxOut) -> Result<()> {
+        let entry = UtxoEntry::new(outpoint, output)?;
+        if let Some(mut cache) = self.utxo_cache() {
+            cache.forget(&entry.key);
+        }
+        (&self.tree("utxo")?, &self.tree("addr_utxo")?)
+            .transaction(|(utxo, addr_utxo)| {
+                if let Some(previous) = utxo.insert(entry.key.as_slice(), entry.value.clone())? {
+                    let previous: TxOut = decode("utxo", &entry.key, &previous).map_err(abort)?;
+                    addr_utxo.remove(address_index_key(&previous.address, &entry.key))?;
+                }
+                addr_utxo.insert(entry.index_key.as_slice(), entry.value.clone())?;
+                Ok(())
+            })
+            .map_err(transaction_error)
+    }
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<TxOut>> {
+        let key = outpoint_key(outpoint);
+        match self.utxo_cache() {
+            Some(cache) => self.cached_utxo(&cache, &key),
+            None => self.stored_utxo(&key),
+        }
+    }
+
+    fn delete_utxo(&self, outpoint: &OutPoint) -> Result<()> {
+        let key = outpoint_key(outpoint);
+        if let Some(mut cache) = self.utxo_cache() {
+            cache.forget(&key);
+        }
+        (&self.tree("utxo")?, &self.tree("addr_utxo")?)
+            .transaction(|(utxo, addr_utxo)| {
+                if let Some(previous) = utxo.remove(key.as_slice())? {
+                    let previous: TxOut = decode("utxo", &key, &previous).map_err(abort)?;
+                    addr_utxo.remove(address_index_key(&previous.address, &key))?;
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)
+    }
+
+    /// Lists the unspent outputs paying `address` using the `addr_utxo` index,
+    /// so the cost is proportional to that address's own UTXO count.
+    fn list_utxos_by_address(&self, address: &[u8; 32]) -> Result<Vec<(OutPoint, TxOut)>> {
+        let mut results = Vec::new();
+        for item in self.tree("addr_utxo")?.scan_prefix(address) {
+            let (key, value) = item?;
+            let outpoint = outpoint_from_key("addr_utxo", &key, 32)?;
+            let output = decode("addr_utxo", &key, &value)?;
+            results.push((outpoint, output));
+        }
+        self.overlay_utxos(results, Some(address))
+    }
+
+    fn list_utxos(&self) -> Result<Vec<(OutPoint, TxOut)>> {
+        let mut results = Vec::new();
+        for item in self.tree("utxo")?.iter() {
+            let (key, value) = item?;
+            let outpoint = outpoint_from_key("utxo", &key, 0)?;
+            results.push((outpoint, decode("utxo", &key, &value)?));
+        }
+        self.overlay_utxos(results, None)
+    }
+
+    /// Connects `block` on top of the current tip in a single sled transaction:
+    /// spent outputs are removed, new outputs inserted, and the block, header,
+    /// height index, undo data and tip are written together or not at all.
+    ///
+    /// With a UTXO cache the UTXO changes go to the cache instead, and the
+    /// transaction records the tip the on-disk set still reflects so a crash
+    /// before the next flush can be recovered from the stored blocks.
+    fn connect_block(&self, block: &Block) -> Result<Hash32> {
+        let hash = block.header.hash();
+        let height = block.header.height;
+        check_extends_tip(self.get_tip()?, &block.header)?;
+        let mut cache = self.utxo_cache();
+        let changes = self.block_changes(block, cache.as_deref())?;
+        let (spent, created) = if cache.is_some() {
+            (Vec::new(), Vec::new())
         } else {
-            Ok(None)
+            (changes.spent_entries()?, changes.created_entries()?)
+        };
+        let undo_bytes = encode(&BlockUndo {
+            spent: changes.spent.clone(),
+        })?;
+        let history = if self.options.address_history {
+            history_entries(block, &changes.spent_by_tx)?
+        } else {
+            Vec::new()
+        };
+        let block_bytes = encode(block)?;
+        let header_bytes = encode(&block.header)?;
+        let locations = self.tx_locations(block, hash)?;
+        let trees = (
+            &self.tree("blocks")?,
+            &self.tree("headers")?,
+            &self.tree("height_hash")?,
+            &self.tree("hash_height")?,
+            &self.tree("meta")?,
+            &self.tree("utxo")?,
+            &self.tree("addr_utxo")?,
+            &self.tree("undo")?,
+            &self.tree("tx_index")?,
+            &self.tree("addr_history")?,
+        );
+        trees
+            .transaction(
+                |(
+                    blocks,
+                    headers,
+                    height_hash,
+                    hash_height,
+                    meta,
+                    utxo,
+                    addr_utxo,
+                    undo,
+                    tx_index,
+                    addr_history,
+                )| {
+                    for entry in &spent {
+                        entry.remove(utxo, addr_utxo)?;
+                    }
+                    for entry in &created {
+                        entry.insert(utxo, addr_utxo)?;
+                    }
+                    if cache.is_some() && meta.get(UTXO_TIP_KEY)?.is_none() {
+                        meta.insert(
+                            UTXO_TIP_KEY,
+                            tip_value(height.saturating_sub(1), &block.header.prev_hash),
+                        )?;
+                    }
+                    for (key, value) in &history {
+                        addr_history.insert(key.as_slice(), value.clone())?;
+                    }
+                    undo.insert(&hash.0, undo_bytes.clone())?;
+                    for (txid, location) in &locations {
+                        tx_index.insert(&txid.0, location.clone())?;
+                    }
+                    blocks.insert(&hash.0, block_bytes.clone())?;
+                    headers.insert(&height.to_be_bytes(), header_bytes.clone())?;
+                    height_hash.insert(&height.to_be_bytes(), hash.0.to_vec())?;
+                    hash_height.insert(&hash.0, height.to_be_bytes().to_vec())?;
+                    meta.insert(b"tip", tip_value(height, &hash))?;
+                    Ok(())
+                },
+            )
+            .map_err(transaction_error)?;
+        if let Some(cache) = cache.as_deref_mut() {
+            for (outpoint, output) in changes.spent {
+                cache.spend(outpoint_key(&outpoint), output);
+            }
+            for (outpoint, output) in changes.created {
+                cache.create(outpoint_key(&outpoint), output);
+            }
+            self.maybe_flush_utxo_cache(cache);
         }
+        Ok(hash)
     }
 
-    pub fn put_utxo(&self, outpoint: &OutPoint, output: &TxOut) -> Result<()> {
-        self.tree("utxo")
-            .insert(outpoint_key(outpoint), borsh::to_vec(output)?)?;
-        Ok(())
+    /// Disconnects the current tip block atomically, restoring the outputs it
+    /// spent from its undo data and moving the tip back to its parent. The
+    /// block itself stays in the `blocks` tree.
+    fn disconnect_block(&self) -> Result<Block> {
+        // Undo data is applied to the on-disk set, so it must be current.
+        if let Some(mut cache) = self.utxo_cache() {
+            self.flush_utxo_cache(&mut cache)?;
+        }
+        let (height, hash) = self
+            .get_tip()?
+            .ok_or(StorageError::Rejected("tip missing"))?;
+        if height == 0 {
+            return Err(StorageError::Rejected("cannot disconnect genesis block"));
+        }
+        if self
+            .snapshot_base()?
+            .is_some_and(|snapshot| snapshot.base.height == height)
+        {
+            return Err(StorageError::Rejected(
+                "cannot disconnect the utxo snapshot base block",
+            ));
+        }
+        let block = self
+            .get_block(&hash)?
+            .ok_or_else(|| corruption("blocks", hash.0, "tip block missing"))?;
+        let undo_bytes = self
+            .tree("undo")?
+            .get(hash.0)?
+            .ok_or_else(|| corruption("undo", hash.0, "undo data missing for tip block"))?;
+        let undo: BlockUndo = decode("undo", hash.0, &undo_bytes)?;
+        let created = created_outputs(&block)?;
+        let restored = undo
+            .spent
+            .iter()
+            .map(|(outpoint, output)| UtxoEntry::new(outpoint, output))
+            .collect::<Result<Vec<_>>>()?;
+        let txids: Vec<Hash32> = block.txs.iter().map(|tx| tx.txid()).collect();
+        let history_keys = if self.options.address_history {
+            let spent_by_tx = block_spent_outputs(&block, &undo)?;
+            history_entries(&block, &spent_by_tx)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let trees = (
+            &self.tree("headers")?,
+            &self.tree("height_hash")?,
+            &self.tree("hash_height")?,
+            &self.tree("meta")?,
+            &self.tree("utxo")?,
+            &self.tree("addr_utxo")?,
+            &self.tree("undo")?,
+            &self.tree("tx_index")?,
+            &self.tree("addr_history")?,
+        );
+        trees
+            .transaction(
+                |(
+                    headers,
+                    height_hash,
+                    hash_height,
+                    meta,
+                    utxo,
+                    addr_utxo,
+                    undo,
+                    tx_index,
+                    addr_history,
+                )| {
+                    for txid in &txids {
+                        tx_index.remove(&txid.0)?;
+                    }
+                    for key in &history_keys {
+                        addr_history.remove(key.as_slice())?;
+                    }
+                    for entry in created.iter().flatten() {
+                        utxo.remove(entry.key.as_slice())?;
+                        addr_utxo.remove(entry.index_key.as_slice())?;
+                    }
+                    for entry in &restored {
+                        entry.insert(utxo, addr_utxo)?;
+                    }
+                    undo.remove(&hash.0)?;
+                    headers.remove(&height.to_be_bytes())?;
+                    height_hash.remove(&height.to_be_bytes())?;
+                    hash_height.remove(&hash.0)?;
+                    meta.insert(b"tip", tip_value(height - 1, &block.header.prev_hash))?;
+                    Ok(())
+                },
+            )
+            .map_err(transaction_error)?;
+        Ok(block)
+    }
+
+    fn put_genesis(&self, block: &Block) -> Result<Hash32> {
+        let hash = block.header.hash();
+        let block_bytes = encode(block)?;
+        let header_bytes = encode(&block.header)?;
+        let locations = self.tx_locations(block, hash)?;
+        let trees = (
+            &self.tree("blocks")?,
+            &self.tree("headers")?,
+            &self.tree("height_hash")?,
+            &self.tree("hash_height")?,
+            &self.tree("meta")?,
+            &self.tree("tx_index")?,
+        );
+        trees
+            .transaction(
+                |(blocks, headers, height_hash, hash_height, meta, tx_index)| {
+                    for (txid, location) in &locations {
+                        tx_index.insert(&txid.0, location.clone())?;
+                    }
+                    blocks.insert(&hash.0, block_bytes.clone())?;
+                    headers.insert(&0u64.to_be_bytes(), header_bytes.clone())?;
+                    height_hash.insert(&0u64.to_be_bytes(), hash.0.to_vec())?;
+                    hash_height.insert(&hash.0, 0u64.to_be_bytes().to_vec())?;
+                    meta.insert(b"tip", tip_value(0, &hash))?;
+                    Ok(())
+                },
+            )
+            .map_err(transaction_error)?;
+        Ok(hash)
+    }
+}
+
+/// Pre-existing outputs spent by a block, in input order, kept so the block
+/// can be disconnected again.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct BlockUndo {
+    spent: Vec<(OutPoint, TxOut)>,
+}
+
+/// Read-only handle on the active chain's blocks. It shares the database
+/// with the [`Storage`] it came from but not its borrow, so blocks can be
+/// served while the `Storage` is locked for other work.
+#[derive(Clone)]
+pub struct BlockReader {
+    blocks: sled::Tree,
+    height_hash: sled::Tree,
+    /// Height of the UTXO snapshot base, indexed before its block arrives
+    /// with the validated history.
+    snapshot_height: Option<u64>,
+}
+
+impl BlockReader {
+    /// Block at `height` on the active chain; `None` above the tip and up to
+    /// a UTXO snapshot base whose history is not validated yet.
+    pub fn block_at(&self, height: u64) -> Result<Option<Block>> {
+        let key = height.to_be_bytes();
+        let Some(hash) = self.height_hash.get(key)? else {
+            return Ok(None);
+        };
+        let hash: [u8; 32] = hash
+            .as_ref()
+            .try_into()
+            .map_err(|_| corruption("height_hash", key, "malformed hash"))?;
+        match self.blocks.get(hash)? {
+            Some(value) => Ok(Some(decode("blocks", hash, &value)?)),
+            None if self.snapshot_height.is_some_and(|base| height <= base) => Ok(None),
+            None => Err(corruption("height_hash", key, "points at missing block")),
+        }
     }
+}
 
-    pub fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<TxOut>> {
-        if let Some(value) = self.tree("utxo").get(outpoint_key(outpoint))? {
-            Ok(Some(TxOut::try_from_slice(&value)?))
+impl Storage {
+    pub fn block_reader(&self) -> Result<BlockReader> {
+        Ok(BlockReader {
+            blocks: self.tree("blocks")?,
+            height_hash: self.tree("height_hash")?,
+            snapshot_height: self.snapshot_base()?.map(|snapshot| snapshot.base.height),
+        })
+    }
+
+    pub fn get_tx_location(&self, txid: &Hash32) -> Result<Option<TxLocation>> {
+        if let Some(value) = self.tree("tx_index")?.get(txid.0)? {
+            Ok(Some(decode("tx_index", txid.0, &value)?))
         } else {
             Ok(None)
         }
     }
 
-    pub fn delete_utxo(&self, outpoint: &OutPoint) -> Result<()> {
-        self.tree("utxo").remove(outpoint_key(outpoint))?;
+    /// Looks up a confirmed transaction through the tx index. Always `None`
+    /// when the index is disabled.
+    pub fn get_transaction(&self, txid: &Hash32) -> Result<Option<(Transaction, TxLocation)>> {
+        let Some(location) = self.get_tx_location(txid)? else {
+            return Ok(None);
+        };
+        let block = self
+            .get_block(&location.block_hash)?
+            .ok_or_else(|| corruption("tx_index", txid.0, "points at missing block"))?;
+        let tx = block
+            .txs
+            .into_iter()
+            .nth(location.position as usize)
+            .ok_or_else(|| corruption("tx_index", txid.0, "position out of range"))?;
+        Ok(Some((tx, location)))
+    }
+
+    /// Returns up to `limit` history entries for `address`, newest first,
+    /// after skipping the `offset` newest. Empty when the index is disabled.
+    pub fn address_history(
+        &self,
+        address: &[u8; 32],
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AddressHistoryEntry>> {
+        let mut entries = Vec::new();
+        for item in self
+            .tree("addr_history")?
+            .scan_prefix(address)
+            .rev()
+            .skip(offset)
+            .take(limit)
+        {
+            let (key, value) = item?;
+            let value: HistoryValue = decode("addr_history", &key, &value)?;
+            let (height, position) = match key.get(32..) {
+                Some(&[h0, h1, h2, h3, h4, h5, h6, h7, p0, p1, p2, p3]) => (
+                    u64::from_be_bytes([h0, h1, h2, h3, h4, h5, h6, h7]),
+                    u32::from_be_bytes([p0, p1, p2, p3]),
+                ),
+                _ => return Err(corruption("addr_history", &key, "malformed history key")),
+            };
+            entries.push(AddressHistoryEntry {
+                txid: value.txid,
+                height,
+                position,
+                timestamp: value.timestamp,
+                received: value.received,
+                sent: value.sent,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Builds the address history from stored blocks when it is enabled for
+    /// the first time, and drops it when disabled.
+    fn ensure_address_history(&self) -> Result<()> {
+        let meta = self.tree("meta")?;
+        let addr_history = self.tree("addr_history")?;
+        if !self.options.address_history {
+            if meta.remove(b"addr_history")?.is_some() {
+                addr_history.clear()?;
+            }
+            return Ok(());
+        }
+        if meta.contains_key(b"addr_history")? {
+            return Ok(());
+        }
+        addr_history.clear()?;
+        self.replay_chain(|block, spent_by_tx| {
+            // Genesis outputs are not part of the UTXO set, so not of history.
+            if block.header.height == 0 {
+                return Ok(());
+            }
+            for (key, value) in history_entries(block, spent_by_tx)? {
+                addr_history.insert(key, value)?;
+            }
+            Ok(())
+        })?;
+        meta.insert(b"addr_history", &[1u8])?;
         Ok(())
     }
 
-    pub fn list_utxos_by_address(&self, address: &[u8; 32]) -> Result<Vec<(OutPoint, TxOut)>> {
-        let mut results = Vec::new();
-        for item in self.tree("utxo").iter() {
+    /// Rebuilds the UTXO set, undo data and enabled indexes by reconnecting
+    /// every block of the active chain from genesis. Returns the tip height.
+    pub fn reindex(&self) -> Result<u64> {
+        if self.snapshot_base()?.is_some() {
+            return Err(StorageError::Rejected(
+                "blocks below the utxo snapshot base are not validated yet",
+            ));
+        }
+        // The height index is only trimmed by disconnects, so it still lists
+        // the whole chain if an earlier reindex was interrupted.
+        let mut hashes = Vec::new();
+        for item in self.tree("height_hash")?.iter() {
             let (key, value) = item?;
-            let outpoint = outpoint_from_key(&key);
-            let output = TxOut::try_from_slice(&value)?;
-            if &output.address == address {
-                results.push((outpoint, output));
+            let height = key
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| corruption("height_hash", &key, "malformed height key"))?;
+            if height != hashes.len() as u64 {
+                return Err(corruption("height_hash", &key, "gap in height index"));
+            }
+            let hash = value
+                .as_ref()
+                .try_into()
+                .map_err(|_| corruption("height_hash", &key, "malformed hash"))?;
+            hashes.push(Hash32(hash));
+        }
+        let Some(genesis_hash) = hashes.first() else {
+            return Err(StorageError::Rejected("no chain to reindex"));
+        };
+        for (height, hash) in hashes.iter().enumerate() {
+            if !self.tree("blocks")?.contains_key(hash.0)? {
+                return Err(missing_block(height as u64, hash));
+            }
+        }
+        if let Some(mut cache) = self.utxo_cache() {
+            cache.clear();
+        }
+        self.tree("meta")?.remove(UTXO_TIP_KEY)?;
+        for name in ["utxo", "addr_utxo", "undo", "tx_index", "addr_history"] {
+            self.tree(name)?.clear()?;
+        }
+        let genesis = self
+            .get_block(genesis_hash)?
+            .ok_or_else(|| missing_block(0, genesis_hash))?;
+        self.put_genesis(&genesis)?;
+        let tip_height = hashes.len() as u64 - 1;
+        for (height, hash) in hashes.iter().enumerate().skip(1) {
+            let block = self
+                .get_block(hash)?
+                .ok_or_else(|| missing_block(height as u64, hash))?;
+            self.connect_block(&block).map_err(|err| {
+                corruption(
+                    "blocks",
+                    hash.0,
+                    format!("block at height {height} does not connect: {err}"),
+                )
+            })?;
+            if (height as u64).is_multiple_of(schema::PROGRESS_INTERVAL) {
+                tracing::info!("reindexed to height {height}/{tip_height}");
+            }
+        }
+        self.flush()?;
+        Ok(tip_height)
+    }
+
+    /// Walks the active chain from genesis to the tip, handing each block and
+    /// the outputs spent by each of its transactions to `f`. Spent outputs are
+    /// resolved from a scratch tree of every output created so far, so this
+    /// works without undo data.
+    fn replay_chain(&self, mut f: impl FnMut(&Block, &[Vec<TxOut>]) -> Result<()>) -> Result<()> {
+        let Some((tip_height, _)) = self.get_tip()? else {
+            return Ok(());
+        };
+        let scratch = self.tree("replay_outputs")?;
+        scratch.clear()?;
+        for height in 0..=tip_height {
+            let (hash, block) = self.block_at_height(height)?;
+            let mut spent_by_tx = Vec::with_capacity(block.txs.len());
+            for (tx, outputs) in block.txs.iter().zip(created_outputs(&block)?) {
+                let mut spent = Vec::with_capacity(tx.inputs.len());
+                for input in &tx.inputs {
+                    let key = outpoint_key(&input.outpoint);
+                    let value = scratch.remove(&key)?.ok_or_else(|| {
+                        corruption(
+                            "blocks",
+                            hash.0,
+                            format!("block at height {height} spends unknown output"),
+                        )
+                    })?;
+                    spent.push(decode("replay_outputs", &key, &value)?);
+                }
+                spent_by_tx.push(spent);
+                for entry in outputs {
+                    scratch.insert(entry.key, entry.value)?;
+                }
+            }
+            f(&block, &spent_by_tx)?;
+        }
+        self.db.drop_tree("replay_outputs")?;
+        Ok(())
+    }
+
+    /// The active-chain block at `height`, which must exist.
+    fn block_at_height(&self, height: u64) -> Result<(Hash32, Block)> {
+        let hash = self.get_hash_by_height(height)?.ok_or_else(|| {
+            corruption(
+                "height_hash",
+                height.to_be_bytes(),
+                "missing entry below the tip",
+            )
+        })?;
+        let block = self
+            .get_block(&hash)?
+            .ok_or_else(|| missing_block(height, &hash))?;
+        Ok((hash, block))
+    }
+
+    /// Resolves the outputs `block` spends against `cache`, then disk, without
+    /// writing anything. Spending a missing output is an error.
+    fn block_changes(&self, block: &Block, cache: Option<&UtxoCache>) -> Result<BlockChanges> {
+        let mut created: BTreeMap<Vec<u8>, (OutPoint, TxOut)> = BTreeMap::new();
+        let mut spent_keys = HashSet::new();
+        let mut spent = Vec::new();
+        let mut spent_by_tx = Vec::with_capacity(block.txs.len());
+        for tx in &block.txs {
+            let mut tx_spent = Vec::with_capacity(tx.inputs.len());
+            for input in &tx.inputs {
+                let key = outpoint_key(&input.outpoint);
+                // Outputs created and spent within this block need no undo.
+                if let Some((_, output)) = created.remove(&key) {
+                    tx_spent.push(output);
+                    continue;
+                }
+                let missing = StorageError::Rejected("missing utxo when applying block");
+                if !spent_keys.insert(key.clone()) {
+                    return Err(missing);
+                }
+                let output = match cache {
+                    Some(cache) => self.cached_utxo(cache, &key)?,
+                    None => self.stored_utxo(&key)?,
+                }
+                .ok_or(missing)?;
+                tx_spent.push(output.clone());
+                spent.push((input.outpoint.clone(), output));
             }
+            spent_by_tx.push(tx_spent);
+            // Outputs of this transaction become visible to later ones.
+            let txid = tx.txid();
+            for (index, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid,
+                    index: index as u32,
+                };
+                created.insert(outpoint_key(&outpoint), (outpoint, output.clone()));
+            }
+        }
+        Ok(BlockChanges {
+            spent,
+            created: created.into_values().collect(),
+            spent_by_tx,
+        })
+    }
+
+    fn cached_utxo(&self, cache: &UtxoCache, key: &[u8]) -> Result<Option<TxOut>> {
+        match cache.get(key) {
+            Some(output) => Ok(output.cloned()),
+            None => self.stored_utxo(key),
+        }
+    }
+
+    fn stored_utxo(&self, key: &[u8]) -> Result<Option<TxOut>> {
+        match self.tree("utxo")?.get(key)? {
+            Some(value) => Ok(Some(decode("utxo", key, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Serialized tx index entries for `block`, or none when the index is off.
+    fn tx_locations(&self, block: &Block, hash: Hash32) -> Result<Vec<(Hash32, Vec<u8>)>> {
+        if !self.options.tx_index {
+            return Ok(Vec::new());
+        }
+        block
+            .txs
+            .iter()
+            .enumerate()
+            .map(|(position, tx)| {
+                let location = TxLocation {
+                    block_hash: hash,
+                    height: block.header.height,
+                    position: position as u32,
+                };
+                Ok((tx.txid(), encode(&location)?))
+            })
+            .collect()
+    }
+
+    /// Builds the tx index from stored blocks when it is enabled for the
+    /// first time, and drops it when disabled so a later re-enable rebuilds
+    /// it instead of serving a stale index.
+    fn ensure_tx_index(&self) -> Result<()> {
+        let meta = self.tree("meta")?;
+        let tx_index = self.tree("tx_index")?;
+        if !self.options.tx_index {
+            if meta.remove(b"tx_index")?.is_some() {
+                tx_index.clear()?;
+            }
+            return Ok(());
+        }
+        if meta.contains_key(b"tx_index")? {
+            return Ok(());
+        }
+        tx_index.clear()?;
+        if let Some((tip_height, _)) = self.get_tip()? {
+            for height in 0..=tip_height {
+                let (hash, block) = self.block_at_height(height)?;
+                for (txid, location) in self.tx_locations(&block, hash)? {
+                    tx_index.insert(txid.0, location)?;
+                }
+            }
+        }
+        meta.insert(b"tx_index", &[1u8])?;
+        Ok(())
+    }
+}
+
+/// A serialized UTXO together with its `utxo` and `addr_utxo` keys.
+struct UtxoEntry {
+    key: Vec<u8>,
+    index_key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl UtxoEntry {
+    fn new(outpoint: &OutPoint, output: &TxOut) -> Result<Self> {
+        let key = outpoint_key(outpoint);
+        Ok(Self {
+            index_key: address_index_key(&output.address, &key),
+            key,
+            value: encode(output)?,
+        })
+    }
+
+    fn insert(
+        &self,
+        utxo: &TransactionalTree,
+        addr_utxo: &TransactionalTree,
+    ) -> ConflictableTransactionResult<(), StorageError> {
+        utxo.insert(self.key.as_slice(), self.value.clone())?;
+        addr_utxo.insert(self.index_key.as_slice(), self.value.clone())?;
+        Ok(())
+    }
+
+    fn remove(
+        &self,
+        utxo: &TransactionalTree,
+        addr_utxo: &TransactionalTree,
+    ) -> ConflictableTransactionResult<(), StorageError> {
+        utxo.remove(self.key.as_slice())?;
+        addr_utxo.remove(self.index_key.as_slice())?;
+        Ok(())
+    }
+}
+
+/// UTXO set changes of one block, resolved before anything is written.
+struct BlockChanges {
+    /// Pre-existing outputs the block spends, in input order; its undo data.
+    spent: Vec<(OutPoint, TxOut)>,
+    /// Outputs the block creates that are still unspent after it.
+    created: Vec<(OutPoint, TxOut)>,
+    /// Outputs spent by each transaction, including ones created in-block.
+    spent_by_tx: Vec<Vec<TxOut>>,
+}
+
+impl BlockChanges {
+    fn spent_entries(&self) -> Result<Vec<UtxoEntry>> {
+        self.spent
+            .iter()
+            .map(|(outpoint, output)| UtxoEntry::new(outpoint, output))
+            .collect()
+    }
+
+    fn created_entries(&self) -> Result<Vec<UtxoEntry>> {
+        self.created
+            .iter()
+            .map(|(outpoint, output)| UtxoEntry::new(outpoint, output))
+            .collect()
+    }
+}
+
+/// Entries created by each transaction of `block`, in block order.
+fn created_outputs(block: &Block) -> Result<Vec<Vec<UtxoEntry>>> {
+    let mut created = Vec::with_capacity(block.txs.len());
+    for tx in &block.txs {
+        let txid = tx.txid();
+        let mut outputs = Vec::with_capacity(tx.outputs.len());
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid,
+                index: index as u32,
+            };
+            outputs.push(UtxoEntry::new(&outpoint, output)?);
+        }
+        created.push(outputs);
+    }
+    Ok(created)
+}
+
+/// Outputs spent by each transaction of `block`, rebuilt from its undo data
+/// and the outputs created earlier in the block.
+fn block_spent_outputs(block: &Block, undo: &BlockUndo) -> Result<Vec<Vec<TxOut>>> {
+    let mut known: HashMap<Vec<u8>, TxOut> = undo
+        .spent
+        .iter()
+        .map(|(outpoint, output)| (outpoint_key(outpoint), output.clone()))
+        .collect();
+    let mut spent_by_tx = Vec::with_capacity(block.txs.len());
+    for tx in &block.txs {
+        let mut spent = Vec::with_capacity(tx.inputs.len());
+        for input in &tx.inputs {
+            let output = known
+                .remove(&outpoint_key(&input.outpoint))
+                .ok_or_else(|| {
+                    corruption(
+                        "undo",
+                        block.header.hash().0,
+                        "undo data does not cover block inputs",
+                    )
+                })?;
+            spent.push(output);
+        }
+        spent_by_tx.push(spent);
+        let txid = tx.txid();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid,
+                index: index as u32,
+            };
+            known.insert(outpoint_key(&outpoint), output.clone());
         }
-        Ok(results)
+    }
+    Ok(spent_by_tx)
+}
+
+/// `addr_history` value; the address, height and position live in the key.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct HistoryValue {
+    txid: Hash32,
+    timestamp: u64,
+    received: u64,
+    sent: u64,
+}
+
+/// `addr_history` entries for `block`: one per (transaction, address) pair,
+/// keyed by address, height and position so a reverse prefix scan lists an
+/// address's history newest first.
+fn history_entries(block: &Block, spent_by_tx: &[Vec<TxOut>]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut entries = Vec::new();
+    for (position, (tx, spent)) in block.txs.iter().zip(spent_by_tx).enumerate() {
+        let mut totals: BTreeMap<[u8; 32], (u64, u64)> = BTreeMap::new();
+        for output in &tx.outputs {
+            let total = totals.entry(output.address).or_default();
+            total.0 = total.0.saturating_add(output.amount);
+        }
+        for output in spent {
+            let total = totals.entry(output.address).or_default();
+            total.1 = total.1.saturating_add(output.amount);
+        }
+        let txid = tx.txid();
+        for (address, (received, sent)) in totals {
+            let mut key = Vec::with_capacity(44);
+            key.extend_from_slice(&address);
+            key.extend_from_slice(&block.header.height.to_be_bytes());
+            key.extend_from_slice(&(position as u32).to_be_bytes());
+            let value = HistoryValue {
+                txid,
+                timestamp: block.header.timestamp,
+                received,
+                sent,
+            };
+            entries.push((key, encode(&value)?));
+        }
+    }
+    Ok(entries)
+}
+
+fn abort(err: StorageError) -> ConflictableTransactionError<StorageError> {
+    ConflictableTransactionError::Abort(err)
+}
+
+fn transaction_error(err: TransactionError<StorageError>) -> StorageError {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => err.into(),
+    }
+}
+
+/// Refuses a block that is not the child of `tip`, which would leave a gap
+/// or a stale entry in the height index.
+fn check_extends_tip(tip: Option<(u64, Hash32)>, header: &BlockHeader) -> Result<()> {
+    match tip {
+        Some((height, hash)) if header.height == height + 1 && header.prev_hash == hash => Ok(()),
+        Some(_) => Err(StorageError::Rejected("block does not extend the tip")),
+        None => Err(StorageError::Rejected("tip missing")),
     }
 }
 
+fn missing_block(height: u64, hash: &Hash32) -> StorageError {
+    corruption(
+        "blocks",
+        hash.0,
+        format!("block at height {height} is missing"),
+    )
+}
+
+fn tip_value(height: u64, hash: &Hash32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(40);
+    data.extend_from_slice(&height.to_be_bytes());
+    data.extend_from_slice(&hash.0);
+    data
+}
+
+fn parse_tip(key: &[u8], value: &[u8]) -> Result<(u64, Hash32)> {
+    if value.len() != 40 {
+        return Err(corruption("meta", key, "malformed tip record"));
+    }
+    let mut height = [0u8; 8];
+    height.copy_from_slice(&value[..8]);
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&value[8..]);
+    Ok((u64::from_be_bytes(height), Hash32(hash)))
+}
+
+/// `addr_utxo` key: address payload followed by the outpoint key, so a prefix
+/// scan over the address yields exactly its UTXOs.
+fn address_index_key(address: &[u8; 32], outpoint_key: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(32 + outpoint_key.len());
+    key.extend_from_slice(address);
+    key.extend_from_slice(outpoint_key);
+    key
+}
+
 fn outpoint_key(outpoint: &OutPoint) -> Vec<u8> {
     let mut key = Vec::with_capacity(36);
     key.extend_from_slice(&outpoint.txid.0);
@@ -121,12 +1087,901 @@ fn outpoint_key(outpoint: &OutPoint) -> Vec<u8> {
     key
 }
 
-fn outpoint_from_key(key: &[u8]) -> OutPoint {
+/// Decodes the outpoint stored at `key[offset..]` of a `tree` record.
+fn outpoint_from_key(tree: &'static str, key: &[u8], offset: usize) -> Result<OutPoint> {
+    let raw = key
+        .get(offset..)
+        .filter(|raw| raw.len() == 36)
+        .ok_or_else(|| corruption(tree, key, "malformed outpoint key"))?;
     let mut txid = [0u8; 32];
-    txid.copy_from_slice(&key[..32]);
-    let index = u32::from_be_bytes(key[32..36].try_into().expect("index"));
-    OutPoint {
+    txid.copy_from_slice(&raw[..32]);
+    let mut index = [0u8; 4];
+    index.copy_from_slice(&raw[32..]);
+    Ok(OutPoint {
         txid: Hash32(txid),
-        index,
+        index: u32::from_be_bytes(index),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kexa_proto::TxIn;
+
+    fn temp_storage() -> Storage {
+        temp_storage_with(StorageOptions {
+            tx_index: true,
+            address_history: true,
+            ..StorageOptions::default()
+        })
+    }
+
+    fn temp_storage_with(options: StorageOptions) -> Storage {
+        let db = sled::Config::new().temporary(true).open().expect("db");
+        Storage::from_db(db, options).expect("storage")
+    }
+
+    fn block_on(prev: &BlockHeader, txs: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 0,
+                prev_hash: prev.hash(),
+                merkle_root: Hash32::zero(),
+                timestamp: prev.timestamp + 1,
+                bits: 0,
+                nonce: 0,
+                height: prev.height + 1,
+            },
+            txs,
+        }
+    }
+
+    fn coinbase(amount: u64, address: u8) -> Transaction {
+        Transaction {
+            version: 0,
+            inputs: vec![],
+            outputs: vec![TxOut {
+                amount,
+                address: [address; 32],
+            }],
+        }
+    }
+
+    fn spend(outpoint: OutPoint, amount: u64, address: u8) -> Transaction {
+        Transaction {
+            version: 0,
+            inputs: vec![TxIn {
+                outpoint,
+                signature: [0u8; 64],
+                pubkey: [0u8; 32],
+            }],
+            outputs: vec![TxOut {
+                amount,
+                address: [address; 32],
+            }],
+        }
+    }
+
+    fn genesis(storage: &dyn ChainStore) -> BlockHeader {
+        let block = Block {
+            header: BlockHeader {
+                version: 0,
+                prev_hash: Hash32::zero(),
+                merkle_root: Hash32::zero(),
+                timestamp: 0,
+                bits: 0,
+                nonce: 0,
+                height: 0,
+            },
+            txs: vec![coinbase(50, 1)],
+        };
+        storage.put_genesis(&block).expect("genesis");
+        block.header
+    }
+
+    #[test]
+    fn connect_then_disconnect_restores_state() {
+        let storage = temp_storage();
+        let genesis = genesis(&storage);
+        let funding = OutPoint {
+            txid: Hash32([3u8; 32]),
+            index: 0,
+        };
+        let funded = TxOut {
+            amount: 20,
+            address: [2u8; 32],
+        };
+        storage.put_utxo(&funding, &funded).expect("utxo");
+
+        let parent = spend(funding.clone(), 15, 2);
+        let child = spend(
+            OutPoint {
+                txid: parent.txid(),
+                index: 0,
+            },
+            10,
+            3,
+        );
+        let block = block_on(&genesis, vec![coinbase(55, 1), parent, child.clone()]);
+        let reader = storage.block_reader().expect("reader");
+        let hash = storage.connect_block(&block).expect("connect");
+        assert_eq!(reader.block_at(1).expect("read"), Some(block.clone()));
+
+        assert_eq!(storage.get_tip().expect("tip"), Some((1, hash)));
+        assert_eq!(storage.get_hash_by_height(1).expect("height"), Some(hash));
+        assert!(storage.get_utxo(&funding).expect("get").is_none());
+        let child_out = OutPoint {
+            txid: child.txid(),
+            index: 0,
+        };
+        assert!(storage.get_utxo(&child_out).expect("get").is_some());
+
+        let disconnected = storage.disconnect_block().expect("disconnect");
+        assert_eq!(disconnected, block);
+        assert_eq!(storage.get_tip().expect("tip"), Some((0, genesis.hash())));
+        assert!(storage.get_hash_by_height(1).expect("height").is_none());
+        assert!(reader.block_at(1).expect("read").is_none());
+        assert_eq!(storage.get_utxo(&funding).expect("get"), Some(funded));
+        assert_eq!(storage.tree("utxo").expect("tree").len(), 1);
+    }
+
+    #[test]
+    fn memory_store_matches_sled_store() {
+        let sled_store = temp_storage();
+        let memory_store = MemoryStore::new();
+        let stores: [&dyn ChainStore; 2] = [&sled_store, &memory_store];
+        let funding = OutPoint {
+            txid: Hash32([5u8; 32]),
+            index: 0,
+        };
+        let funded = TxOut {
+            amount: 30,
+            address: [2u8; 32],
+        };
+        let parent = spend(funding.clone(), 25, 2);
+        let child = spend(
+            OutPoint {
+                txid: parent.txid(),
+                index: 0,
+            },
+            20,
+            3,
+        );
+        let mut tips = Vec::new();
+        for store in stores {
+            let genesis = genesis(store);
+            store.put_utxo(&funding, &funded).expect("utxo");
+            let first = block_on(&genesis, vec![coinbase(50, 1), parent.clone()]);
+            store.connect_block(&first).expect("connect first");
+            let second = block_on(&first.header, vec![coinbase(50, 1), child.clone()]);
+            store.connect_block(&second).expect("connect second");
+            let invalid = block_on(&second.header, vec![spend(funding.clone(), 1, 4)]);
+            assert!(store.connect_block(&invalid).is_err());
+            // Blocks off the tip are refused, whoever skipped validating them.
+            for stale in [
+                block_on(&first.header, vec![coinbase(50, 4)]),
+                block_on(&genesis, vec![coinbase(50, 4)]),
+            ] {
+                assert!(matches!(
+                    store.connect_block(&stale),
+                    Err(StorageError::Rejected("block does not extend the tip"))
+                ));
+            }
+            let mut skipped = block_on(&second.header, vec![coinbase(50, 4)]);
+            skipped.header.height += 1;
+            assert!(store.connect_block(&skipped).is_err());
+            assert_eq!(store.get_hash_by_height(3).expect("height"), None);
+
+            let snapshot = |store: &dyn ChainStore| {
+                let utxos: Vec<_> = (1..=4u8)
+                    .map(|address| store.list_utxos_by_address(&[address; 32]).expect("list"))
+                    .collect();
+                (store.get_tip().expect("tip"), utxos)
+            };
+            let connected = snapshot(store);
+            assert_eq!(
+                store
+                    .get_height_by_hash(&second.header.hash())
+                    .expect("height"),
+                Some(2)
+            );
+            assert_eq!(store.disconnect_block().expect("disconnect"), second);
+            let disconnected = snapshot(store);
+            assert_eq!(store.get_header(2).expect("header"), None);
+            assert_eq!(
+                store
+                    .get_height_by_hash(&second.header.hash())
+                    .expect("height"),
+                None
+            );
+            assert_eq!(
+                store
+                    .get_height_by_hash(&first.header.hash())
+                    .expect("height"),
+                Some(1)
+            );
+            // Replacing a height index entry unindexes the hash it held.
+            let side = Hash32([9u8; 32]);
+            store.put_height_hash(1, &side).expect("height");
+            assert_eq!(
+                store
+                    .get_height_by_hash(&first.header.hash())
+                    .expect("height"),
+                None
+            );
+            assert_eq!(store.get_height_by_hash(&side).expect("height"), Some(1));
+            assert_eq!(store.get_header(1).expect("header"), Some(first.header));
+            tips.push((connected, disconnected));
+        }
+        assert_eq!(tips[0], tips[1]);
+    }
+
+    #[test]
+    fn failed_connect_leaves_no_partial_writes() {
+        let storage = temp_storage();
+        let genesis = genesis(&storage);
+        let funding = OutPoint {
+            txid: Hash32([4u8; 32]),
+            index: 0,
+        };
+        storage
+            .put_utxo(
+                &funding,
+                &TxOut {
+                    amount: 20,
+                    address: [2u8; 32],
+                },
+            )
+            .expect("utxo");
+        let missing = OutPoint {
+            txid: Hash32([5u8; 32]),
+            index: 0,
+        };
+        let block = block_on(
+            &genesis,
+            vec![
+                coinbase(50, 1),
+                spend(funding.clone(), 20, 2),
+                spend(missing, 1, 2),
+            ],
+        );
+
+        let err = storage.connect_block(&block).unwrap_err();
+        assert!(err.to_string().contains("missing utxo"));
+        assert_eq!(storage.get_tip().expect("tip"), Some((0, genesis.hash())));
+        assert!(storage.get_utxo(&funding).expect("get").is_some());
+        assert!(storage
+            .get_block(&block.header.hash())
+            .expect("get")
+            .is_none());
+        assert_eq!(storage.tree("utxo").expect("tree").len(), 1);
+    }
+
+    #[test]
+    fn address_index_follows_utxo_changes() {
+        let storage = temp_storage();
+        let genesis = genesis(&storage);
+        let output = |amount| TxOut {
+            amount,
+            address: [2u8; 32],
+        };
+        let first = OutPoint {
+            txid: Hash32([6u8; 32]),
+            index: 0,
+        };
+        let second = OutPoint {
+            txid: Hash32([6u8; 32]),
+            index: 1,
+        };
+        storage.put_utxo(&first, &output(5)).expect("utxo");
+        storage.put_utxo(&second, &output(7)).expect("utxo");
+        assert_eq!(
+            storage
+                .list_utxos_by_address(&[2u8; 32])
+                .expect("list")
+                .len(),
+            2
+        );
+
+        storage.delete_utxo(&second).expect("delete");
+        assert_eq!(
+            storage.list_utxos_by_address(&[2u8; 32]).expect("list"),
+            vec![(first.clone(), output(5))]
+        );
+
+        let block = block_on(&genesis, vec![coinbase(50, 1), spend(first.clone(), 5, 3)]);
+        storage.connect_block(&block).expect("connect");
+        assert!(storage
+            .list_utxos_by_address(&[2u8; 32])
+            .expect("list")
+            .is_empty());
+        assert_eq!(
+            storage
+                .list_utxos_by_address(&[3u8; 32])
+                .expect("list")
+                .len(),
+            1
+        );
+        assert_eq!(
+            storage
+                .list_utxos_by_address(&[1u8; 32])
+                .expect("list")
+                .len(),
+            1
+        );
+
+        storage.disconnect_block().expect("disconnect");
+        assert_eq!(
+            storage.list_utxos_by_address(&[2u8; 32]).expect("list"),
+            vec![(first, output(5))]
+        );
+        assert!(storage
+            .list_utxos_by_address(&[3u8; 32])
+            .expect("list")
+            .is_empty());
+        assert!(storage
+            .list_utxos_by_address(&[1u8; 32])
+            .expect("list")
+            .is_empty());
+    }
+
+    #[test]
+    fn address_index_backfilled_for_existing_utxos() {
+        let storage = temp_storage();
+        let outpoint = OutPoint {
+            txid: Hash32([8u8; 32]),
+            index: 3,
+        };
+        let output = TxOut {
+            amount: 9,
+            address: [4u8; 32],
+        };
+        storage
+            .tree("utxo")
+            .expect("tree")
+            .insert(
+                outpoint_key(&outpoint),
+                borsh::to_vec(&output).expect("encode"),
+            )
+            .expect("raw insert");
+        assert!(storage
+            .list_utxos_by_address(&[4u8; 32])
+            .expect("list")
+            .is_empty());
+
+        // Simulate a data dir written before the index existed.
+        storage.set_schema_version(0).expect("version");
+        storage.migrate().expect("migrate");
+        assert_eq!(storage.schema_version().expect("version"), SCHEMA_VERSION);
+        assert_eq!(
+            storage.list_utxos_by_address(&[4u8; 32]).expect("list"),
+            vec![(outpoint, output)]
+        );
+    }
+
+    #[test]
+    fn migration_backfills_undo_data() {
+        let storage = temp_storage();
+        let genesis = genesis(&storage);
+        let funding = OutPoint {
+            txid: Hash32([6u8; 32]),
+            index: 0,
+        };
+        let funded = TxOut {
+            amount: 12,
+            address: [2u8; 32],
+        };
+        storage.put_utxo(&funding, &funded).expect("utxo");
+        let block = block_on(&genesis, vec![coinbase(50, 1)]);
+        let hash = storage.connect_block(&block).expect("connect");
+
+        // Simulate a pre-versioning data dir whose blocks have no undo data
+        // or hash index.
+        storage
+            .tree("undo")
+            .expect("tree")
+            .remove(hash.0)
+            .expect("drop undo");
+        storage
+            .tree("hash_height")
+            .expect("tree")
+            .clear()
+            .expect("drop hash index");
+        storage
+            .tree("meta")
+            .expect("tree")
+            .remove(b"schema_version")
+            .expect("version");
+        assert_eq!(storage.schema_version().expect("version"), 0);
+        storage.migrate().expect("migrate");
+        assert_eq!(storage.schema_version().expect("version"), SCHEMA_VERSION);
+        assert!(storage
+            .tree("undo")
+            .expect("tree")
+            .contains_key(hash.0)
+            .expect("undo"));
+        assert_eq!(storage.get_height_by_hash(&hash).expect("height"), Some(1));
+        assert_eq!(
+            storage.get_height_by_hash(&genesis.hash()).expect("height"),
+            Some(0)
+        );
+        assert_eq!(storage.disconnect_block().expect("disconnect"), block);
+    }
+
+    #[test]
+    fn reindex_rebuilds_utxo_set_from_blocks() {
+        let storage = temp_storage();
+        let genesis = genesis(&storage);
+        let first = block_on(&genesis, vec![coinbase(50, 1)]);
+        storage.connect_block(&first).expect("connect first");
+        let reward = OutPoint {
+            txid: first.txs[0].txid(),
+            index: 0,
+        };
+        let second = block_on(&first.header, vec![coinbase(50, 1), spend(reward, 45, 2)]);
+        let tip = storage.connect_block(&second).expect("connect second");
+        let expected = storage.list_utxos().expect("utxos");
+
+        storage
+            .tree("utxo")
+            .expect("tree")
+            .clear()
+            .expect("wipe utxo");
+        storage
+            .tree("tx_index")
+            .expect("tree")
+            .clear()
+            .expect("wipe tx index");
+        assert_eq!(storage.reindex().expect("reindex"), 2);
+        assert_eq!(storage.list_utxos().expect("utxos"), expected);
+        assert_eq!(storage.get_tip().expect("tip"), Some((2, tip)));
+        assert_eq!(
+            storage
+                .list_utxos_by_address(&[2u8; 32])
+                .expect("list")
+                .len(),
+            1
+        );
+        let location = storage
+            .get_tx_location(&second.txs[1].txid())
+            .expect("location");
+        assert_eq!(location.map(|l| l.height), Some(2));
+    }
+
+    #[test]
+    fn corrupt_records_are_reported_not_panicked() {
+        let storage = temp_storage();
+        storage
+            .tree("meta")
+            .expect("tree")
+            .insert(b"tip", &[1u8, 2, 3])
+            .expect("raw insert");
+        let err = storage.get_tip().unwrap_err();
+        assert!(matches!(err, StorageError::Corruption { tree: "meta", .. }));
+
+        let outpoint = OutPoint {
+            txid: Hash32([7u8; 32]),
+            index: 1,
+        };
+        storage
+            .tree("utxo")
+            .expect("tree")
+            .insert(outpoint_key(&outpoint), &[0xffu8][..])
+            .expect("raw insert");
+        let err = storage.get_utxo(&outpoint).unwrap_err();
+        assert!(matches!(err, StorageError::Decode { tree: "utxo", .. }));
+        assert!(err.is_corruption());
+
+        storage
+            .tree("addr_utxo")
+            .expect("tree")
+            .insert(
+                [5u8; 33],
+                borsh::to_vec(&coinbase(1, 5).outputs[0]).expect("encode"),
+            )
+            .expect("raw insert");
+        let err = storage.list_utxos_by_address(&[5u8; 32]).unwrap_err();
+        assert!(err.to_string().contains("malformed outpoint key"));
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let storage = temp_storage();
+        storage
+            .set_schema_version(SCHEMA_VERSION + 1)
+            .expect("version");
+        let err = Storage::from_db(storage.db.clone(), StorageOptions::default())
+            .err()
+            .expect("newer schema rejected");
+        assert!(err.to_string().contains("newer than supported"));
+    }
+
+    #[test]
+    fn tx_index_follows_connect_and_disconnect() {
+        let mut storage = temp_storage();
+        let genesis = genesis(&storage);
+        let genesis_txid = coinbase(50, 1).txid();
+        assert_eq!(
+            storage.get_tx_location(&genesis_txid).expect("get"),
+            Some(TxLocation {
+                block_hash: genesis.hash(),
+                height: 0,
+                position: 0,
+            })
+        );
+
+        let funding = OutPoint {
+            txid: Hash32([7u8; 32]),
+            index: 0,
+        };
+        storage
+            .put_utxo(
+                &funding,
+                &TxOut {
+                    amount: 20,
+                    address: [2u8; 32],
+                },
+            )
+            .expect("utxo");
+        let tx = spend(funding, 20, 3);
+        let block = block_on(&genesis, vec![coinbase(50, 1), tx.clone()]);
+        let hash = storage.connect_block(&block).expect("connect");
+        let (found, location) = storage
+            .get_transaction(&tx.txid())
+            .expect("get")
+            .expect("indexed");
+        assert_eq!(found, tx);
+        assert_eq!(
+            location,
+            TxLocation {
+                block_hash: hash,
+                height: 1,
+                position: 1,
+            }
+        );
+
+        // Disabling drops the index; re-enabling rebuilds it from blocks.
+        storage.options.tx_index = false;
+        storage.ensure_tx_index().expect("drop");
+        assert!(storage.get_tx_location(&tx.txid()).expect("get").is_none());
+        storage.options.tx_index = true;
+        storage.ensure_tx_index().expect("rebuild");
+        assert_eq!(
+            storage.get_tx_location(&tx.txid()).expect("get"),
+            Some(location)
+        );
+
+        storage.disconnect_block().expect("disconnect");
+        assert!(storage.get_transaction(&tx.txid()).expect("get").is_none());
+    }
+
+    #[test]
+    fn address_history_follows_connect_and_disconnect() {
+        let mut storage = temp_storage();
+        let genesis = genesis(&storage);
+        let first = block_on(&genesis, vec![coinbase(50, 2)]);
+        storage.connect_block(&first).expect("connect");
+        let reward = OutPoint {
+            txid: first.txs[0].txid(),
+            index: 0,
+        };
+        let pay = Transaction {
+            version: 0,
+            inputs: spend(reward, 0, 0).inputs,
+            outputs: vec![
+                TxOut {
+                    amount: 30,
+                    address: [3u8; 32],
+                },
+                TxOut {
+                    amount: 19,
+                    address: [2u8; 32],
+                },
+            ],
+        };
+        let second = block_on(&first.header, vec![coinbase(51, 1), pay.clone()]);
+        storage.connect_block(&second).expect("connect");
+
+        let history = storage.address_history(&[2u8; 32], 0, 10).expect("history");
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].txid, pay.txid());
+        assert_eq!((history[0].height, history[0].position), (2, 1));
+        assert_eq!((history[0].received, history[0].sent), (19, 50));
+        assert_eq!(history[0].timestamp, second.header.timestamp);
+        assert_eq!((history[1].height, history[1].received), (1, 50));
+        let page = storage.address_history(&[2u8; 32], 1, 10).expect("page");
+        assert_eq!(page, history[1..].to_vec());
+        let receiver = storage.address_history(&[3u8; 32], 0, 10).expect("history");
+        assert_eq!((receiver[0].received, receiver[0].sent), (30, 0));
+        // Genesis outputs are not spendable, so they have no history.
+        assert!(storage
+            .address_history(&[1u8; 32], 0, 10)
+            .expect("history")
+            .iter()
+            .all(|entry| entry.height > 0));
+
+        // Rebuilding from stored blocks yields the same index.
+        storage.options.address_history = false;
+        storage.ensure_address_history().expect("drop");
+        assert!(storage
+            .address_history(&[2u8; 32], 0, 10)
+            .expect("history")
+            .is_empty());
+        storage.options.address_history = true;
+        storage.ensure_address_history().expect("rebuild");
+        assert_eq!(
+            storage.address_history(&[2u8; 32], 0, 10).expect("history"),
+            history
+        );
+
+        storage.disconnect_block().expect("disconnect");
+        assert_eq!(
+            storage.address_history(&[2u8; 32], 0, 10).expect("history"),
+            history[1..].to_vec()
+        );
+        assert!(storage
+            .address_history(&[3u8; 32], 0, 10)
+            .expect("history")
+            .is_empty());
+    }
+
+    /// Three blocks where the second spends the first's reward and the third
+    /// spends an output created in the second.
+    fn spending_chain(genesis: &BlockHeader) -> Vec<Block> {
+        let first = block_on(genesis, vec![coinbase(50, 1)]);
+        let reward = OutPoint {
+            txid: first.txs[0].txid(),
+            index: 0,
+        };
+        let payment = spend(reward, 45, 2);
+        let change = OutPoint {
+            txid: payment.txid(),
+            index: 0,
+        };
+        let second = block_on(&first.header, vec![coinbase(50, 5), payment]);
+        let third = block_on(&second.header, vec![coinbase(50, 3), spend(change, 40, 4)]);
+        vec![first, second, third]
+    }
+
+    #[test]
+    fn utxo_cache_defers_writes_until_flush() {
+        let cached = temp_storage_with(StorageOptions {
+            address_history: true,
+            utxo_cache_bytes: 1 << 20,
+            ..StorageOptions::default()
+        });
+        let plain = temp_storage();
+        genesis(&plain);
+        let blocks = spending_chain(&genesis(&cached));
+        for block in &blocks {
+            cached.connect_block(block).expect("connect cached");
+            plain.connect_block(block).expect("connect plain");
+        }
+
+        assert!(cached.tree("utxo").expect("tree").is_empty());
+        assert!(cached
+            .tree("meta")
+            .expect("tree")
+            .contains_key(UTXO_TIP_KEY)
+            .expect("meta"));
+        assert_eq!(
+            cached.list_utxos().expect("list"),
+            plain.list_utxos().expect("list")
+        );
+        assert_eq!(
+            cached.list_utxos_by_address(&[4u8; 32]).expect("list"),
+            plain.list_utxos_by_address(&[4u8; 32]).expect("list")
+        );
+        assert_eq!(
+            cached.address_history(&[2u8; 32], 0, 10).expect("history"),
+            plain.address_history(&[2u8; 32], 0, 10).expect("history")
+        );
+
+        // Idle flushes wait for the interval.
+        cached.flush_if_due().expect("not due");
+        assert!(cached.tree("utxo").expect("tree").is_empty());
+        cached.utxo_cache().expect("cache").last_flush -= std::time::Duration::from_secs(3_600);
+        cached.flush_if_due().expect("due");
+        assert_eq!(
+            cached.tree("utxo").expect("tree").len(),
+            plain.tree("utxo").expect("tree").len()
+        );
+        assert!(!cached
+            .tree("meta")
+            .expect("tree")
+            .contains_key(UTXO_TIP_KEY)
+            .expect("meta"));
+
+        // Disconnecting flushes first, then restores from undo data on disk.
+        cached.disconnect_block().expect("disconnect cached");
+        plain.disconnect_block().expect("disconnect plain");
+        assert_eq!(
+            cached.list_utxos().expect("list"),
+            plain.list_utxos().expect("list")
+        );
+        cached.connect_block(&blocks[2]).expect("reconnect");
+        cached.flush().expect("flush");
+        assert!(!cached
+            .tree("meta")
+            .expect("tree")
+            .contains_key(UTXO_TIP_KEY)
+            .expect("meta"));
+        assert_eq!(cached.tree("utxo").expect("tree").len(), 3);
+        assert_eq!(cached.tree("addr_utxo").expect("tree").len(), 3);
+    }
+
+    #[test]
+    fn unflushed_utxo_cache_is_recovered_on_open() {
+        let db = sled::Config::new().temporary(true).open().expect("db");
+        let options = StorageOptions {
+            utxo_cache_bytes: 1 << 20,
+            ..StorageOptions::default()
+        };
+        let cached = Storage::from_db(db.clone(), options).expect("storage");
+        let genesis = genesis(&cached);
+        let blocks = spending_chain(&genesis);
+        cached.connect_block(&blocks[0]).expect("connect");
+        cached.flush().expect("flush");
+        cached.connect_block(&blocks[1]).expect("connect");
+        cached.connect_block(&blocks[2]).expect("connect");
+        let expected = cached.list_utxos().expect("list");
+        // Simulate a crash: the cached changes are never written.
+        std::mem::forget(cached);
+
+        let reopened = Storage::from_db(db, StorageOptions::default()).expect("reopen");
+        assert_eq!(reopened.list_utxos().expect("list"), expected);
+        assert_eq!(reopened.tree("utxo").expect("tree").len(), 3);
+        assert!(!reopened
+            .tree("meta")
+            .expect("tree")
+            .contains_key(UTXO_TIP_KEY)
+            .expect("meta"));
+        reopened.disconnect_block().expect("disconnect");
+    }
+
+    #[test]
+    fn utxo_cache_flushes_when_over_budget() {
+        let storage = temp_storage_with(StorageOptions {
+            utxo_cache_bytes: 1,
+            ..StorageOptions::default()
+        });
+        let genesis = genesis(&storage);
+        let block = block_on(&genesis, vec![coinbase(50, 1)]);
+        storage.connect_block(&block).expect("connect");
+        assert_eq!(storage.tree("utxo").expect("tree").len(), 1);
+        assert!(!storage
+            .tree("meta")
+            .expect("tree")
+            .contains_key(UTXO_TIP_KEY)
+            .expect("meta"));
+    }
+
+    #[test]
+    fn utxo_snapshot_loads_and_completes_with_validated_history() {
+        let source = temp_storage();
+        let blocks = spending_chain(&genesis(&source));
+        for block in &blocks {
+            source.connect_block(block).expect("connect");
+        }
+        let mut file = Vec::new();
+        let snapshot = source.write_utxo_snapshot(2, &mut file).expect("write");
+        assert_eq!(snapshot.base, blocks[1].header);
+
+        let history = temp_storage_with(StorageOptions::default());
+        genesis(&history);
+        for block in &blocks[..2] {
+            history.connect_block(block).expect("connect history");
+        }
+        assert_eq!(
+            history.utxo_set_hash().expect("hash"),
+            (snapshot.utxo_count, snapshot.utxo_hash)
+        );
+
+        let target = temp_storage_with(StorageOptions::default());
+        genesis(&target);
+        target
+            .load_utxo_snapshot(&mut file.as_slice(), &snapshot.utxo_hash)
+            .expect("load");
+        assert_eq!(
+            target.get_tip().expect("tip"),
+            Some((2, blocks[1].header.hash()))
+        );
+        assert_eq!(
+            target
+                .get_height_by_hash(&blocks[1].header.hash())
+                .expect("height"),
+            Some(2)
+        );
+        assert_eq!(
+            target.list_utxos().expect("list"),
+            history.list_utxos().expect("list")
+        );
+        target
+            .connect_block(&blocks[2])
+            .expect("connect on snapshot");
+        assert!(target.reindex().is_err());
+        target.disconnect_block().expect("disconnect above base");
+        assert!(matches!(
+            target.disconnect_block(),
+            Err(StorageError::Rejected(_))
+        ));
+        target.connect_block(&blocks[2]).expect("reconnect");
+
+        target
+            .complete_snapshot_history(&history)
+            .expect("complete");
+        assert!(target.snapshot_base().expect("base").is_none());
+        assert_eq!(
+            target
+                .get_height_by_hash(&blocks[0].header.hash())
+                .expect("height"),
+            Some(1)
+        );
+        assert_eq!(target.reindex().expect("reindex"), 3);
+        assert_eq!(
+            target.list_utxos().expect("list"),
+            source.list_utxos().expect("list")
+        );
+    }
+
+    #[test]
+    fn utxo_snapshot_contradicted_by_history_is_recorded() {
+        let source = temp_storage();
+        let blocks = spending_chain(&genesis(&source));
+        for block in &blocks {
+            source.connect_block(block).expect("connect");
+        }
+        let mut file = Vec::new();
+        let snapshot = source.write_utxo_snapshot(2, &mut file).expect("write");
+        let target = temp_storage_with(StorageOptions::default());
+        genesis(&target);
+        target
+            .load_utxo_snapshot(&mut file.as_slice(), &snapshot.utxo_hash)
+            .expect("load");
+        assert_eq!(target.snapshot_failure().expect("failure"), None);
+
+        // The validated chain reaches the base with another UTXO set.
+        let history = temp_storage_with(StorageOptions::default());
+        genesis(&history);
+        history.connect_block(&blocks[0]).expect("connect");
+        let other = block_on(&blocks[0].header, vec![coinbase(50, 9)]);
+        history.connect_block(&other).expect("connect");
+        let err = target.complete_snapshot_history(&history).unwrap_err();
+        assert!(matches!(err, StorageError::InvalidSnapshot(_)));
+        let reason = target
+            .snapshot_failure()
+            .expect("failure")
+            .expect("recorded");
+        assert!(reason.contains("does not match the snapshot"), "{reason}");
+        assert!(target.snapshot_base().expect("base").is_some());
+    }
+
+    #[test]
+    fn utxo_snapshot_rejects_mismatched_commitment() {
+        let source = temp_storage();
+        let blocks = spending_chain(&genesis(&source));
+        for block in &blocks {
+            source.connect_block(block).expect("connect");
+        }
+        let mut file = Vec::new();
+        let snapshot = source.write_utxo_snapshot(3, &mut file).expect("write");
+        let target = temp_storage_with(StorageOptions::default());
+        let genesis_tip = Some((0, genesis(&target).hash()));
+
+        let err = target
+            .load_utxo_snapshot(&mut file.as_slice(), &Hash32([9u8; 32]))
+            .unwrap_err();
+        assert!(matches!(err, StorageError::InvalidSnapshot(_)));
+
+        // Bump the amount of the last entry.
+        let amount_at = file.len() - 40;
+        file[amount_at] ^= 1;
+        let err = target
+            .load_utxo_snapshot(&mut file.as_slice(), &snapshot.utxo_hash)
+            .unwrap_err();
+        assert!(err.to_string().contains("do not match the commitment"));
+        assert_eq!(target.get_tip().expect("tip"), genesis_tip);
+        assert!(target.tree("utxo").expect("tree").is_empty());
+        assert!(target.snapshot_base().expect("base").is_none());
     }
 }
@@ -0,0 +1,207 @@
+use crate::error::corruption;
+use crate::{check_extends_tip, outpoint_from_key, outpoint_key, ChainStore, Result, StorageError};
+use kexa_proto::{Block, BlockHeader, Hash32, OutPoint, TxOut};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// [`ChainStore`] kept entirely in process memory. Nothing touches disk, so
+/// tests, fuzzers and simulators can spin up as many chains as they like.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    blocks: HashMap<Hash32, Block>,
+    headers: BTreeMap<u64, BlockHeader>,
+    height_hash: BTreeMap<u64, Hash32>,
+    hash_height: HashMap<Hash32, u64>,
+    tip: Option<(u64, Hash32)>,
+    /// Keyed like the sled `utxo` tree so iteration order matches.
+    utxos: BTreeMap<Vec<u8>, TxOut>,
+    undo: HashMap<Hash32, Vec<(OutPoint, TxOut)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every write validates before mutating, so a panic elsewhere while
+    /// the lock was held cannot have left the state half-updated.
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn put_block(&self, hash: &Hash32, block: &Block) -> Result<()> {
+        self.state().blocks.insert(*hash, block.clone());
+        Ok(())
+    }
+
+    fn get_block(&self, hash: &Hash32) -> Result<Option<Block>> {
+        Ok(self.state().blocks.get(hash).cloned())
+    }
+
+    fn put_header(&self, height: u64, header: &BlockHeader) -> Result<()> {
+        self.state().headers.insert(height, header.clone());
+        Ok(())
+    }
+
+    fn get_header(&self, height: u64) -> Result<Option<BlockHeader>> {
+        Ok(self.state().headers.get(&height).cloned())
+    }
+
+    fn put_height_hash(&self, height: u64, hash: &Hash32) -> Result<()> {
+        let mut state = self.state();
+        if let Some(replaced) = state.height_hash.insert(height, *hash) {
+            state.hash_height.remove(&replaced);
+        }
+        state.hash_height.insert(*hash, height);
+        Ok(())
+    }
+
+    fn get_hash_by_height(&self, height: u64) -> Result<Option<Hash32>> {
+        Ok(self.state().height_hash.get(&height).copied())
+    }
+
+    fn get_height_by_hash(&self, hash: &Hash32) -> Result<Option<u64>> {
+        Ok(self.state().hash_height.get(hash).copied())
+    }
+
+    fn set_tip(&self, height: u64, hash: &Hash32) -> Result<()> {
+        self.state().tip = Some((height, *hash));
+        Ok(())
+    }
+
+    fn get_tip(&self) -> Result<Option<(u64, Hash32)>> {
+        Ok(self.state().tip)
+    }
+
+    fn put_utxo(&self, outpoint: &OutPoint, output: &TxOut) -> Result<()> {
+        self.state()
+            .utxos
+            .insert(outpoint_key(outpoint), output.clone());
+        Ok(())
+    }
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<TxOut>> {
+        Ok(self.state().utxos.get(&outpoint_key(outpoint)).cloned())
+    }
+
+    fn delete_utxo(&self, outpoint: &OutPoint) -> Result<()> {
+        self.state().utxos.remove(&outpoint_key(outpoint));
+        Ok(())
+    }
+
+    fn list_utxos_by_address(&self, address: &[u8; 32]) -> Result<Vec<(OutPoint, TxOut)>> {
+        self.state()
+            .utxos
+            .iter()
+            .filter(|(_, output)| &output.address == address)
+            .map(|(key, output)| Ok((outpoint_from_key("utxo", key, 0)?, output.clone())))
+            .collect()
+    }
+
+    fn list_utxos(&self) -> Result<Vec<(OutPoint, TxOut)>> {
+        self.state()
+            .utxos
+            .iter()
+            .map(|(key, output)| Ok((outpoint_from_key("utxo", key, 0)?, output.clone())))
+            .collect()
+    }
+
+    fn connect_block(&self, block: &Block) -> Result<Hash32> {
+        let hash = block.header.hash();
+        let height = block.header.height;
+        let mut state = self.state();
+        check_extends_tip(state.tip, &block.header)?;
+        // Resolve every input before mutating anything so a failed connect
+        // leaves the store untouched, as the sled transaction does.
+        let mut created: HashMap<Vec<u8>, TxOut> = HashMap::new();
+        let mut spent_keys = HashSet::new();
+        let mut spent = Vec::new();
+        for tx in &block.txs {
+            for input in &tx.inputs {
+                let key = outpoint_key(&input.outpoint);
+                // Outputs created and spent within this block need no undo.
+                if created.remove(&key).is_some() {
+                    continue;
+                }
+                let output = match state.utxos.get(&key) {
+                    Some(output) if !spent_keys.contains(&key) => output.clone(),
+                    _ => return Err(StorageError::Rejected("missing utxo when applying block")),
+                };
+                spent.push((input.outpoint.clone(), output));
+                spent_keys.insert(key);
+            }
+            let txid = tx.txid();
+            for (index, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid,
+                    index: index as u32,
+                };
+                created.insert(outpoint_key(&outpoint), output.clone());
+            }
+        }
+        for key in &spent_keys {
+            state.utxos.remove(key);
+        }
+        state.utxos.extend(created);
+        state.undo.insert(hash, spent);
+        state.blocks.insert(hash, block.clone());
+        state.headers.insert(height, block.header.clone());
+        state.height_hash.insert(height, hash);
+        state.hash_height.insert(hash, height);
+        state.tip = Some((height, hash));
+        Ok(hash)
+    }
+
+    fn disconnect_block(&self) -> Result<Block> {
+        let mut state = self.state();
+        let (height, hash) = state.tip.ok_or(StorageError::Rejected("tip missing"))?;
+        if height == 0 {
+            return Err(StorageError::Rejected("cannot disconnect genesis block"));
+        }
+        let block = state
+            .blocks
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| corruption("blocks", hash.0, "tip block missing"))?;
+        let undo = state
+            .undo
+            .remove(&hash)
+            .ok_or_else(|| corruption("undo", hash.0, "undo data missing for tip block"))?;
+        for tx in &block.txs {
+            let txid = tx.txid();
+            for index in 0..tx.outputs.len() {
+                let outpoint = OutPoint {
+                    txid,
+                    index: index as u32,
+                };
+                state.utxos.remove(&outpoint_key(&outpoint));
+            }
+        }
+        for (outpoint, output) in undo {
+            state.utxos.insert(outpoint_key(&outpoint), output);
+        }
+        state.headers.remove(&height);
+        state.height_hash.remove(&height);
+        state.hash_height.remove(&hash);
+        state.tip = Some((height - 1, block.header.prev_hash));
+        Ok(block)
+    }
+
+    fn put_genesis(&self, block: &Block) -> Result<Hash32> {
+        let hash = block.header.hash();
+        let mut state = self.state();
+        state.blocks.insert(hash, block.clone());
+        state.headers.insert(0, block.header.clone());
+        state.height_hash.insert(0, hash);
+        state.hash_height.insert(hash, 0);
+        state.tip = Some((0, hash));
+        Ok(hash)
+    }
+}
@@ -0,0 +1,173 @@
+use crate::error::{corruption, decode, encode};
+use crate::{
+    address_index_key, outpoint_key, BlockUndo, ChainStore, Result, Storage, StorageError,
+};
+use kexa_proto::{OutPoint, TxOut};
+use std::collections::HashSet;
+use tracing::info;
+
+/// Layout version written by this build. Bump it together with a new entry in
+/// [`MIGRATIONS`] whenever trees or key formats change.
+pub const SCHEMA_VERSION: u32 = 5;
+
+const VERSION_KEY: &[u8] = b"schema_version";
+
+/// How often long-running migrations report progress, in blocks or entries.
+pub(crate) const PROGRESS_INTERVAL: u64 = 10_000;
+
+struct Migration {
+    /// Version the data dir is at once this step has run.
+    to: u32,
+    description: &'static str,
+    run: fn(&Storage) -> Result<()>,
+}
+
+/// Upgrade steps in order. Each must tolerate partially migrated data, since
+/// the version only advances after the step completes.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 1,
+        description: "build addr_utxo index",
+        run: Storage::migrate_address_index,
+    },
+    Migration {
+        to: 2,
+        description: "backfill block undo data",
+        run: Storage::migrate_undo_data,
+    },
+    Migration {
+        to: 3,
+        // Older builds would ignore the `utxo_tip` marker and serve a UTXO
+        // set that lags the tip; nothing to rewrite.
+        description: "track unflushed utxo cache writes",
+        run: |_| Ok(()),
+    },
+    Migration {
+        to: 4,
+        // Older builds would not know that a snapshot-based dir lacks the
+        // blocks below its base.
+        description: "record utxo snapshot base",
+        run: |_| Ok(()),
+    },
+    Migration {
+        to: 5,
+        description: "index active chain heights by block hash",
+        run: Storage::migrate_hash_index,
+    },
+];
+
+impl Storage {
+    /// Schema version recorded in the data dir; `0` for dirs written before
+    /// versioning existed.
+    pub fn schema_version(&self) -> Result<u32> {
+        match self.tree("meta")?.get(VERSION_KEY)? {
+            Some(value) => {
+                let bytes = value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| corruption("meta", VERSION_KEY, "malformed schema version"))?;
+                Ok(u32::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    pub(crate) fn set_schema_version(&self, version: u32) -> Result<()> {
+        self.tree("meta")?
+            .insert(VERSION_KEY, &version.to_be_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Brings the data dir up to [`SCHEMA_VERSION`], one migration at a time,
+    /// and refuses dirs written by a newer build. A dir with no chain yet is
+    /// stamped with the current version directly.
+    pub(crate) fn migrate(&self) -> Result<()> {
+        let meta = self.tree("meta")?;
+        if !meta.contains_key(VERSION_KEY)? && self.get_tip()?.is_none() {
+            return self.set_schema_version(SCHEMA_VERSION);
+        }
+        let stored = self.schema_version()?;
+        if stored > SCHEMA_VERSION {
+            return Err(StorageError::UnsupportedSchema {
+                found: stored,
+                supported: SCHEMA_VERSION,
+            });
+        }
+        let mut version = stored;
+        for migration in MIGRATIONS.iter().filter(|m| m.to > stored) {
+            info!(
+                "migrating storage schema {} -> {}: {}",
+                version, migration.to, migration.description
+            );
+            (migration.run)(self)?;
+            self.set_schema_version(migration.to)?;
+            version = migration.to;
+        }
+        Ok(())
+    }
+
+    /// v1: index every UTXO by address in `addr_utxo`.
+    fn migrate_address_index(&self) -> Result<()> {
+        let addr_utxo = self.tree("addr_utxo")?;
+        addr_utxo.clear()?;
+        for (done, item) in self.tree("utxo")?.iter().enumerate() {
+            let (key, value) = item?;
+            let output: TxOut = decode("utxo", &key, &value)?;
+            addr_utxo.insert(address_index_key(&output.address, &key), value)?;
+            if (done as u64 + 1).is_multiple_of(PROGRESS_INTERVAL) {
+                info!("indexed {} utxos by address", done + 1);
+            }
+        }
+        // Marker used by builds that predate schema versioning.
+        self.tree("meta")?.remove(b"addr_index")?;
+        Ok(())
+    }
+
+    /// v2: write undo data for connected blocks that lack it, so they can be
+    /// disconnected.
+    fn migrate_undo_data(&self) -> Result<()> {
+        let undo = self.tree("undo")?;
+        let tip_height = self.get_tip()?.map(|(height, _)| height).unwrap_or(0);
+        self.replay_chain(|block, spent_by_tx| {
+            let height = block.header.height;
+            if height > 0 && height.is_multiple_of(PROGRESS_INTERVAL) {
+                info!("undo data backfilled to height {height}/{tip_height}");
+            }
+            let hash = block.header.hash();
+            if height == 0 || undo.contains_key(hash.0)? {
+                return Ok(());
+            }
+            let mut created_here = HashSet::new();
+            let mut spent = Vec::new();
+            for (tx, outputs) in block.txs.iter().zip(spent_by_tx) {
+                for (input, output) in tx.inputs.iter().zip(outputs) {
+                    if !created_here.contains(&outpoint_key(&input.outpoint)) {
+                        spent.push((input.outpoint.clone(), output.clone()));
+                    }
+                }
+                let txid = tx.txid();
+                for index in 0..tx.outputs.len() {
+                    created_here.insert(outpoint_key(&OutPoint {
+                        txid,
+                        index: index as u32,
+                    }));
+                }
+            }
+            undo.insert(hash.0, encode(&BlockUndo { spent })?)?;
+            Ok(())
+        })
+    }
+
+    /// v5: index the active chain's heights by block hash in `hash_height`,
+    /// the reverse of `height_hash`.
+    fn migrate_hash_index(&self) -> Result<()> {
+        let hash_height = self.tree("hash_height")?;
+        hash_height.clear()?;
+        for item in self.tree("height_hash")?.iter() {
+            let (key, value) = item?;
+            hash_height.insert(value, key)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+//! UTXO set snapshots.
+//!
+//! File layout: the magic `KXUS`, a big-endian u32 format version, the
+//! borsh [`SnapshotHeader`], then `utxo_count` entries in `utxo` key order,
+//! each the 36-byte outpoint key followed by the borsh `TxOut`.
+
+use crate::error::{corruption, decode, encode};
+use crate::{
+    address_index_key, missing_block, outpoint_key, tip_value, transaction_error, ChainStore,
+    Result, Storage, StorageError,
+};
+use borsh::{BorshDeserialize, BorshSerialize};
+use kexa_proto::{BlockHeader, Hash32, OutPoint, TxOut};
+use sha2::{Digest, Sha256};
+use sled::transaction::Transactional;
+use std::io::{Read, Write};
+use tracing::{info, warn};
+
+const MAGIC: &[u8; 4] = b"KXUS";
+const FORMAT_VERSION: u32 = 1;
+
+/// Meta key holding the [`SnapshotHeader`] a data dir was started from,
+/// until the history below it has been validated.
+const BASE_KEY: &[u8] = b"snapshot_base";
+/// Meta key present while a snapshot is being loaded.
+const LOADING_KEY: &[u8] = b"snapshot_loading";
+/// Meta key holding why the snapshot was found to contradict the validated
+/// chain. The UTXO set it seeded cannot be trusted from then on.
+const FAILED_KEY: &[u8] = b"snapshot_failed";
+
+/// Indexes are built from stored blocks, which a snapshot-based data dir
+/// lacks below its base.
+pub(crate) const INDEXES_NEED_HISTORY: &str =
+    "tx and address history indexes need the full chain; enable them once the utxo snapshot's history is validated";
+
+/// Entries written per sled batch while loading.
+const LOAD_BATCH: u64 = 10_000;
+
+/// Chain state a UTXO snapshot was taken at.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct SnapshotHeader {
+    /// Header of the last block the snapshot includes.
+    pub base: BlockHeader,
+    pub utxo_count: u64,
+    /// [`Storage::utxo_set_hash`] of the snapshot's UTXO set.
+    pub utxo_hash: Hash32,
+}
+
+/// UTXO set commitment: sha256 over every entry's `utxo` key followed by its
+/// borsh `TxOut`, in key order.
+#[derive(Default)]
+struct UtxoHasher {
+    hasher: Sha256,
+    count: u64,
+}
+
+impl UtxoHasher {
+    fn update(&mut self, key: &[u8], value: &[u8]) {
+        self.hasher.update(key);
+        self.hasher.update(value);
+        self.count += 1;
+    }
+
+    fn finish(self) -> (u64, Hash32) {
+        (self.count, Hash32(self.hasher.finalize().into()))
+    }
+}
+
+fn hash_tree(tree: &sled::Tree) -> Result<(u64, Hash32)> {
+    let mut hasher = UtxoHasher::default();
+    for item in tree.iter() {
+        let (key, value) = item?;
+        hasher.update(&key, &value);
+    }
+    Ok(hasher.finish())
+}
+
+fn io_error(err: std::io::Error) -> StorageError {
+    StorageError::SnapshotIo(err)
+}
+
+impl Storage {
+    /// Entry count and commitment hash of the UTXO set at the tip.
+    pub fn utxo_set_hash(&self) -> Result<(u64, Hash32)> {
+        if let Some(mut cache) = self.utxo_cache() {
+            self.flush_utxo_cache(&mut cache)?;
+        }
+        hash_tree(&self.tree("utxo")?)
+    }
+
+    /// The snapshot this data dir was started from, while the blocks below
+    /// it are still unvalidated and missing.
+    pub fn snapshot_base(&self) -> Result<Option<SnapshotHeader>> {
+        match self.tree("meta")?.get(BASE_KEY)? {
+            Some(value) => Ok(Some(decode("meta", BASE_KEY, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Why the UTXO snapshot this data dir was started from contradicts the
+    /// validated chain, if [`Storage::complete_snapshot_history`] found so.
+    pub fn snapshot_failure(&self) -> Result<Option<String>> {
+        Ok(self
+            .tree("meta")?
+            .get(FAILED_KEY)?
+            .map(|reason| String::from_utf8_lossy(&reason).into_owned()))
+    }
+
+    /// Records `err`, a mismatch between the snapshot and the validated
+    /// chain, for [`Storage::snapshot_failure`], and returns it.
+    fn snapshot_failed(&self, err: StorageError) -> StorageError {
+        let reason = err.to_string();
+        let recorded = self.tree("meta").and_then(|meta| {
+            meta.insert(FAILED_KEY, reason.as_bytes())?;
+            self.db.flush()?;
+            Ok(())
+        });
+        if let Err(write_err) = recorded {
+            warn!("failed to record utxo snapshot failure: {write_err}");
+        }
+        err
+    }
+
+    /// Writes the UTXO set as of the active-chain block at `height` to
+    /// `writer`. Heights below the tip are rebuilt from the stored blocks.
+    pub fn write_utxo_snapshot(
+        &self,
+        height: u64,
+        writer: &mut impl Write,
+    ) -> Result<SnapshotHeader> {
+        let (tip_height, _) = self
+            .get_tip()?
+            .ok_or(StorageError::Rejected("tip missing"))?;
+        if height == 0 || height > tip_height {
+            return Err(StorageError::Rejected(
+                "snapshot height must be above genesis and at most the tip",
+            ));
+        }
+        if self.snapshot_base()?.is_some() && height < tip_height {
+            return Err(StorageError::Rejected(
+                "blocks below the utxo snapshot base are not validated yet",
+            ));
+        }
+        let base = self.get_header(height)?.ok_or_else(|| {
+            corruption(
+                "headers",
+                height.to_be_bytes(),
+                "missing entry below the tip",
+            )
+        })?;
+        let scratch = height < tip_height;
+        let tree = if scratch {
+            self.utxos_at_height(height)?
+        } else {
+            if let Some(mut cache) = self.utxo_cache() {
+                self.flush_utxo_cache(&mut cache)?;
+            }
+            self.tree("utxo")?
+        };
+        let (utxo_count, utxo_hash) = hash_tree(&tree)?;
+        let header = SnapshotHeader {
+            base,
+            utxo_count,
+            utxo_hash,
+        };
+        writer.write_all(MAGIC).map_err(io_error)?;
+        writer
+            .write_all(&FORMAT_VERSION.to_be_bytes())
+            .map_err(io_error)?;
+        header.serialize(writer).map_err(io_error)?;
+        for item in tree.iter() {
+            let (key, value) = item?;
+            writer.write_all(&key).map_err(io_error)?;
+            writer.write_all(&value).map_err(io_error)?;
+        }
+        writer.flush().map_err(io_error)?;
+        if scratch {
+            self.db.drop_tree("snapshot_utxos")?;
+        }
+        Ok(header)
+    }
+
+    /// Loads a snapshot into a data dir that holds only genesis, making its
+    /// base block the tip. The snapshot's commitment must equal
+    /// `expected_hash`, obtained from a source the operator trusts, and the
+    /// entries must hash to it.
+    ///
+    /// Blocks below the base stay missing until
+    /// [`Storage::complete_snapshot_history`] supplies them.
+    pub fn load_utxo_snapshot(
+        &self,
+        reader: &mut impl Read,
+        expected_hash: &Hash32,
+    ) -> Result<SnapshotHeader> {
+        if !matches!(self.get_tip()?, Some((0, _))) {
+            return Err(StorageError::Rejected(
+                "a utxo snapshot can only be loaded into a data dir holding just genesis",
+            ));
+        }
+        if self.options.tx_index || self.options.address_history {
+            return Err(StorageError::Rejected(INDEXES_NEED_HISTORY));
+        }
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(io_error)?;
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version).map_err(io_error)?;
+        if &magic != MAGIC || u32::from_be_bytes(version) != FORMAT_VERSION {
+            return Err(StorageError::InvalidSnapshot(
+                "not a utxo snapshot, or an unsupported format version".into(),
+            ));
+        }
+        let header = SnapshotHeader::deserialize_reader(reader).map_err(io_error)?;
+        if header.utxo_hash != *expected_hash {
+            return Err(StorageError::InvalidSnapshot(format!(
+                "commitment {} does not match expected {}",
+                hex::encode(header.utxo_hash.0),
+                hex::encode(expected_hash.0)
+            )));
+        }
+        if header.base.height == 0 {
+            return Err(StorageError::InvalidSnapshot(
+                "snapshot base is genesis".into(),
+            ));
+        }
+
+        let meta = self.tree("meta")?;
+        meta.insert(LOADING_KEY, &[1u8])?;
+        if let Err(err) = self.load_snapshot_entries(reader, &header) {
+            self.discard_partial_snapshot()?;
+            return Err(err);
+        }
+        let base_hash = header.base.hash();
+        let height_key = header.base.height.to_be_bytes();
+        let header_bytes = encode(&header.base)?;
+        let snapshot_bytes = encode(&header)?;
+        let trees = (
+            &self.tree("headers")?,
+            &self.tree("height_hash")?,
+            &self.tree("hash_height")?,
+            &meta,
+        );
+        trees
+            .transaction(|(headers, height_hash, hash_height, meta)| {
+                headers.insert(&height_key, header_bytes.clone())?;
+                height_hash.insert(&height_key, base_hash.0.to_vec())?;
+                hash_height.insert(&base_hash.0, height_key.to_vec())?;
+                meta.insert(b"tip", tip_value(header.base.height, &base_hash))?;
+                meta.insert(BASE_KEY, snapshot_bytes.clone())?;
+                meta.remove(LOADING_KEY)?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+        Ok(header)
+    }
+
+    fn load_snapshot_entries(&self, reader: &mut impl Read, header: &SnapshotHeader) -> Result<()> {
+        if let Some(mut cache) = self.utxo_cache() {
+            cache.clear();
+        }
+        let utxo = self.tree("utxo")?;
+        let addr_utxo = self.tree("addr_utxo")?;
+        utxo.clear()?;
+        addr_utxo.clear()?;
+        let mut hasher = UtxoHasher::default();
+        let mut last_key: Option<[u8; 36]> = None;
+        let (mut utxo_batch, mut addr_batch) = (sled::Batch::default(), sled::Batch::default());
+        for loaded in 1..=header.utxo_count {
+            let mut key = [0u8; 36];
+            reader.read_exact(&mut key).map_err(io_error)?;
+            let output = TxOut::deserialize_reader(reader).map_err(io_error)?;
+            if last_key.is_some_and(|last| last >= key) {
+                return Err(StorageError::InvalidSnapshot(
+                    "entries are not in strictly increasing key order".into(),
+                ));
+            }
+            last_key = Some(key);
+            let value = encode(&output)?;
+            hasher.update(&key, &value);
+            addr_batch.insert(address_index_key(&output.address, &key), value.clone());
+            utxo_batch.insert(&key[..], value);
+            if loaded.is_multiple_of(LOAD_BATCH) {
+                utxo.apply_batch(std::mem::take(&mut utxo_batch))?;
+                addr_utxo.apply_batch(std::mem::take(&mut addr_batch))?;
+                info!("loaded {loaded}/{} snapshot utxos", header.utxo_count);
+            }
+        }
+        utxo.apply_batch(utxo_batch)?;
+        addr_utxo.apply_batch(addr_batch)?;
+        if reader.read(&mut [0u8; 1]).map_err(io_error)? != 0 {
+            return Err(StorageError::InvalidSnapshot(
+                "trailing data after the last entry".into(),
+            ));
+        }
+        if hasher.finish() != (header.utxo_count, header.utxo_hash) {
+            return Err(StorageError::InvalidSnapshot(
+                "utxo entries do not match the commitment".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Drops the UTXOs of a snapshot load that failed or was interrupted,
+    /// leaving the genesis-only data dir it started from.
+    pub(crate) fn discard_partial_snapshot(&self) -> Result<()> {
+        let meta = self.tree("meta")?;
+        if meta.contains_key(LOADING_KEY)? {
+            self.tree("utxo")?.clear()?;
+            self.tree("addr_utxo")?.clear()?;
+            meta.remove(LOADING_KEY)?;
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes a snapshot-based data dir once `history`, a separate store
+    /// that validated the chain from genesis up to the snapshot base, agrees
+    /// with the snapshot: same base block and same UTXO set commitment. The
+    /// validated blocks are copied over, after which the data dir is like any
+    /// other. A mismatch is recorded for [`Storage::snapshot_failure`].
+    pub fn complete_snapshot_history(&self, history: &Storage) -> Result<()> {
+        let snapshot = self.snapshot_base()?.ok_or(StorageError::Rejected(
+            "data dir was not started from a snapshot",
+        ))?;
+        let base_height = snapshot.base.height;
+        let base_hash = snapshot.base.hash();
+        let validated = history.get_hash_by_height(base_height)?;
+        if validated != Some(base_hash) {
+            return Err(self.snapshot_failed(StorageError::InvalidSnapshot(format!(
+                "validated chain has {} at height {base_height}, snapshot base is {}",
+                validated.map_or("no block".into(), |hash| hex::encode(hash.0)),
+                hex::encode(base_hash.0)
+            ))));
+        }
+        if history.get_tip()? != Some((base_height, base_hash)) {
+            return Err(StorageError::Rejected(
+                "validated history must end at the snapshot base",
+            ));
+        }
+        if history.utxo_set_hash()? != (snapshot.utxo_count, snapshot.utxo_hash) {
+            return Err(self.snapshot_failed(StorageError::InvalidSnapshot(format!(
+                "utxo set at height {base_height} does not match the snapshot commitment"
+            ))));
+        }
+        let blocks = self.tree("blocks")?;
+        let headers = self.tree("headers")?;
+        let height_hash = self.tree("height_hash")?;
+        let hash_height = self.tree("hash_height")?;
+        let undo = self.tree("undo")?;
+        let history_undo = history.tree("undo")?;
+        for height in 1..=base_height {
+            let (hash, block) = history.block_at_height(height)?;
+            let block_undo = history_undo
+                .get(hash.0)?
+                .ok_or_else(|| missing_block(height, &hash))?;
+            blocks.insert(hash.0, encode(&block)?)?;
+            headers.insert(height.to_be_bytes(), encode(&block.header)?)?;
+            height_hash.insert(height.to_be_bytes(), &hash.0)?;
+            hash_height.insert(hash.0, &height.to_be_bytes())?;
+            undo.insert(hash.0, block_undo)?;
+            if height.is_multiple_of(crate::schema::PROGRESS_INTERVAL) {
+                info!("copied validated history to height {height}/{base_height}");
+            }
+        }
+        self.db.flush()?;
+        self.tree("meta")?.remove(BASE_KEY)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Scratch tree holding the UTXO set as of `height`, rebuilt by walking
+    /// the stored blocks from genesis. The caller drops it when done.
+    fn utxos_at_height(&self, height: u64) -> Result<sled::Tree> {
+        let scratch = self.tree("snapshot_utxos")?;
+        scratch.clear()?;
+        for height in 1..=height {
+            let (hash, block) = self.block_at_height(height)?;
+            for tx in &block.txs {
+                for input in &tx.inputs {
+                    if scratch.remove(outpoint_key(&input.outpoint))?.is_none() {
+                        return Err(corruption(
+                            "blocks",
+                            hash.0,
+                            format!("block at height {height} spends unknown output"),
+                        ));
+                    }
+                }
+                let txid = tx.txid();
+                for (index, output) in tx.outputs.iter().enumerate() {
+                    let key = outpoint_key(&OutPoint {
+                        txid,
+                        index: index as u32,
+                    });
+                    scratch.insert(key, encode(output)?)?;
+                }
+            }
+        }
+        Ok(scratch)
+    }
+}
@@ -26,6 +26,18 @@ pub fn pick_free_port() -> Result<u16> {
     let l = TcpListener::bind("127.0.0.1:0").context("bind ephemeral port")?;
     Ok(l.local_addr().context("local_addr")?.port())
 }
+
+/// A spawned node, killed and reaped when dropped so a failing test does not
+/// leave it running.
+pub struct NodeProcess(Child);
+
+impl Drop for NodeProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
 pub fn spawn_node(
     bin: &PathBuf,
     rpc: u16,
@@ -34,7 +46,7 @@ pub fn spawn_node(
     peers: &str,
     mine: bool,
     miner_address: Option<&str>,
-) -> Result<Child> {
+) -> Result<NodeProcess> {
     let mut cmd = Command::new(bin);
     cmd.args([
         "--rpc-addr",
@@ -53,7 +65,7 @@ pub fn spawn_node(
         }
     }
     let child = cmd.spawn().context("spawn kexa-node")?;
-    Ok(child)
+    Ok(NodeProcess(child))
 }
 
 pub async fn wait_for_ready(url: &str) -> Result<()> {
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use ed25519_dalek::SigningKey;
 use kexa_proto::{sign_tx, tx_signing_hash, Address, OutPoint, Transaction, TxIn, TxOut};
+use kexa_testkit::NodeProcess;
 use rand::rngs::OsRng;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
@@ -35,8 +36,22 @@ fn pick_unique_port(used: &mut HashSet<u16>) -> Result<u16> {
     }
     anyhow::bail!("could not pick unique free port");
 }
-#[tokio::test]
-async fn devnet_flow_two_nodes() -> Result<()> {
+
+/// Two running nodes, the second connected to the first, and the wallets and
+/// client the tests drive them with.
+struct TwoNodes {
+    _node1: NodeProcess,
+    _node2: NodeProcess,
+    url1: String,
+    url2: String,
+    client: reqwest::Client,
+    alice_key: SigningKey,
+    alice_addr: String,
+    bob_addr: String,
+}
+
+/// Starts two nodes and returns them once both are ready.
+async fn spawn_two_nodes() -> Result<TwoNodes> {
     let bin = kexa_testkit::build_node_binary()?;
     let node1_dir = temp_dir("node1");
     let node2_dir = temp_dir("node2");
@@ -73,10 +88,29 @@ async fn devnet_flow_two_nodes() -> Result<()> {
     let bob_key = SigningKey::generate(&mut OsRng);
     let alice_addr = Address::from_pubkey(&alice_key.verifying_key()).to_bech32();
     let bob_addr = Address::from_pubkey(&bob_key.verifying_key()).to_bech32();
+    Ok(TwoNodes {
+        _node1: node1,
+        _node2: node2,
+        url1,
+        url2,
+        client: reqwest::Client::builder().no_proxy().build()?,
+        alice_key,
+        alice_addr,
+        bob_addr,
+    })
+}
 
-    let client = reqwest::Client::builder().no_proxy().build()?;
+/// Mines a block paying Alice on the node at `url` and returns a signed
+/// transaction sending 10 of it to Bob.
+async fn pay_bob_from_new_coinbase(
+    client: &reqwest::Client,
+    url: &str,
+    alice_key: &SigningKey,
+    bob_addr: &str,
+) -> Result<Transaction> {
+    let alice_addr = Address::from_pubkey(&alice_key.verifying_key()).to_bech32();
     client
-        .post(format!("{}/mine_blocks", url1))
+        .post(format!("{}/mine_blocks", url))
         .json(&serde_json::json!({"count": 1, "miner_address": alice_addr}))
         .send()
         .await?;
@@ -84,15 +118,15 @@ async fn devnet_flow_two_nodes() -> Result<()> {
     tokio::time::sleep(Duration::from_millis(500)).await;
 
     let balance: u64 = get_json(
-        &client,
-        &format!("{}/balance/{}", url1, alice_addr),
+        client,
+        &format!("{}/balance/{}", url, alice_addr),
         "balance",
     )
     .await?;
     assert!(balance > 0);
 
     let utxos: Vec<UtxoResponse> =
-        get_json(&client, &format!("{}/utxos/{}", url1, alice_addr), "utxos").await?;
+        get_json(client, &format!("{}/utxos/{}", url, alice_addr), "utxos").await?;
     let utxo = utxos.first().expect("utxo");
     let mut txid = [0u8; 32];
     txid.copy_from_slice(&hex::decode(&utxo.txid)?);
@@ -110,7 +144,7 @@ async fn devnet_flow_two_nodes() -> Result<()> {
         outputs: vec![
             TxOut {
                 amount: 10,
-                address: Address::from_bech32(&bob_addr)?.payload,
+                address: Address::from_bech32(bob_addr)?.payload,
             },
             TxOut {
                 amount: utxo.amount - 11,
@@ -119,7 +153,22 @@ async fn devnet_flow_two_nodes() -> Result<()> {
         ],
     };
     let signing_hash = tx_signing_hash(&tx);
-    tx.inputs[0].signature = sign_tx(&alice_key, &signing_hash.0);
+    tx.inputs[0].signature = sign_tx(alice_key, &signing_hash.0);
+    Ok(tx)
+}
+
+#[tokio::test]
+async fn devnet_flow_two_nodes() -> Result<()> {
+    let TwoNodes {
+        url1,
+        url2,
+        client,
+        alice_key,
+        alice_addr,
+        bob_addr,
+        ..
+    } = &spawn_two_nodes().await?;
+    let tx = pay_bob_from_new_coinbase(client, url1, alice_key, bob_addr).await?;
 
     client
         .post(format!("{}/submit_tx", url1))
@@ -136,18 +185,58 @@ async fn devnet_flow_two_nodes() -> Result<()> {
     tokio::time::sleep(Duration::from_millis(500)).await;
 
     let balance_after: u64 = get_json(
-        &client,
+        client,
         &format!("{}/balance/{}", url1, bob_addr),
         "balance_after",
     )
     .await?;
     assert_eq!(balance_after, 10);
 
-    let tip2 = wait_for_tip(&client, &format!("{}/tip", url2), 1).await?;
+    let tip2 = wait_for_tip(client, &format!("{}/tip", url2), 1).await?;
     assert!(!tip2.hash.is_empty());
 
-    drop(node1);
-    drop(node2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn submitted_tx_is_relayed_to_the_mining_node() -> Result<()> {
+    let TwoNodes {
+        url1,
+        url2,
+        client,
+        alice_key,
+        alice_addr,
+        bob_addr,
+        ..
+    } = &spawn_two_nodes().await?;
+    let tx = pay_bob_from_new_coinbase(client, url1, alice_key, bob_addr).await?;
+
+    // Submit to node2 and let relay carry it to node1, which mines it.
+    wait_for_tip(client, &format!("{}/tip", url2), 1).await?;
+    let txid = client
+        .post(format!("{}/submit_tx", url2))
+        .json(&serde_json::json!({"tx": tx}))
+        .send()
+        .await?
+        .json::<String>()
+        .await?;
+    assert_eq!(txid, hex::encode(tx.txid().0));
+    wait_for_mempool(client, &format!("{}/tx/{}", url1, txid)).await?;
+
+    client
+        .post(format!("{}/mine_blocks", url1))
+        .json(&serde_json::json!({"count": 1, "miner_address": alice_addr}))
+        .send()
+        .await?;
+    wait_for_tip(client, &format!("{}/tip", url2), 2).await?;
+    let bob_balance: u64 = get_json(
+        client,
+        &format!("{}/balance/{}", url2, bob_addr),
+        "bob_balance",
+    )
+    .await?;
+    assert_eq!(bob_balance, 10);
+
     Ok(())
 }
 
@@ -167,6 +256,17 @@ async fn get_json<T: DeserializeOwned>(
     Ok(value)
 }
 
+async fn wait_for_mempool(client: &reqwest::Client, url: &str) -> Result<()> {
+    for _ in 0..40 {
+        let response = client.get(url).send().await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+    anyhow::bail!("transaction never reached {}", url);
+}
+
 async fn wait_for_tip(client: &reqwest::Client, url: &str, min_height: u64) -> Result<TipResponse> {
     for _ in 0..20 {
         let tip: TipResponse = get_json(client, url, "tip2").await?;